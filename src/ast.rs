@@ -0,0 +1,757 @@
+use crate::lexer::{Token, TokenKind};
+use crate::intern::Symbol;
+use crate::source::Span;
+use crate::Error;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::num::NonZeroU32;
+use std::ops::{Index, IndexMut};
+
+/// Handle to a node inside an `Ast`, only valid while the generation of its slot matches
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct NodeId {
+    pub gen: NonZeroU32,
+    pub offset: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct PrimaryExpr {
+    pub value: Token,
+}
+
+#[derive(Clone, Debug)]
+pub struct UnaryExpr {
+    pub operator: TokenKind,
+    pub operand: NodeId,
+}
+
+#[derive(Clone, Debug)]
+pub struct BinaryExpr {
+    pub operator: TokenKind,
+    pub left: NodeId,
+    pub right: NodeId,
+}
+
+/// `let name: ty = init;`, the binding is visible until the end of the enclosing block
+#[derive(Clone, Debug)]
+pub struct LetStmt {
+    pub name: Symbol,
+    pub name_span: Span,
+    pub ty: Option<NodeId>,
+    pub init: NodeId,
+}
+
+/// `{ stmts; value }`, evaluates to `value` or to nothing when it is missing
+#[derive(Clone, Debug)]
+pub struct BlockExpr {
+    pub stmts: Vec<NodeId>,
+    pub value: Option<NodeId>,
+}
+
+/// `if cond { .. } else { .. }`, `otherwise` is either a block or another `if`
+#[derive(Clone, Debug)]
+pub struct IfExpr {
+    pub cond: NodeId,
+    pub then: NodeId,
+    pub otherwise: Option<NodeId>,
+}
+
+/// `while cond { body }`, evaluates to `Void`
+#[derive(Clone, Debug)]
+pub struct WhileExpr {
+    pub cond: NodeId,
+    pub body: NodeId,
+}
+
+/// `target = value`, evaluates to `Void`
+#[derive(Clone, Debug)]
+pub struct AssignExpr {
+    pub target: NodeId,
+    pub value: NodeId,
+}
+
+/// `(a, b, ..)`, `()` is the empty tuple
+#[derive(Clone, Debug)]
+pub struct TupleExpr {
+    pub elems: Vec<NodeId>,
+}
+
+/// `tuple[index]`, also written `tuple.0`. In dynamically typed programs the tuple is an
+/// `Any` and the index any integer, both are checked at runtime. Reads and writes of a
+/// proxy go through its functions
+#[derive(Clone, Debug)]
+pub struct IndexExpr {
+    pub tuple: NodeId,
+    pub index: NodeId,
+}
+
+/// `len(tuple)`
+#[derive(Clone, Debug)]
+pub struct LenExpr {
+    pub tuple: NodeId,
+}
+
+/// Tags a value of the ground type `ty` as an `Any`, introduced by `lower_dynamic`
+#[derive(Clone, Debug)]
+pub struct InjectExpr {
+    pub expr: NodeId,
+    pub ty: NodeId,
+}
+
+/// Value of the ground type `ty` held by an `Any`, the program traps when the tag, the
+/// tuple length or the function arity doesn't match
+#[derive(Clone, Debug)]
+pub struct ProjectExpr {
+    pub expr: NodeId,
+    pub ty: NodeId,
+}
+
+/// Whether an `Any` holds a value of the ground type `ty`
+#[derive(Clone, Debug)]
+pub struct IsExpr {
+    pub expr: NodeId,
+    pub ty: NodeId,
+}
+
+/// Conversion of `expr` from the type `from` to the consistent type `to`, inserted by the
+/// gradual checker where typed and untyped code meet. Its span is the one blamed when
+/// the conversion fails at runtime
+#[derive(Clone, Debug)]
+pub struct CastExpr {
+    pub expr: NodeId,
+    pub from: NodeId,
+    pub to: NodeId,
+}
+
+/// Tuple seen through a cast, introduced by `lower_casts` and allocated by the runtime.
+/// `reads` is a tuple of functions, the one at `i` converts element `i` when it is read,
+/// and `writes` holds the ones converting values written back
+#[derive(Clone, Debug)]
+pub struct ProxyExpr {
+    pub tuple: NodeId,
+    pub reads: NodeId,
+    pub writes: NodeId,
+}
+
+/// Heap allocation of an uninitialized tuple, introduced by `expose_allocation`
+#[derive(Clone, Debug)]
+pub struct AllocateExpr {
+    pub len: usize,
+    pub tag: i64,
+}
+
+/// Runs the garbage collector so that at least `bytes` can be allocated
+#[derive(Clone, Debug)]
+pub struct CollectExpr {
+    pub bytes: i64,
+}
+
+/// Expression evaluated only for its effects, its value is discarded
+#[derive(Clone, Debug)]
+pub struct ExprStmt {
+    pub expr: NodeId,
+}
+
+/// `callee(args..)`
+#[derive(Clone, Debug)]
+pub struct CallExpr {
+    pub callee: NodeId,
+    pub args: Vec<NodeId>,
+}
+
+/// Parameter of a function or lambda. The type can be left out of lambda parameters, and
+/// of every parameter in dynamically typed programs
+#[derive(Clone, Debug)]
+pub struct Param {
+    pub name: Symbol,
+    pub name_span: Span,
+    pub ty: Option<NodeId>,
+}
+
+/// `fn name<generics..>(params..) -> ret { body }`, a missing return type means `Void`
+#[derive(Clone, Debug)]
+pub struct FnDecl {
+    pub name: Symbol,
+    pub name_span: Span,
+    /// Type parameters, the function is generic when there are any
+    pub generics: Vec<Symbol>,
+    pub params: Vec<Param>,
+    pub ret: Option<NodeId>,
+    pub body: NodeId,
+}
+
+/// Anonymous function `fn(params..) -> ret { body }`, captures the variables it uses.
+/// Types left out are inferred, including the return type
+#[derive(Clone, Debug)]
+pub struct LambdaExpr {
+    pub params: Vec<Param>,
+    pub ret: Option<NodeId>,
+    pub body: NodeId,
+}
+
+/// Root of the tree, `main` is the block made of the top-level statements
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub functions: Vec<NodeId>,
+    pub main: NodeId,
+}
+
+/// Type annotation naming a type, like `Int`
+#[derive(Clone, Debug)]
+pub struct TypeName {
+    pub name: Symbol,
+}
+
+/// Type annotation `(A, B, ..)`
+#[derive(Clone, Debug)]
+pub struct TupleType {
+    pub elems: Vec<NodeId>,
+}
+
+/// Type annotation `fn(A, B, ..) -> R`, a missing return type means `Void`
+#[derive(Clone, Debug)]
+pub struct FnType {
+    pub params: Vec<NodeId>,
+    pub ret: Option<NodeId>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Node {
+    Primary(PrimaryExpr),
+    Unary(UnaryExpr),
+    Binary(BinaryExpr),
+    Block(BlockExpr),
+    If(IfExpr),
+    While(WhileExpr),
+    Call(CallExpr),
+    Assign(AssignExpr),
+    Break,
+    Continue,
+    Tuple(TupleExpr),
+    Index(IndexExpr),
+    Len(LenExpr),
+    Lambda(LambdaExpr),
+    /// Address of the code of a top-level function, introduced by closure conversion
+    FunRef(Symbol),
+
+    Allocate(AllocateExpr),
+    Collect(CollectExpr),
+    /// Value of a variable of the runtime, like `free_ptr`
+    GlobalValue(Symbol),
+
+    Inject(InjectExpr),
+    Project(ProjectExpr),
+    Is(IsExpr),
+    Cast(CastExpr),
+    Proxy(ProxyExpr),
+
+    Let(LetStmt),
+    ExprStmt(ExprStmt),
+
+    FnDecl(FnDecl),
+    Program(Program),
+
+    TypeName(TypeName),
+    TupleType(TupleType),
+    FnType(FnType),
+}
+
+impl Node {
+    #[allow(dead_code, reason = "part of the node API, no pass needs to classify nodes yet")]
+    pub fn is_expression(&self) -> bool {
+        use Node as T;
+
+        matches!(self,
+            | T::Primary(_)
+            | T::Unary(_)
+            | T::Binary(_)
+            | T::Block(_)
+            | T::If(_)
+            | T::While(_)
+            | T::Call(_)
+            | T::Assign(_)
+            | T::Break
+            | T::Continue
+            | T::Tuple(_)
+            | T::Index(_)
+            | T::Len(_)
+            | T::Lambda(_)
+            | T::FunRef(_)
+            | T::Allocate(_)
+            | T::Collect(_)
+            | T::GlobalValue(_)
+            | T::Inject(_)
+            | T::Project(_)
+            | T::Is(_)
+            | T::Cast(_)
+            | T::Proxy(_))
+    }
+
+    /// Direct children of the node, in evaluation order
+    pub fn children(&self) -> Vec<NodeId> {
+        use Node as T;
+
+        match &self {
+            T::Primary(_) => Vec::new(),
+            T::Unary(u) => vec![u.operand],
+            T::Binary(b) => vec![b.left, b.right],
+            T::Block(b) => b.stmts.iter().copied().chain(b.value).collect(),
+            T::If(i) => [i.cond, i.then].into_iter().chain(i.otherwise).collect(),
+            T::While(w) => vec![w.cond, w.body],
+            T::Assign(a) => vec![a.value, a.target],
+            T::Break | T::Continue => Vec::new(),
+            T::Tuple(t) => t.elems.clone(),
+            T::Index(i) => vec![i.tuple, i.index],
+            T::Len(l) => vec![l.tuple],
+            T::Lambda(l) => l.params.iter().filter_map(|p| p.ty).chain(l.ret).chain([l.body]).collect(),
+            T::FunRef(_) => Vec::new(),
+            T::Allocate(_) | T::Collect(_) | T::GlobalValue(_) => Vec::new(),
+            T::Inject(i) => vec![i.expr, i.ty],
+            T::Project(p) => vec![p.expr, p.ty],
+            T::Is(i) => vec![i.expr, i.ty],
+            T::Cast(c) => vec![c.expr, c.from, c.to],
+            T::Proxy(p) => vec![p.tuple, p.reads, p.writes],
+            T::Call(c) => [c.callee].into_iter().chain(c.args.iter().copied()).collect(),
+            T::Let(l) => l.ty.into_iter().chain([l.init]).collect(),
+            T::ExprStmt(e) => vec![e.expr],
+            T::FnDecl(f) => f.params.iter().filter_map(|p| p.ty).chain(f.ret).chain([f.body]).collect(),
+            T::Program(p) => p.functions.iter().copied().chain([p.main]).collect(),
+            T::TypeName(_) => Vec::new(),
+            T::TupleType(t) => t.elems.clone(),
+            T::FnType(f) => f.params.iter().copied().chain(f.ret).collect(),
+        }
+    }
+
+    /// Same as `children`, but the ids can be rewritten in place
+    pub fn children_mut(&mut self) -> Vec<&mut NodeId> {
+        use Node as T;
+
+        match self {
+            T::Primary(_) => Vec::new(),
+            T::Unary(u) => vec![&mut u.operand],
+            T::Binary(b) => vec![&mut b.left, &mut b.right],
+            T::Block(b) => b.stmts.iter_mut().chain(b.value.as_mut()).collect(),
+            T::If(i) => [&mut i.cond, &mut i.then].into_iter().chain(i.otherwise.as_mut()).collect(),
+            T::While(w) => vec![&mut w.cond, &mut w.body],
+            T::Assign(a) => vec![&mut a.value, &mut a.target],
+            T::Break | T::Continue => Vec::new(),
+            T::Tuple(t) => t.elems.iter_mut().collect(),
+            T::Index(i) => vec![&mut i.tuple, &mut i.index],
+            T::Len(l) => vec![&mut l.tuple],
+            T::Lambda(l) => {
+                l.params.iter_mut().filter_map(|p| p.ty.as_mut()).chain(l.ret.as_mut()).chain([&mut l.body]).collect()
+            }
+            T::FunRef(_) => Vec::new(),
+            T::Allocate(_) | T::Collect(_) | T::GlobalValue(_) => Vec::new(),
+            T::Inject(i) => vec![&mut i.expr, &mut i.ty],
+            T::Project(p) => vec![&mut p.expr, &mut p.ty],
+            T::Is(i) => vec![&mut i.expr, &mut i.ty],
+            T::Cast(c) => vec![&mut c.expr, &mut c.from, &mut c.to],
+            T::Proxy(p) => vec![&mut p.tuple, &mut p.reads, &mut p.writes],
+            T::Call(c) => [&mut c.callee].into_iter().chain(c.args.iter_mut()).collect(),
+            T::Let(l) => l.ty.as_mut().into_iter().chain([&mut l.init]).collect(),
+            T::ExprStmt(e) => vec![&mut e.expr],
+            T::FnDecl(f) => {
+                f.params.iter_mut().filter_map(|p| p.ty.as_mut()).chain(f.ret.as_mut()).chain([&mut f.body]).collect()
+            }
+            T::Program(p) => p.functions.iter_mut().chain([&mut p.main]).collect(),
+            T::TypeName(_) => Vec::new(),
+            T::TupleType(t) => t.elems.iter_mut().collect(),
+            T::FnType(f) => f.params.iter_mut().chain(f.ret.as_mut()).collect(),
+        }
+    }
+}
+
+const_assert!(size_of::<NodeId>() == 8, "Unexpected layout for NodeId");
+
+struct Slot {
+    gen: NonZeroU32,
+    node: Option<Node>,
+    span: Span,
+}
+
+/// Arena owning every node of a program. Freed slots are recycled with a bumped
+/// generation, so ids that outlived their node are rejected instead of aliasing
+/// whatever got allocated in their place. A slot that ran out of generations is
+/// never handed out again.
+pub struct Ast {
+    slots: Vec<Slot>,
+    free_list: Vec<u32>,
+}
+
+impl Ast {
+    pub fn new() -> Ast {
+        Ast {
+            slots: Vec::new(),
+            free_list: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: Node, span: Span) -> NodeId {
+        if let Some(offset) = self.free_list.pop() {
+            let slot = &mut self.slots[offset as usize];
+            slot.node = Some(node);
+            slot.span = span;
+            return NodeId { gen: slot.gen, offset };
+        }
+
+        let offset = u32::try_from(self.slots.len()).expect("Too many nodes");
+        self.slots.push(Slot {
+            gen: NonZeroU32::MIN,
+            node: Some(node),
+            span,
+        });
+
+        NodeId { gen: NonZeroU32::MIN, offset }
+    }
+
+    /// Reference to the variable `name`
+    pub fn add_variable(&mut self, name: Symbol, span: Span) -> NodeId {
+        let tk = Token::new(TokenKind::Identifier(name), span);
+        self.add(Node::Primary(PrimaryExpr { value: tk }), span)
+    }
+
+    pub fn add_integer(&mut self, value: i64, span: Span) -> NodeId {
+        let tk = Token::new(TokenKind::Integer(value), span);
+        self.add(Node::Primary(PrimaryExpr { value: tk }), span)
+    }
+
+    /// Number of live nodes
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.node.is_some()).count()
+    }
+
+    #[allow(dead_code, reason = "part of the arena API")]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code, reason = "part of the arena API, probes an id without building an error")]
+    pub fn is_valid(&self, id: NodeId) -> bool {
+        self.get(id).is_ok()
+    }
+
+    pub fn get(&self, id: NodeId) -> Result<&Node, Error> {
+        match self.slots.get(id.offset as usize) {
+            Some(Slot { gen, node: Some(node), .. }) if *gen == id.gen => Ok(node),
+            _ => Err(Error::StaleNodeId),
+        }
+    }
+
+    pub fn get_mut(&mut self, id: NodeId) -> Result<&mut Node, Error> {
+        match self.slots.get_mut(id.offset as usize) {
+            Some(Slot { gen, node: Some(node), .. }) if *gen == id.gen => Ok(node),
+            _ => Err(Error::StaleNodeId),
+        }
+    }
+
+    /// Part of the source the node was parsed from
+    pub fn span(&self, id: NodeId) -> Span {
+        self.get(id).expect("Stale NodeId");
+        self.slots[id.offset as usize].span
+    }
+
+    pub fn set_span(&mut self, id: NodeId, span: Span) {
+        self.get(id).expect("Stale NodeId");
+        self.slots[id.offset as usize].span = span;
+    }
+
+    /// Swap the node behind `id`, keeping the id valid. The children of the old
+    /// node stay alive so the new node is free to reuse them.
+    pub fn replace(&mut self, id: NodeId, node: Node) -> Result<Node, Error> {
+        let slot = self.get_mut(id)?;
+        Ok(std::mem::replace(slot, node))
+    }
+
+    /// Replace the node behind `id` and free the subtree it used to own
+    #[allow(dead_code, reason = "part of the arena API, the passes only replace single nodes so far")]
+    pub fn replace_subtree(&mut self, id: NodeId, node: Node) -> Result<(), Error> {
+        let old = self.replace(id, node)?;
+        for child in old.children() {
+            self.free(child)?;
+        }
+        Ok(())
+    }
+
+    /// Free `id` and every node below it, invalidating their ids. The whole subtree is
+    /// checked first, so a stale id below `id` leaves the tree untouched. A node reached
+    /// twice, shared by two parents, is only freed once
+    pub fn free(&mut self, id: NodeId) -> Result<Node, Error> {
+        let mut subtree = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let children = self.get(id)?.children();
+            if seen.insert(id) {
+                stack.extend(children);
+                subtree.push(id);
+            }
+        }
+
+        let mut root = None;
+        for id in subtree {
+            let slot = &mut self.slots[id.offset as usize];
+            let node = slot.node.take().expect("Node checked before freeing");
+            // A slot whose generation would wrap around is retired, reusing it would let
+            // the oldest stale ids alias the new node
+            if let Some(gen) = slot.gen.checked_add(1) {
+                slot.gen = gen;
+                self.free_list.push(id.offset);
+            }
+            if root.is_none() {
+                root = Some(node);
+            }
+        }
+
+        Ok(root.unwrap())
+    }
+
+    /// Copy of the whole tree rooted at `id`, so it can be grafted somewhere else without
+    /// two parents sharing a node
+    pub fn deep_copy(&mut self, id: NodeId) -> NodeId {
+        let mut node = self[id].clone();
+        let span = self.span(id);
+
+        for child in node.children_mut() {
+            *child = self.deep_copy(*child);
+        }
+
+        self.add(node, span)
+    }
+
+    /// Every node of the tree rooted at `id`, children before their parent
+    pub fn post_order(&self, id: NodeId) -> Vec<NodeId> {
+        let mut out = Vec::new();
+        let mut stack = vec![(id, false)];
+
+        while let Some((id, visited)) = stack.pop() {
+            if visited {
+                out.push(id);
+                continue;
+            }
+            stack.push((id, true));
+            for child in self[id].children().into_iter().rev() {
+                stack.push((child, false));
+            }
+        }
+
+        out
+    }
+
+    /// Render the tree rooted at `id` as a S-expression
+    pub fn dump(&self, id: NodeId) -> String {
+        self.dump_with(id, &|_| None)
+    }
+
+    /// Like `dump`, with every node `note` has something to say about rendered as
+    /// `[node : note]`
+    pub fn dump_with(&self, id: NodeId, note: &dyn Fn(NodeId) -> Option<String>) -> String {
+        let dump = match &self[id] {
+            Node::Primary(p) => format!("{}", p.value.kind),
+            Node::Unary(u) => format!("({} {})", u.operator, self.dump_with(u.operand, note)),
+            Node::Binary(b) => format!("({} {} {})", b.operator, self.dump_with(b.left, note), self.dump_with(b.right, note)),
+            Node::Block(b) => {
+                let children: Vec<NodeId> = b.stmts.iter().copied().chain(b.value).collect();
+                self.dump_list("block", &children, note)
+            }
+            Node::If(i) => match i.otherwise {
+                Some(otherwise) => format!("(if {} {} {})", self.dump_with(i.cond, note), self.dump_with(i.then, note), self.dump_with(otherwise, note)),
+                None => format!("(if {} {})", self.dump_with(i.cond, note), self.dump_with(i.then, note)),
+            },
+            Node::While(w) => format!("(while {} {})", self.dump_with(w.cond, note), self.dump_with(w.body, note)),
+            Node::Assign(a) => format!("(set! {} {})", self.dump_with(a.target, note), self.dump_with(a.value, note)),
+            Node::Break => "(break)".to_string(),
+            Node::Continue => "(continue)".to_string(),
+            Node::Tuple(t) => self.dump_list("tuple", &t.elems, note),
+            Node::Index(i) => format!("(index {} {})", self.dump_with(i.tuple, note), self.dump_with(i.index, note)),
+            Node::Len(l) => format!("(len {})", self.dump_with(l.tuple, note)),
+            Node::Lambda(l) => {
+                let ret = match l.ret {
+                    Some(ret) => self.dump_with(ret, note),
+                    None => "_".to_string(),
+                };
+                format!("(lambda ({}) {} {})", self.dump_params(&l.params, note), ret, self.dump_with(l.body, note))
+            }
+            Node::FunRef(name) => format!("(fun-ref {})", name),
+            Node::Allocate(a) => format!("(allocate {} {:#x})", a.len, a.tag),
+            Node::Collect(c) => format!("(collect {})", c.bytes),
+            Node::GlobalValue(name) => format!("(global {})", name),
+            Node::Inject(i) => format!("(inject {} {})", self.dump_with(i.expr, note), self.dump_with(i.ty, note)),
+            Node::Project(p) => format!("(project {} {})", self.dump_with(p.expr, note), self.dump_with(p.ty, note)),
+            Node::Is(i) => format!("(is? {} {})", self.dump_with(i.expr, note), self.dump_with(i.ty, note)),
+            Node::Cast(c) => format!("(cast {} {} {})", self.dump_with(c.expr, note), self.dump_with(c.from, note), self.dump_with(c.to, note)),
+            Node::Proxy(p) => format!("(proxy {} {} {})", self.dump_with(p.tuple, note), self.dump_with(p.reads, note), self.dump_with(p.writes, note)),
+            Node::Call(c) => {
+                let children: Vec<NodeId> = [c.callee].into_iter().chain(c.args.iter().copied()).collect();
+                self.dump_list("call", &children, note)
+            }
+            Node::Let(l) => match l.ty {
+                Some(ty) => format!("(let ({} {}) {})", l.name, self.dump_with(ty, note), self.dump_with(l.init, note)),
+                None => format!("(let {} {})", l.name, self.dump_with(l.init, note)),
+            },
+            Node::ExprStmt(e) => format!("(stmt {})", self.dump_with(e.expr, note)),
+            Node::FnDecl(f) => {
+                let ret = match f.ret {
+                    Some(ret) => self.dump_with(ret, note),
+                    None => "Void".to_string(),
+                };
+                let generics: Vec<&str> = f.generics.iter().map(|g| g.as_str()).collect();
+                let name = match generics.is_empty() {
+                    true => f.name.to_string(),
+                    false => format!("{}<{}>", f.name, generics.join(", ")),
+                };
+                format!("(fn {} ({}) {} {})", name, self.dump_params(&f.params, note), ret, self.dump_with(f.body, note))
+            }
+            Node::Program(p) => {
+                let mut out = String::from("(program");
+                for child in p.functions.iter().chain([&p.main]) {
+                    out.push_str("\n  ");
+                    out.push_str(&self.dump_with(*child, note));
+                }
+                out.push(')');
+                out
+            }
+            Node::TypeName(t) => t.name.to_string(),
+            Node::TupleType(t) => {
+                let elems: Vec<String> = t.elems.iter().map(|e| self.dump_with(*e, note)).collect();
+                match elems.len() {
+                    1 => format!("({},)", elems[0]),
+                    _ => format!("({})", elems.join(", ")),
+                }
+            }
+            Node::FnType(f) => {
+                let params: Vec<String> = f.params.iter().map(|p| self.dump_with(*p, note)).collect();
+                match f.ret {
+                    Some(ret) => format!("fn({}) -> {}", params.join(", "), self.dump_with(ret, note)),
+                    None => format!("fn({})", params.join(", ")),
+                }
+            }
+        };
+
+        match note(id) {
+            Some(note) => format!("[{} : {}]", dump, note),
+            None => dump,
+        }
+    }
+}
+
+impl Ast {
+    fn dump_params(&self, params: &[Param], note: &dyn Fn(NodeId) -> Option<String>) -> String {
+        let params: Vec<String> = params.iter()
+            .map(|p| match p.ty {
+                Some(ty) => format!("({} {})", p.name, self.dump_with(ty, note)),
+                None => format!("({} _)", p.name),
+            })
+            .collect();
+        params.join(" ")
+    }
+
+    fn dump_list(&self, head: &str, children: &[NodeId], note: &dyn Fn(NodeId) -> Option<String>) -> String {
+        let mut out = format!("({}", head);
+        for child in children {
+            out.push(' ');
+            out.push_str(&self.dump_with(*child, note));
+        }
+        out.push(')');
+        out
+    }
+}
+
+impl Index<NodeId> for Ast {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        match self.get(id) {
+            Ok(node) => node,
+            Err(_) => panic!("Stale {:?}", id),
+        }
+    }
+}
+
+impl IndexMut<NodeId> for Ast {
+    fn index_mut(&mut self, id: NodeId) -> &mut Node {
+        match self.get_mut(id) {
+            Ok(node) => node,
+            Err(_) => panic!("Stale {:?}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `left + right` with both operands given
+    fn binary(ast: &mut Ast, left: NodeId, right: NodeId) -> NodeId {
+        ast.add(Node::Binary(BinaryExpr {
+            operator: TokenKind::Plus,
+            left,
+            right,
+        }), Span::new(0, 5))
+    }
+
+    #[test]
+    fn freed_ids_are_stale() {
+        let mut ast = Ast::new();
+        let left = ast.add_integer(1, Span::new(0, 1));
+        let right = ast.add_integer(2, Span::new(4, 5));
+        let sum = binary(&mut ast, left, right);
+
+        assert!(matches!(ast.free(sum), Ok(Node::Binary(_))));
+        for id in [sum, left, right] {
+            let err = ast.get(id).expect_err("Freed node still reachable");
+            assert_eq!(err, Error::StaleNodeId);
+            assert_eq!(err.code(), "E9000");
+        }
+        assert_eq!(ast.free(sum).expect_err("Freed twice"), Error::StaleNodeId);
+        assert!(ast.is_empty());
+    }
+
+    #[test]
+    fn stale_child_leaves_the_tree_untouched() {
+        let mut ast = Ast::new();
+        let left = ast.add_integer(1, Span::new(0, 1));
+        let right = ast.add_integer(2, Span::new(4, 5));
+        let sum = binary(&mut ast, left, right);
+        ast.free(right).expect("Live node");
+
+        assert_eq!(ast.free(sum).expect_err("Stale child"), Error::StaleNodeId);
+        assert!(ast.is_valid(sum));
+        assert!(ast.is_valid(left));
+    }
+
+    #[test]
+    fn shared_node_is_freed_once() {
+        let mut ast = Ast::new();
+        let x = ast.add_variable(Symbol::intern("x"), Span::new(0, 1));
+        let double = binary(&mut ast, x, x);
+
+        assert!(ast.free(double).is_ok());
+        assert!(!ast.is_valid(x));
+        assert!(ast.is_empty());
+    }
+
+    #[test]
+    fn replaced_id_stays_valid() {
+        let mut ast = Ast::new();
+        let id = ast.add_integer(1, Span::new(0, 1));
+        let old = ast.replace(id, Node::Break).expect("Live node");
+
+        assert!(matches!(old, Node::Primary(_)));
+        assert!(matches!(ast.get(id), Ok(Node::Break)));
+        assert_eq!(ast.span(id), Span::new(0, 1));
+    }
+
+    #[test]
+    fn reused_slot_gets_a_new_generation() {
+        let mut ast = Ast::new();
+        let old = ast.add_integer(1, Span::new(0, 1));
+        ast.free(old).expect("Live node");
+        let new = ast.add_integer(2, Span::new(0, 1));
+
+        assert_eq!(new.offset, old.offset);
+        assert_ne!(new.gen, old.gen);
+        assert_eq!(ast.get(old).expect_err("Stale id aliases the new node"), Error::StaleNodeId);
+        assert!(ast.is_valid(new));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::intern::Symbol;
+use crate::source::Span;
+use crate::types::Type;
+use std::fmt;
+
+/// Name of a basic block
+pub type Label = Symbol;
+
+/// Operand of an expression, available without computing anything
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Atom {
+    Int(i64),
+    Bool(bool),
+    Str(Symbol),
+    Var(Symbol),
+    /// The only value of `Void`
+    Void,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UnaryOp {
+    Neg,
+    /// Bitwise not, `~` on integers and booleans
+    Not,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Cmp(Cmp),
+}
+
+/// Function a call jumps to
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Callee {
+    /// Top-level function known by name
+    Direct(Symbol),
+    /// Code pointer held in a variable
+    Indirect(Atom),
+}
+
+/// Right-hand side of an assignment, one operation on atoms
+#[derive(Clone, PartialEq, Debug)]
+pub enum Exp {
+    Atom(Atom),
+    Unary(UnaryOp, Atom),
+    Binary(BinaryOp, Atom, Atom),
+    /// Element of a tuple. Indexing an `Any` checks it holds a tuple long enough, a
+    /// failed check reports the span
+    Index(Atom, Atom, Span),
+    Len(Atom, Span),
+    /// Uninitialized tuple with its header word
+    Allocate(usize, i64),
+    GlobalValue(Symbol),
+    FunRef(Symbol),
+    Call(Callee, Vec<Atom>),
+    /// Tag a value of the ground type
+    Inject(Atom, Type),
+    /// Untag a value expected to have the ground type, trapping at the span when it does not
+    Project(Atom, Type, Span),
+    /// Whether a tagged value has the ground type
+    Is(Atom, Type),
+    /// Tuple proxy with the proxied tuple, its read and its write functions
+    Proxy(Atom, Atom, Atom),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Stmt {
+    Assign(Symbol, Exp),
+    /// `tuple[index] = value`, checked like `Exp::Index`
+    SetIndex(Atom, Atom, Atom, Span),
+    /// Make room for this many bytes on the heap
+    Collect(i64),
+    /// Expression evaluated for its effect, like a call whose result is not used
+    Exp(Exp),
+}
+
+/// How a basic block ends
+#[derive(Clone, PartialEq, Debug)]
+pub enum Tail {
+    Return(Exp),
+    Goto(Label),
+    If {
+        cmp: Cmp,
+        left: Atom,
+        right: Atom,
+        then: Label,
+        otherwise: Label,
+    },
+}
+
+/// Straight-line code, control only leaves at the end
+#[derive(Clone, PartialEq, Debug)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub tail: Tail,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Function {
+    pub name: Symbol,
+    pub params: Vec<(Symbol, Type)>,
+    pub ret: Type,
+    /// Variables assigned in the body, with their type
+    pub locals: Vec<(Symbol, Type)>,
+    /// The entry block comes first
+    pub blocks: Vec<(Label, Block)>,
+}
+
+/// The code of `main` ends up in a function of its own, the last one
+#[derive(Clone, PartialEq, Debug)]
+pub struct Program {
+    pub functions: Vec<Function>,
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Atom::Int(n) => write!(f, "{}", n),
+            Atom::Bool(b) => write!(f, "{}", b),
+            Atom::Str(s) => write!(f, "{:?}", s.as_str()),
+            Atom::Var(name) => write!(f, "{}", name),
+            Atom::Void => write!(f, "void"),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "-"),
+            UnaryOp::Not => write!(f, "~"),
+        }
+    }
+}
+
+impl fmt::Display for Cmp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Cmp::Eq => "==",
+            Cmp::Ne => "!=",
+            Cmp::Lt => "<",
+            Cmp::Le => "<=",
+            Cmp::Gt => ">",
+            Cmp::Ge => ">=",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Or => "|",
+            BinaryOp::Cmp(cmp) => return write!(f, "{}", cmp),
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for Exp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |atoms: &[Atom]| -> String {
+            let atoms: Vec<String> = atoms.iter().map(|a| a.to_string()).collect();
+            atoms.join(", ")
+        };
+
+        match self {
+            Exp::Atom(a) => write!(f, "{}", a),
+            Exp::Unary(op, a) => write!(f, "{}{}", op, a),
+            Exp::Binary(op, a, b) => write!(f, "{} {} {}", a, op, b),
+            Exp::Index(t, i, _) => write!(f, "{}[{}]", t, i),
+            Exp::Len(t, _) => write!(f, "len({})", t),
+            Exp::Allocate(len, tag) => write!(f, "allocate({}, {:#x})", len, tag),
+            Exp::GlobalValue(name) => write!(f, "global({})", name),
+            Exp::FunRef(name) => write!(f, "fun-ref({})", name),
+            Exp::Call(Callee::Direct(name), args) => write!(f, "{}({})", name, list(args)),
+            Exp::Call(Callee::Indirect(callee), args) => write!(f, "(*{})({})", callee, list(args)),
+            Exp::Inject(a, ty) => write!(f, "inject({}, {})", a, ty),
+            Exp::Project(a, ty, _) => write!(f, "project({}, {})", a, ty),
+            Exp::Is(a, ty) => write!(f, "is?({}, {})", a, ty),
+            Exp::Proxy(t, r, w) => write!(f, "proxy({}, {}, {})", t, r, w),
+        }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stmt::Assign(name, e) => write!(f, "{} = {};", name, e),
+            Stmt::SetIndex(t, i, v, _) => write!(f, "{}[{}] = {};", t, i, v),
+            Stmt::Collect(bytes) => write!(f, "collect({});", bytes),
+            Stmt::Exp(e) => write!(f, "{};", e),
+        }
+    }
+}
+
+impl fmt::Display for Tail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tail::Return(e) => write!(f, "return {};", e),
+            Tail::Goto(label) => write!(f, "goto {};", label),
+            Tail::If { cmp, left, right, then, otherwise } => {
+                write!(f, "if {} {} {} goto {}; else goto {};", left, cmp, right, then, otherwise)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(|(name, ty)| format!("{}: {}", name, ty)).collect();
+        writeln!(f, "fn {}({}) -> {} {{", self.name, params.join(", "), self.ret)?;
+        for (name, ty) in &self.locals {
+            writeln!(f, "    var {}: {};", name, ty)?;
+        }
+        for (label, block) in &self.blocks {
+            writeln!(f, "{}:", label)?;
+            for stmt in &block.stmts {
+                writeln!(f, "    {}", stmt)?;
+            }
+            writeln!(f, "    {}", block.tail)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let functions: Vec<String> = self.functions.iter().map(|func| func.to_string()).collect();
+        write!(f, "{}", functions.join("\n\n"))
+    }
+}
@@ -0,0 +1,484 @@
+use crate::ast::{
+    Ast, BlockExpr, CallExpr, FnDecl, IndexExpr, LambdaExpr, LetStmt, Node, NodeId, Param, PrimaryExpr,
+    TupleExpr, TupleType,
+};
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::source::Span;
+use crate::types::{annotation, Type, TypeTable};
+use std::collections::{HashMap, HashSet};
+
+/// Identity of a variable, two bindings with the same name are still different variables
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+enum BindingId {
+    Let(NodeId),
+    /// Parameter `index` of a function or lambda
+    Param(NodeId, usize),
+}
+
+/// Turns every lambda into a top-level function and every function value into a flat
+/// closure, a tuple holding the code pointer followed by the captured values:
+///
+/// ```text
+/// fn(x: Int) -> Int { x + y }    =>  (tuple (fun-ref lambda.0) y)
+/// fn lambda.0(clos.0: _, x: Int) -> Int { let y = clos.0[1]; { x + y } }
+/// ```
+///
+/// Calls through a value load the code pointer from the closure and pass the closure
+/// itself as the first argument, `f(a)` becomes `{ let clos.1 = f; clos.1[0](clos.1, a) }`.
+/// Calls to top-level functions by name stay direct calls through a `fun-ref`, using one
+/// as a value wraps it in a `f.closure` function that drops the closure argument.
+///
+/// Captured values are copies, so `let` variables that are both captured and assigned
+/// are boxed into a 1-tuple first and every use goes through the box. Every node created
+/// gets its type recorded in `types`.
+pub fn convert_closures(ast: &mut Ast, program: NodeId, types: &mut TypeTable) {
+    let (mut functions, main) = match &ast[program] {
+        Node::Program(p) => (p.functions.clone(), p.main),
+        _ => panic!("Closure conversion expects a program"),
+    };
+
+    let mut decls = HashMap::new();
+    for id in &functions {
+        if let Node::FnDecl(decl) = &ast[*id] {
+            decls.insert(decl.name, *id);
+        }
+    }
+
+    let mut analysis = Analysis {
+        ast,
+        functions: decls.keys().copied().collect(),
+        scopes: Vec::new(),
+        lambdas: Vec::new(),
+        free: HashMap::new(),
+        lambda_order: Vec::new(),
+        uses: Vec::new(),
+        assigned: HashSet::new(),
+        captured: HashSet::new(),
+        fun_values: Vec::new(),
+        direct_calls: Vec::new(),
+        closure_calls: Vec::new(),
+    };
+    for id in functions.iter().chain([&main]) {
+        analysis.visit(*id);
+    }
+
+    let Analysis {
+        free,
+        lambda_order,
+        uses,
+        assigned,
+        captured,
+        fun_values,
+        direct_calls,
+        closure_calls,
+        ..
+    } = analysis;
+
+    let boxed: HashSet<BindingId> = assigned.intersection(&captured).copied().collect();
+
+    // Types of the captured variables, read before their uses get rewritten
+    let free: HashMap<NodeId, Vec<(Symbol, Type)>> = free.into_iter()
+        .map(|(lambda, vars)| {
+            let vars = vars.into_iter()
+                .map(|(name, binding, use_id)| {
+                    let ty = types.get(use_id).clone();
+                    match boxed.contains(&binding) {
+                        true => (name, Type::Tuple(vec![ty])),
+                        false => (name, ty),
+                    }
+                })
+                .collect();
+            (lambda, vars)
+        })
+        .collect();
+
+    let mut conv = Converter { ast, types, counter: 0 };
+
+    // Box the variables whose assignments have to be seen by the closures capturing them
+    for binding in &boxed {
+        let let_id = match binding {
+            BindingId::Let(id) => *id,
+            BindingId::Param(..) => unreachable!("Parameters cannot be assigned"),
+        };
+        let (init, ty) = match &conv.ast[let_id] {
+            Node::Let(l) => (l.init, l.ty),
+            _ => unreachable!("Binding is not a let"),
+        };
+
+        let span = conv.ast.span(init);
+        let init_ty = conv.types.get(init).clone();
+        let boxed_init = conv.add(Node::Tuple(TupleExpr { elems: vec![init] }), span, Type::Tuple(vec![init_ty]));
+        let boxed_ty = ty.map(|ty| {
+            let span = conv.ast.span(ty);
+            conv.ast.add(Node::TupleType(TupleType { elems: vec![ty] }), span)
+        });
+
+        if let Node::Let(l) = &mut conv.ast[let_id] {
+            l.init = boxed_init;
+            l.ty = boxed_ty;
+        }
+    }
+    for (id, binding) in &uses {
+        if !boxed.contains(binding) {
+            continue;
+        }
+        let name = identifier(conv.ast, *id).expect("Use is not a variable");
+        let span = conv.ast.span(*id);
+        let ty = conv.types.get(*id).clone();
+        let var = conv.variable(name, span, Type::Tuple(vec![ty]));
+        let index = conv.integer(0, span);
+        conv.ast.replace(*id, Node::Index(IndexExpr { tuple: var, index })).expect("Stale use");
+    }
+
+    for (callee, name) in &direct_calls {
+        let ty = code_type(conv.types.get(*callee));
+        conv.ast.replace(*callee, Node::FunRef(*name)).expect("Stale callee");
+        conv.types.insert(*callee, ty);
+    }
+
+    let mut wrappers: HashMap<Symbol, Symbol> = HashMap::new();
+    for (id, name) in &fun_values {
+        let wrapper = match wrappers.get(name) {
+            Some(wrapper) => *wrapper,
+            None => {
+                let decl = conv.closure_wrapper(decls[name]);
+                functions.push(decl);
+                let wrapper = Symbol::intern(&format!("{}.closure", name));
+                wrappers.insert(*name, wrapper);
+                wrapper
+            }
+        };
+
+        // The node keeps its function type, function values are closures from now on
+        let span = conv.ast.span(*id);
+        let ty = code_type(conv.types.get(*id));
+        let code = conv.add(Node::FunRef(wrapper), span, ty);
+        conv.ast.replace(*id, Node::Tuple(TupleExpr { elems: vec![code] })).expect("Stale function use");
+    }
+
+    for id in &closure_calls {
+        let call = match &conv.ast[*id] {
+            Node::Call(call) => call.clone(),
+            _ => unreachable!("Closure call is not a call"),
+        };
+
+        let span = conv.ast.span(*id);
+        let callee_span = conv.ast.span(call.callee);
+        let closure_ty = conv.types.get(call.callee).clone();
+        let ret = conv.types.get(*id).clone();
+        let temp = conv.fresh("clos");
+
+        let bind = conv.add(Node::Let(LetStmt {
+            name: temp,
+            name_span: callee_span,
+            ty: None,
+            init: call.callee,
+        }), callee_span, Type::Void);
+
+        let code = conv.index(temp, closure_ty.clone(), 0, code_type(&closure_ty), callee_span);
+        let env = conv.variable(temp, callee_span, closure_ty);
+        let args = [env].into_iter().chain(call.args).collect();
+        let call = conv.add(Node::Call(CallExpr { callee: code, args }), span, ret);
+
+        conv.ast.replace(*id, Node::Block(BlockExpr { stmts: vec![bind], value: Some(call) })).expect("Stale call");
+    }
+
+    for id in &lambda_order {
+        let lambda = match &conv.ast[*id] {
+            Node::Lambda(lambda) => lambda.clone(),
+            _ => unreachable!("Lambda was already converted"),
+        };
+
+        let span = conv.ast.span(*id);
+        let name = conv.fresh("lambda");
+        let env = conv.fresh("clos");
+        let free = &free[id];
+
+        let fn_ty = conv.types.get(*id).clone();
+        let code_ty = code_type(&fn_ty);
+        let env_ty = Type::Tuple([code_ty.clone()].into_iter().chain(free.iter().map(|(_, ty)| ty.clone())).collect());
+
+        let mut prelude = Vec::new();
+        for (i, (var, ty)) in free.iter().enumerate() {
+            let init = conv.index(env, env_ty.clone(), i as i64 + 1, ty.clone(), span);
+            prelude.push(conv.add(Node::Let(LetStmt {
+                name: *var,
+                name_span: span,
+                ty: None,
+                init,
+            }), span, Type::Void));
+        }
+
+        let body_span = conv.ast.span(lambda.body);
+        let body_ty = conv.types.get(lambda.body).clone();
+        let body = conv.add(Node::Block(BlockExpr { stmts: prelude, value: Some(lambda.body) }), body_span, body_ty);
+        // Inferred types are written down, a function has to say what it returns
+        let (param_types, ret_ty) = match &fn_ty {
+            Type::Fn(params, ret) => (params.clone(), ret),
+            _ => unreachable!("Lambda is not typed as a function"),
+        };
+        let mut params = vec![conv.env_param(env, span)];
+        for (mut param, ty) in lambda.params.into_iter().zip(param_types) {
+            if param.ty.is_none() {
+                param.ty = Some(annotation(conv.ast, &ty, param.name_span));
+            }
+            params.push(param);
+        }
+        let ret = match lambda.ret {
+            Some(ret) => ret,
+            None => annotation(conv.ast, ret_ty, body_span),
+        };
+
+        let decl = conv.add(Node::FnDecl(FnDecl {
+            name,
+            name_span: span,
+            generics: Vec::new(),
+            params,
+            ret: Some(ret),
+            body,
+        }), span, with_env(&fn_ty, env_ty));
+        functions.push(decl);
+
+        let code = conv.add(Node::FunRef(name), span, code_ty);
+        let mut elems = vec![code];
+        for (var, ty) in free {
+            elems.push(conv.variable(*var, span, ty.clone()));
+        }
+        conv.ast.replace(*id, Node::Tuple(TupleExpr { elems })).expect("Stale lambda");
+    }
+
+    if let Node::Program(p) = &mut conv.ast[program] {
+        p.functions = functions;
+    }
+}
+
+/// Type of the code behind a function value of type `ty`
+fn code_type(ty: &Type) -> Type {
+    match ty {
+        Type::Fn(params, ret) => Type::Code(params.clone(), ret.clone()),
+        _ => unreachable!("Expected a function type, found `{}`", ty),
+    }
+}
+
+/// Signature of the code taking a closure of type `env` before the parameters of `ty`
+fn with_env(ty: &Type, env: Type) -> Type {
+    match ty {
+        Type::Fn(params, ret) => Type::Fn([env].into_iter().chain(params.iter().cloned()).collect(), ret.clone()),
+        _ => unreachable!("Expected a function type, found `{}`", ty),
+    }
+}
+
+/// Creates nodes and records their type
+struct Converter<'a> {
+    ast: &'a mut Ast,
+    types: &'a mut TypeTable,
+    counter: usize,
+}
+
+impl Converter<'_> {
+    fn fresh(&mut self, prefix: &str) -> Symbol {
+        let sym = Symbol::intern(&format!("{}.{}", prefix, self.counter));
+        self.counter += 1;
+        sym
+    }
+
+    fn add(&mut self, node: Node, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add(node, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn variable(&mut self, name: Symbol, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add_variable(name, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn integer(&mut self, value: i64, span: Span) -> NodeId {
+        let id = self.ast.add_integer(value, span);
+        self.types.insert(id, Type::Int);
+        id
+    }
+
+    /// `name[i]`
+    fn index(&mut self, name: Symbol, tuple_ty: Type, i: i64, ty: Type, span: Span) -> NodeId {
+        let tuple = self.variable(name, span, tuple_ty);
+        let index = self.integer(i, span);
+        self.add(Node::Index(IndexExpr { tuple, index }), span, ty)
+    }
+
+    /// The closure parameter, its type has no syntax so it is only known from `types`
+    fn env_param(&mut self, name: Symbol, span: Span) -> Param {
+        Param { name, name_span: span, ty: None }
+    }
+
+    /// `fn f.closure(env: _, params..) -> ret { f(params..) }`, lets `f` be called like a closure
+    fn closure_wrapper(&mut self, decl: NodeId) -> NodeId {
+        let (name, generics, params, ret) = match &self.ast[decl] {
+            Node::FnDecl(f) => (f.name, f.generics.clone(), f.params.clone(), f.ret),
+            _ => unreachable!("Top-level item is not a function"),
+        };
+
+        let span = self.ast.span(decl);
+        let fn_ty = self.types.get(decl).clone();
+        let (param_types, ret_ty) = match &fn_ty {
+            Type::Fn(params, ret) => (params.clone(), (**ret).clone()),
+            _ => unreachable!("Function is not typed as one"),
+        };
+
+        let env = self.fresh("clos");
+        let mut wrapper_params = vec![self.env_param(env, span)];
+        let mut args = Vec::new();
+        for (param, ty) in params.iter().zip(param_types) {
+            wrapper_params.push(Param {
+                name: param.name,
+                name_span: param.name_span,
+                ty: param.ty.map(|ty| self.ast.deep_copy(ty)),
+            });
+            args.push(self.variable(param.name, param.name_span, ty));
+        }
+
+        let callee = self.add(Node::FunRef(name), span, code_type(&fn_ty));
+        let call = self.add(Node::Call(CallExpr { callee, args }), span, ret_ty.clone());
+        let body = self.add(Node::Block(BlockExpr { stmts: Vec::new(), value: Some(call) }), span, ret_ty);
+        let ret = ret.map(|ret| self.ast.deep_copy(ret));
+
+        let env_ty = Type::Tuple(vec![code_type(&fn_ty)]);
+        self.add(Node::FnDecl(FnDecl {
+            name: Symbol::intern(&format!("{}.closure", name)),
+            name_span: span,
+            generics,
+            params: wrapper_params,
+            ret,
+            body,
+        }), span, with_env(&fn_ty, env_ty))
+    }
+}
+
+fn identifier(ast: &Ast, id: NodeId) -> Option<Symbol> {
+    match &ast[id] {
+        Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => Some(*name),
+        _ => None,
+    }
+}
+
+/// Resolves every variable and records what closure conversion has to rewrite
+struct Analysis<'a> {
+    ast: &'a Ast,
+    functions: HashSet<Symbol>,
+    /// Variables in scope with the number of lambdas around their binding
+    scopes: Vec<(Symbol, BindingId, usize)>,
+    /// Lambdas around the current node, innermost last
+    lambdas: Vec<NodeId>,
+    /// Variables each lambda uses from outside in order of first use, with that use
+    free: HashMap<NodeId, Vec<(Symbol, BindingId, NodeId)>>,
+    /// Inner lambdas before the lambdas around them
+    lambda_order: Vec<NodeId>,
+    uses: Vec<(NodeId, BindingId)>,
+    assigned: HashSet<BindingId>,
+    captured: HashSet<BindingId>,
+    /// Top-level functions used as values
+    fun_values: Vec<(NodeId, Symbol)>,
+    /// Callees naming a top-level function
+    direct_calls: Vec<(NodeId, Symbol)>,
+    /// Calls through a closure
+    closure_calls: Vec<NodeId>,
+}
+
+impl Analysis<'_> {
+    fn lookup(&self, name: Symbol) -> Option<(BindingId, usize)> {
+        self.scopes.iter().rev().find(|(n, ..)| *n == name).map(|(_, b, depth)| (*b, *depth))
+    }
+
+    /// Top-level function `id` names, unless a variable shadows it
+    fn function(&self, id: NodeId) -> Option<Symbol> {
+        match identifier(self.ast, id) {
+            Some(name) if self.lookup(name).is_none() && self.functions.contains(&name) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn visit(&mut self, id: NodeId) {
+        let ast = self.ast;
+        let scope = self.scopes.len();
+
+        match &ast[id] {
+            Node::FnDecl(decl) => {
+                for (i, param) in decl.params.iter().enumerate() {
+                    self.scopes.push((param.name, BindingId::Param(id, i), 0));
+                }
+                self.visit(decl.body);
+            }
+
+            Node::Lambda(LambdaExpr { params, body, .. }) => {
+                self.lambdas.push(id);
+                self.free.insert(id, Vec::new());
+                let depth = self.lambdas.len();
+                for (i, param) in params.iter().enumerate() {
+                    self.scopes.push((param.name, BindingId::Param(id, i), depth));
+                }
+                self.visit(*body);
+                self.lambdas.pop();
+                self.lambda_order.push(id);
+            }
+
+            Node::Let(l) => {
+                self.visit(l.init);
+                self.scopes.push((l.name, BindingId::Let(id), self.lambdas.len()));
+                return;
+            }
+
+            Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => {
+                match self.lookup(*name) {
+                    Some((binding, depth)) => {
+                        self.uses.push((id, binding));
+                        if depth < self.lambdas.len() {
+                            self.captured.insert(binding);
+                            // Every lambda between the binding and the use has to carry it
+                            for lambda in &self.lambdas[depth..] {
+                                let free = self.free.get_mut(lambda).unwrap();
+                                if !free.iter().any(|(n, ..)| n == name) {
+                                    free.push((*name, binding, id));
+                                }
+                            }
+                        }
+                    }
+                    None if self.functions.contains(name) => self.fun_values.push((id, *name)),
+                    None => {}
+                }
+            }
+
+            Node::Assign(assign) => {
+                if let Some(name) = identifier(ast, assign.target) {
+                    if let Some((binding, _)) = self.lookup(name) {
+                        self.assigned.insert(binding);
+                    }
+                }
+                self.visit(assign.value);
+                self.visit(assign.target);
+            }
+
+            Node::Call(call) => {
+                match self.function(call.callee) {
+                    Some(name) => self.direct_calls.push((call.callee, name)),
+                    None => {
+                        self.closure_calls.push(id);
+                        self.visit(call.callee);
+                    }
+                }
+                for arg in &call.args {
+                    self.visit(*arg);
+                }
+            }
+
+            node => {
+                for child in node.children() {
+                    self.visit(child);
+                }
+            }
+        }
+
+        self.scopes.truncate(scope);
+    }
+}
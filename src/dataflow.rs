@@ -0,0 +1,78 @@
+use crate::cir::Label;
+use std::collections::{HashMap, HashSet};
+
+/// Control-flow graph as the successors of each block, the entry block first. Successors
+/// can name blocks outside the graph, like the conclusion of a function
+pub type Cfg = Vec<(Label, Vec<Label>)>;
+
+/// Blocks of `cfg` with every block before its predecessors, leaving out the edges that
+/// close a loop. That is the post-order of a depth-first walk from the entry, followed by
+/// the blocks it does not reach
+pub fn reverse_topological(cfg: &Cfg) -> Vec<Label> {
+    let succs: HashMap<Label, &Vec<Label>> = cfg.iter().map(|(label, succs)| (*label, succs)).collect();
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+
+    for (root, _) in cfg {
+        if !visited.insert(*root) {
+            continue;
+        }
+        // Blocks on the path from the root, with how many of their successors were walked
+        let mut stack = vec![(*root, 0)];
+        while let Some((label, next)) = stack.pop() {
+            match succs[&label].get(next) {
+                Some(succ) => {
+                    stack.push((label, next + 1));
+                    if succs.contains_key(succ) && visited.insert(*succ) {
+                        stack.push((*succ, 0));
+                    }
+                }
+                None => order.push(label),
+            }
+        }
+    }
+    order
+}
+
+/// Solves a backward dataflow problem over `cfg`. What holds after a block is the `join`
+/// of what holds before its successors, `exit` for successors outside the graph, and
+/// `transfer` gives what holds before the block from that. Starting from `bottom` the
+/// blocks are updated in reverse topological order, where a block sees its successors'
+/// new values right away, and the pass is repeated until nothing changes. Blocks in
+/// loops can take a few passes, `transfer` and `join` have to be monotone for this to
+/// end. Returns what holds before each block
+pub fn backward<T, E, J, F>(cfg: &Cfg, bottom: T, exit: E, join: J, mut transfer: F) -> HashMap<Label, T>
+where
+    T: Clone + PartialEq,
+    E: Fn(Label) -> T,
+    J: Fn(&T, &T) -> T,
+    F: FnMut(Label, &T) -> T,
+{
+    let succs: HashMap<Label, &Vec<Label>> = cfg.iter().map(|(label, succs)| (*label, succs)).collect();
+    let order = reverse_topological(cfg);
+    let mut before: HashMap<Label, T> = cfg.iter().map(|(label, _)| (*label, bottom.clone())).collect();
+
+    loop {
+        let mut changed = false;
+        for label in &order {
+            let mut after = bottom.clone();
+            for succ in succs[label] {
+                let succ_before = match before.get(succ) {
+                    Some(value) => value.clone(),
+                    None => exit(*succ),
+                };
+                after = join(&after, &succ_before);
+            }
+
+            let value = transfer(*label, &after);
+            if value != before[label] {
+                before.insert(*label, value);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    before
+}
@@ -0,0 +1,208 @@
+use crate::source::{SourceMap, Span};
+use crate::Error;
+use std::fmt::Write;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// Secondary span pointing at something related to the problem
+#[derive(Clone, PartialEq, Debug)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A problem found in the source, with enough context to be rendered for humans or tools
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: Error,
+    pub message: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub suggestions: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, kind: Error, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message: kind.message().to_string(),
+            kind,
+            span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+            suggestions: Vec::new(),
+        }
+    }
+
+    pub fn error(kind: Error, span: Span) -> Diagnostic {
+        Diagnostic::new(Severity::Error, kind, span)
+    }
+
+    #[allow(dead_code, reason = "every check reports errors so far, warnings render the same way")]
+    pub fn warning(kind: Error, span: Span) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, kind, span)
+    }
+
+    /// Stable code of the diagnostic, like `E0001`
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> Diagnostic {
+        self.message = message.into();
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label { span, message: message.into() });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_suggestion(mut self, suggestion: impl Into<String>) -> Diagnostic {
+        self.suggestions.push(suggestion.into());
+        self
+    }
+
+    /// Render in the style of rustc, with the offending lines underlined
+    pub fn render(&self, map: &SourceMap, color: bool) -> String {
+        let paint = |style: &str, text: &str| -> String {
+            if color {
+                format!("\x1B[{}m{}\x1B[0m", style, text)
+            } else {
+                text.to_string()
+            }
+        };
+
+        let severity_style = match self.severity {
+            Severity::Error => "1;31",
+            Severity::Warning => "1;33",
+        };
+        let gutter_style = "1;34";
+
+        let mut spans: Vec<(Span, &str, char, &str)> = vec![(self.span, "", '^', severity_style)];
+        for label in &self.labels {
+            spans.push((label.span, &label.message, '-', gutter_style));
+        }
+        spans.sort_by_key(|(span, ..)| span.start);
+
+        let last_line = spans.iter()
+            .map(|(span, ..)| map.location(span.start).line)
+            .max()
+            .unwrap_or(1);
+        let width = last_line.to_string().len();
+        let pad = " ".repeat(width);
+
+        let mut out = String::new();
+        let header = format!("{}[{}]", self.severity.as_str(), self.code());
+        _ = writeln!(out, "{}{}", paint(severity_style, &header), paint("1", &format!(": {}", self.message)));
+        _ = writeln!(out, "{}{} {}", pad, paint(gutter_style, "-->"), map.describe(self.span));
+        _ = writeln!(out, "{} {}", pad, paint(gutter_style, "|"));
+
+        let mut previous_line = 0;
+        for (span, message, marker, style) in &spans {
+            let start = map.location(span.start);
+            let text = map.line_text(start.line);
+            let line_len = text.chars().count();
+
+            // Spans running past the end of their line are only underlined up to it
+            let end = map.location(span.end);
+            let end_column = if end.line == start.line { end.column } else { line_len + 1 };
+            let underline_len = end_column.saturating_sub(start.column).max(1);
+
+            let underline = marker.to_string().repeat(underline_len);
+            let gutter = format!("{:>width$} |", start.line, width = width);
+
+            // Every span on the same line gets its own underline below a single copy of the line
+            if start.line != previous_line {
+                _ = writeln!(out, "{} {}", paint(gutter_style, &gutter), text);
+                previous_line = start.line;
+            }
+            _ = write!(out, "{} {}", paint(gutter_style, &format!("{} |", pad)), " ".repeat(start.column - 1));
+            if message.is_empty() {
+                _ = writeln!(out, "{}", paint(style, &underline));
+            } else {
+                _ = writeln!(out, "{}", paint(style, &format!("{} {}", underline, message)));
+            }
+        }
+
+        for note in &self.notes {
+            _ = writeln!(out, "{} {} note: {}", pad, paint(gutter_style, "="), note);
+        }
+        for suggestion in &self.suggestions {
+            _ = writeln!(out, "{} {} help: {}", pad, paint(gutter_style, "="), suggestion);
+        }
+
+        out
+    }
+
+    /// Render as a single line JSON object, for editor integration
+    pub fn to_json(&self, map: &SourceMap) -> String {
+        let span_json = |span: Span| -> String {
+            let start = map.location(span.start);
+            let end = map.location(span.end);
+            format!(
+                "{{\"start\":{},\"end\":{},\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{}}}",
+                span.start, span.end, start.line, start.column, end.line, end.column,
+            )
+        };
+
+        let strings = |items: &[String]| -> String {
+            let items: Vec<String> = items.iter().map(|s| json_string(s)).collect();
+            format!("[{}]", items.join(","))
+        };
+
+        let labels: Vec<String> = self.labels.iter()
+            .map(|l| format!("{{\"span\":{},\"message\":{}}}", span_json(l.span), json_string(&l.message)))
+            .collect();
+
+        format!(
+            "{{\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"file\":{},\"span\":{},\"labels\":[{}],\"notes\":{},\"suggestions\":{}}}",
+            self.severity.as_str(),
+            self.code(),
+            json_string(&self.message),
+            json_string(&map.name),
+            span_json(self.span),
+            labels.join(","),
+            strings(&self.notes),
+            strings(&self.suggestions),
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
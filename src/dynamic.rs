@@ -0,0 +1,477 @@
+use crate::ast::{
+    AssignExpr, Ast, BinaryExpr, BlockExpr, CallExpr, ExprStmt, IfExpr, IndexExpr, InjectExpr, IsExpr,
+    LambdaExpr, LenExpr, LetStmt, Node, NodeId, Param, PrimaryExpr, ProjectExpr, TupleExpr, UnaryExpr,
+    WhileExpr,
+};
+use crate::diagnostic::Diagnostic;
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::source::Span;
+use crate::types::{annotation, check_tuple_len, Type, TypeTable};
+use crate::Error;
+use std::collections::HashMap;
+
+/// Exit code of a dynamically typed program stopped by a failed check
+pub const TRAP_EXIT_CODE: i32 = 255;
+
+/// Why a dynamically typed program stopped. A failed check jumps to code printing the
+/// message with the location of the check, which exits with `TRAP_EXIT_CODE`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Trap {
+    /// `project` found a value of another type, or a tuple of another length
+    WrongType,
+    /// A function was called with the wrong number of arguments
+    WrongArity,
+    /// A tuple was indexed past its end
+    OutOfBounds,
+}
+
+impl Trap {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Trap::WrongType => "value has the wrong type",
+            Trap::WrongArity => "function called with the wrong number of arguments",
+            Trap::OutOfBounds => "tuple index out of bounds",
+        }
+    }
+}
+
+/// Tag in the low 3 bits of an `Any`, telling which ground type the rest of the word
+/// holds. Integers and booleans are shifted left by 3, tuples and functions are pointers
+/// whose low bits are free since allocations are 8-byte aligned
+pub fn any_tag(ty: &Type) -> i64 {
+    match ty {
+        Type::Int => 0b001,
+        Type::Tuple(_) => 0b010,
+        Type::Fn(..) => 0b011,
+        Type::Bool => 0b100,
+        Type::Void => 0b101,
+        Type::Str => 0b110,
+        _ => unreachable!("`{}` is not a ground type", ty),
+    }
+}
+
+/// Tag `id`, a value of the ground type `ty`
+pub fn inject(ast: &mut Ast, types: &mut TypeTable, id: NodeId, ty: &Type) -> NodeId {
+    // Blocks keep their shape, the value at their end gets injected instead
+    if let Node::Block(BlockExpr { value: Some(value), .. }) = ast[id] {
+        let value = inject(ast, types, value, ty);
+        if let Node::Block(block) = &mut ast[id] {
+            block.value = Some(value);
+        }
+        types.insert(id, Type::Any);
+        return id;
+    }
+
+    let span = ast.span(id);
+    let ty = annotation(ast, ty, span);
+    let inject = ast.add(Node::Inject(InjectExpr { expr: id, ty }), span);
+    types.insert(inject, Type::Any);
+    inject
+}
+
+/// Untag `id`, an `Any` expected to hold a value of the ground type `ty`. A failure
+/// reports the span of `id`
+pub fn project(ast: &mut Ast, types: &mut TypeTable, id: NodeId, ty: &Type) -> NodeId {
+    let span = ast.span(id);
+    let annotation = annotation(ast, ty, span);
+    let project = ast.add(Node::Project(ProjectExpr { expr: id, ty: annotation }), span);
+    types.insert(project, ty.clone());
+    project
+}
+
+/// `operator` applied to the `Any` variables `operands`, on integers or on booleans
+/// depending on the tag of the first one. The other operands have to match it
+pub fn dispatch(ast: &mut Ast, types: &mut TypeTable, operator: TokenKind, operands: &[(Symbol, Span)], span: Span) -> NodeId {
+    let mut branches = Vec::new();
+    for ty in [Type::Int, Type::Bool] {
+        let mut args = Vec::new();
+        for (name, name_span) in operands {
+            let var = ast.add_variable(*name, *name_span);
+            types.insert(var, Type::Any);
+            args.push(project(ast, types, var, &ty));
+        }
+
+        let node = match args[..] {
+            [operand] => Node::Unary(UnaryExpr { operator: operator.clone(), operand }),
+            [left, right] => Node::Binary(BinaryExpr { operator: operator.clone(), left, right }),
+            _ => unreachable!("Operators take one or two operands"),
+        };
+        let result = ast.add(node, span);
+        types.insert(result, ty.clone());
+        let value = inject(ast, types, result, &ty);
+        let branch = ast.add(Node::Block(BlockExpr { stmts: Vec::new(), value: Some(value) }), span);
+        types.insert(branch, Type::Any);
+        branches.push(branch);
+    }
+
+    let (first, first_span) = operands[0];
+    let var = ast.add_variable(first, first_span);
+    types.insert(var, Type::Any);
+    let int = annotation(ast, &Type::Int, first_span);
+    let is_int = ast.add(Node::Is(IsExpr { expr: var, ty: int }), first_span);
+    types.insert(is_int, Type::Bool);
+
+    let choice = ast.add(Node::If(IfExpr {
+        cond: is_int,
+        then: branches[0],
+        otherwise: Some(branches[1]),
+    }), span);
+    types.insert(choice, Type::Any);
+    choice
+}
+
+/// Compiles a program without type annotations in place of the type checker. Every
+/// value is an `Any` and the operations on values are wrapped in explicit conversions:
+///
+/// ```text
+/// a + 1    =>  (inject (+ (project a Int) (project (inject 1 Int) Int)) Int)
+/// f(x)     =>  (call (project f fn(Any) -> Any) x)
+/// ```
+///
+/// A `project` of a value of another type traps at runtime instead of being a compile
+/// error. `&`, `|` and `~` work on both integers and booleans, so they test the tag of
+/// their first operand to pick one. Top-level functions take and return `Any`, calls to
+/// them by name stay direct. Only scoping errors are reported at compile time, the
+/// table returned has the type of every node like the one of `type_check`.
+pub fn lower_dynamic(ast: &mut Ast, program: NodeId) -> Result<TypeTable, Diagnostic> {
+    let (functions, main) = match &ast[program] {
+        Node::Program(p) => (p.functions.clone(), p.main),
+        _ => panic!("Dynamic lowering expects a program"),
+    };
+
+    let mut lowering = Lowering {
+        ast,
+        types: TypeTable::new(),
+        functions: HashMap::new(),
+        locals: Vec::new(),
+        counter: 0,
+    };
+
+    for id in &functions {
+        if let Node::FnDecl(decl) = &lowering.ast[*id] {
+            if let Some(span) = decl.generics.first().map(|_| decl.name_span) {
+                return Err(unexpected_annotation(span));
+            }
+            lowering.functions.insert(decl.name, decl.params.len());
+        }
+    }
+
+    for id in &functions {
+        let mut decl = match &lowering.ast[*id] {
+            Node::FnDecl(decl) => decl.clone(),
+            _ => unreachable!("Top-level item is not a function"),
+        };
+
+        lowering.signature(&mut decl.params, &mut decl.ret, decl.name_span)?;
+        lowering.locals = decl.params.iter().map(|p| p.name).collect();
+        decl.body = lowering.value(decl.body)?;
+        lowering.locals.clear();
+
+        let ty = fn_type(decl.params.len());
+        lowering.ast.replace(*id, Node::FnDecl(decl)).expect("Stale function");
+        lowering.types.insert(*id, ty);
+    }
+
+    lowering.lower(main)?;
+    lowering.types.insert(program, Type::Void);
+
+    Ok(lowering.types)
+}
+
+/// Type of the functions taking `arity` arguments, the only one they can have
+fn fn_type(arity: usize) -> Type {
+    Type::Fn(vec![Type::Any; arity], Box::new(Type::Any))
+}
+
+fn unexpected_annotation(span: Span) -> Diagnostic {
+    Diagnostic::error(Error::UnexpectedAnnotation, span)
+        .with_message("dynamically typed programs cannot have type annotations")
+        .with_suggestion("remove the annotation, or compile without `--dynamic`")
+}
+
+struct Lowering<'a> {
+    ast: &'a mut Ast,
+    types: TypeTable,
+    /// Arity of every top-level function
+    functions: HashMap<Symbol, usize>,
+    /// Variables in scope, innermost last
+    locals: Vec<Symbol>,
+    counter: usize,
+}
+
+impl Lowering<'_> {
+    fn add(&mut self, node: Node, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add(node, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn fresh(&mut self) -> Symbol {
+        let name = Symbol::intern(&format!("dyn.{}", self.counter));
+        self.counter += 1;
+        name
+    }
+
+    fn inject(&mut self, id: NodeId, ty: &Type) -> NodeId {
+        inject(self.ast, &mut self.types, id, ty)
+    }
+
+    fn project(&mut self, id: NodeId, ty: &Type) -> NodeId {
+        project(self.ast, &mut self.types, id, ty)
+    }
+
+    /// Parameters and return type become `Any`, they must not be annotated
+    fn signature(&mut self, params: &mut [Param], ret: &mut Option<NodeId>, span: Span) -> Result<(), Diagnostic> {
+        if let Some(ty) = params.iter().filter_map(|p| p.ty).chain(*ret).next() {
+            return Err(unexpected_annotation(self.ast.span(ty)));
+        }
+
+        for param in params.iter_mut() {
+            param.ty = Some(annotation(self.ast, &Type::Any, param.name_span));
+        }
+        *ret = Some(annotation(self.ast, &Type::Any, span));
+        Ok(())
+    }
+
+    /// Lower `id` where any value is needed, the result is an `Any`
+    fn value(&mut self, id: NodeId) -> Result<NodeId, Diagnostic> {
+        let ty = self.lower(id)?;
+        Ok(self.inject_lowered(id, ty))
+    }
+
+    /// Lower `id` where a value of the ground type `ty` is needed, only values not known
+    /// to have it get projected
+    fn typed(&mut self, id: NodeId, ty: &Type) -> Result<NodeId, Diagnostic> {
+        match self.lower(id)? {
+            found if found == *ty => Ok(id),
+            Type::Any => Ok(self.project(id, ty)),
+            // Fails at runtime, like any other mismatch
+            found => {
+                let id = self.inject(id, &found);
+                Ok(self.project(id, ty))
+            }
+        }
+    }
+
+    /// Lower `id` where its value is discarded, the result is `Void`
+    fn effect(&mut self, id: NodeId) -> Result<NodeId, Diagnostic> {
+        if self.lower(id)? == Type::Void {
+            return Ok(id);
+        }
+
+        let span = self.ast.span(id);
+        let value = match &mut self.ast[id] {
+            // The value of the block becomes its last statement
+            Node::Block(block) => block.value.take(),
+            _ => None,
+        };
+        if let Some(value) = value {
+            let stmt = self.add(Node::ExprStmt(ExprStmt { expr: value }), span, Type::Void);
+            if let Node::Block(block) = &mut self.ast[id] {
+                block.stmts.push(stmt);
+            }
+            self.types.insert(id, Type::Void);
+            return Ok(id);
+        }
+
+        let stmt = self.add(Node::ExprStmt(ExprStmt { expr: id }), span, Type::Void);
+        Ok(self.add(Node::Block(BlockExpr { stmts: vec![stmt], value: None }), span, Type::Void))
+    }
+
+    /// Bind the operands to temporaries, then apply `operator` to what their tags say
+    fn dispatch(&mut self, id: NodeId, operator: TokenKind, operands: &[NodeId]) -> Result<Node, Diagnostic> {
+        let span = self.ast.span(id);
+        let mut stmts = Vec::new();
+        let mut temps = Vec::new();
+
+        for operand in operands {
+            let init = self.value(*operand)?;
+            let temp = self.fresh();
+            let operand_span = self.ast.span(init);
+            stmts.push(self.add(Node::Let(LetStmt {
+                name: temp,
+                name_span: operand_span,
+                ty: None,
+                init,
+            }), operand_span, Type::Void));
+            temps.push((temp, operand_span));
+        }
+
+        let choice = dispatch(self.ast, &mut self.types, operator, &temps, span);
+        Ok(Node::Block(BlockExpr { stmts, value: Some(choice) }))
+    }
+
+    /// Rewrite the node behind `id` in place and record its type. Values are `Any` unless
+    /// their ground type is known, like the one of a literal or of an arithmetic operation,
+    /// they get injected only where an `Any` is needed. Statements are `Void`
+    fn lower(&mut self, id: NodeId) -> Result<Type, Diagnostic> {
+        use TokenKind as T;
+
+        let span = self.ast.span(id);
+        let scope = self.locals.len();
+
+        let (node, ty) = match self.ast[id].clone() {
+            Node::Primary(PrimaryExpr { value: Token { kind, .. } }) => {
+                let ty = match kind {
+                    T::Integer(_) => Type::Int,
+                    T::True | T::False => Type::Bool,
+                    T::String(_) => Type::Str,
+                    T::Identifier(name) if self.locals.contains(&name) => Type::Any,
+                    T::Identifier(name) => match self.functions.get(&name) {
+                        Some(arity) => fn_type(*arity),
+                        None => {
+                            let diag = Diagnostic::error(Error::UnknownVariable, span)
+                                .with_message(format!("cannot find `{}` in this scope", name));
+                            return Err(diag);
+                        }
+                    },
+                    _ => unreachable!("Parser only produces literals and identifiers as primaries"),
+                };
+                self.types.insert(id, ty.clone());
+                return Ok(ty);
+            }
+
+            Node::Unary(u) => match u.operator {
+                T::Minus => {
+                    let operand = self.typed(u.operand, &Type::Int)?;
+                    (Node::Unary(UnaryExpr { operator: u.operator, operand }), Type::Int)
+                }
+                _ => (self.dispatch(id, u.operator, &[u.operand])?, Type::Any),
+            },
+
+            Node::Binary(b) => match b.operator {
+                T::And | T::Or => (self.dispatch(id, b.operator, &[b.left, b.right])?, Type::Any),
+                // Equality compares the tagged values, so it works across types
+                T::Equal | T::NotEqual => {
+                    let left = self.value(b.left)?;
+                    let right = self.value(b.right)?;
+                    (Node::Binary(BinaryExpr { operator: b.operator, left, right }), Type::Bool)
+                }
+                operator => {
+                    let left = self.typed(b.left, &Type::Int)?;
+                    let right = self.typed(b.right, &Type::Int)?;
+                    let ty = match operator {
+                        T::Lt | T::LtEq | T::Gt | T::GtEq => Type::Bool,
+                        _ => Type::Int,
+                    };
+                    (Node::Binary(BinaryExpr { operator, left, right }), ty)
+                }
+            },
+
+            Node::Block(b) => {
+                for stmt in &b.stmts {
+                    self.lower(*stmt)?;
+                }
+                let ty = match b.value {
+                    Some(value) => self.lower(value)?,
+                    None => Type::Void,
+                };
+                (Node::Block(b), ty)
+            }
+
+            Node::If(i) => {
+                let cond = self.typed(i.cond, &Type::Bool)?;
+                let (then, otherwise, ty) = match i.otherwise {
+                    // Branches of different types meet at `Any`
+                    Some(otherwise) => match (self.lower(i.then)?, self.lower(otherwise)?) {
+                        (then, other) if then == other => (i.then, Some(otherwise), then),
+                        (then, other) => {
+                            let then = self.inject_lowered(i.then, then);
+                            let otherwise = self.inject_lowered(otherwise, other);
+                            (then, Some(otherwise), Type::Any)
+                        }
+                    },
+                    None => (self.effect(i.then)?, None, Type::Void),
+                };
+                (Node::If(IfExpr { cond, then, otherwise }), ty)
+            }
+
+            Node::While(w) => {
+                let cond = self.typed(w.cond, &Type::Bool)?;
+                let body = self.effect(w.body)?;
+                (Node::While(WhileExpr { cond, body }), Type::Void)
+            }
+
+            Node::Assign(a) => {
+                self.lower(a.target)?;
+                let value = self.value(a.value)?;
+                (Node::Assign(AssignExpr { target: a.target, value }), Type::Void)
+            }
+
+            Node::Break => (Node::Break, Type::Void),
+            Node::Continue => (Node::Continue, Type::Void),
+
+            // Calls to top-level functions by name stay direct, the parser checked their arity
+            Node::Call(c) => {
+                let callee = self.typed(c.callee, &fn_type(c.args.len()))?;
+                let mut args = Vec::new();
+                for arg in &c.args {
+                    args.push(self.value(*arg)?);
+                }
+                (Node::Call(CallExpr { callee, args }), Type::Any)
+            }
+
+            Node::Tuple(t) => {
+                check_tuple_len(t.elems.len(), span)?;
+                let mut elems = Vec::new();
+                for elem in &t.elems {
+                    elems.push(self.value(*elem)?);
+                }
+                (Node::Tuple(TupleExpr { elems }), Type::Tuple(vec![Type::Any; t.elems.len()]))
+            }
+
+            Node::Index(i) => {
+                let tuple = self.value(i.tuple)?;
+                let index = self.typed(i.index, &Type::Int)?;
+                (Node::Index(IndexExpr { tuple, index }), Type::Any)
+            }
+
+            Node::Len(l) => {
+                let tuple = self.value(l.tuple)?;
+                (Node::Len(LenExpr { tuple }), Type::Int)
+            }
+
+            Node::Lambda(mut lambda) => {
+                self.signature(&mut lambda.params, &mut lambda.ret, span)?;
+                self.locals.extend(lambda.params.iter().map(|p| p.name));
+                let body = self.value(lambda.body)?;
+                let ty = fn_type(lambda.params.len());
+                (Node::Lambda(LambdaExpr { params: lambda.params, ret: lambda.ret, body }), ty)
+            }
+
+            Node::Let(l) => {
+                if let Some(ty) = l.ty {
+                    return Err(unexpected_annotation(self.ast.span(ty)));
+                }
+                let init = self.value(l.init)?;
+                self.ast.replace(id, Node::Let(LetStmt { init, ..l })).expect("Stale let");
+                self.types.insert(id, Type::Void);
+
+                // Stays in scope until the enclosing block ends
+                self.locals.push(l.name);
+                return Ok(Type::Void);
+            }
+
+            Node::ExprStmt(e) => {
+                self.lower(e.expr)?;
+                (Node::ExprStmt(e), Type::Void)
+            }
+
+            node => unreachable!("Unexpected node before lowering: {:?}", node),
+        };
+
+        self.locals.truncate(scope);
+        self.ast.replace(id, node).expect("Stale node");
+        self.types.insert(id, ty.clone());
+        Ok(ty)
+    }
+
+    /// `id`, already lowered to `ty`, as an `Any`
+    fn inject_lowered(&mut self, id: NodeId, ty: Type) -> NodeId {
+        match ty {
+            Type::Any => id,
+            ty => self.inject(id, &ty),
+        }
+    }
+}
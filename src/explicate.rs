@@ -0,0 +1,417 @@
+use crate::ast::{Ast, Node, NodeId, PrimaryExpr};
+use crate::cir::{Atom, BinaryOp, Block, Callee, Cmp, Exp, Function, Label, Program, Stmt, Tail, UnaryOp};
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::types::{annotated, Type, TypeTable};
+use crate::uniquify::Renames;
+
+/// Lowers a program in monadic normal form into basic blocks, making the order of
+/// evaluation and every jump explicit:
+///
+/// ```text
+/// let x = if a < b { 1 } else { 2 }; x
+///
+/// main.start:
+///     if a < b goto block.0; else goto block.1;
+/// block.0:
+///     x = 1;
+///     goto block.2;
+/// ...
+/// ```
+///
+/// Expressions are lowered in one of four contexts: in tail position their value is
+/// returned, in an assignment it goes to a variable, as a condition it picks a branch
+/// and as an effect it is dropped. Each context gets the code that runs after it, so a
+/// condition that is itself an `if` jumps straight to the branches of the outer one.
+/// Code reached from two places gets a block of its own and is jumped to rather than
+/// copied. The value of `main` is returned from a `main` function.
+pub fn explicate_control(ast: &Ast, program: NodeId, types: &TypeTable, renames: &mut Renames) -> Program {
+    let (decls, main) = match &ast[program] {
+        Node::Program(p) => (p.functions.clone(), p.main),
+        _ => panic!("Explicating control expects a program"),
+    };
+
+    let mut explicator = Explicator {
+        ast,
+        types,
+        renames,
+        blocks: Vec::new(),
+        locals: Vec::new(),
+        loops: Vec::new(),
+        counter: 0,
+    };
+
+    let mut functions = Vec::new();
+    for id in decls {
+        let decl = match &ast[id] {
+            Node::FnDecl(decl) => decl,
+            _ => unreachable!("Top-level item is not a function"),
+        };
+        let (param_types, ret) = match types.get(id) {
+            Type::Fn(params, ret) => (params.clone(), (**ret).clone()),
+            ty => unreachable!("Function typed `{}`", ty),
+        };
+        let params = decl.params.iter().map(|p| p.name).zip(param_types).collect();
+        functions.push(explicator.function(decl.name, params, ret, decl.body));
+    }
+
+    let ret = types.get(main).clone();
+    functions.push(explicator.function(Symbol::intern("main"), Vec::new(), ret, main));
+    Program { functions }
+}
+
+fn prepend(stmt: Stmt, block: Block) -> Block {
+    let mut stmts = vec![stmt];
+    stmts.extend(block.stmts);
+    Block { stmts, tail: block.tail }
+}
+
+fn jump(label: Label) -> Block {
+    Block { stmts: Vec::new(), tail: Tail::Goto(label) }
+}
+
+fn returning(e: Exp) -> Block {
+    Block { stmts: Vec::new(), tail: Tail::Return(e) }
+}
+
+fn binary_op(operator: &TokenKind) -> BinaryOp {
+    use TokenKind as T;
+
+    match operator {
+        T::Plus => BinaryOp::Add,
+        T::Minus => BinaryOp::Sub,
+        T::Star => BinaryOp::Mul,
+        T::Slash => BinaryOp::Div,
+        T::Modulo => BinaryOp::Mod,
+        T::ShiftLeft => BinaryOp::Shl,
+        T::ShiftRight => BinaryOp::Shr,
+        T::And => BinaryOp::And,
+        T::Or => BinaryOp::Or,
+        T::Equal => BinaryOp::Cmp(Cmp::Eq),
+        T::NotEqual => BinaryOp::Cmp(Cmp::Ne),
+        T::Lt => BinaryOp::Cmp(Cmp::Lt),
+        T::LtEq => BinaryOp::Cmp(Cmp::Le),
+        T::Gt => BinaryOp::Cmp(Cmp::Gt),
+        T::GtEq => BinaryOp::Cmp(Cmp::Ge),
+        _ => unreachable!("`{}` is not a binary operator", operator),
+    }
+}
+
+struct Explicator<'a> {
+    ast: &'a Ast,
+    types: &'a TypeTable,
+    renames: &'a mut Renames,
+    /// Blocks of the function being lowered, other than its entry
+    blocks: Vec<(Label, Block)>,
+    locals: Vec<(Symbol, Type)>,
+    /// Where `continue` and `break` jump to in the loops around the current node
+    loops: Vec<(Label, Label)>,
+    counter: usize,
+}
+
+impl Explicator<'_> {
+    fn function(&mut self, name: Symbol, params: Vec<(Symbol, Type)>, ret: Type, body: NodeId) -> Function {
+        let entry = self.tail(body);
+
+        // Code is lowered back to front, reversed the blocks and locals come in about the
+        // order of the source
+        let mut blocks = vec![(Symbol::intern(&format!("{}.start", name)), entry)];
+        blocks.extend(self.blocks.drain(..).rev());
+        let mut locals = std::mem::take(&mut self.locals);
+        locals.reverse();
+
+        Function {
+            name,
+            params,
+            ret,
+            locals,
+            blocks,
+        }
+    }
+
+    /// Label to jump to for `block`, which gets added as a block of its own unless it is
+    /// a jump already
+    fn label(&mut self, block: Block) -> Label {
+        if let (true, Tail::Goto(label)) = (block.stmts.is_empty(), &block.tail) {
+            return *label;
+        }
+        let label = Symbol::intern(&format!("block.{}", self.counter));
+        self.counter += 1;
+        self.blocks.push((label, block));
+        label
+    }
+
+    fn atom(&self, id: NodeId) -> Atom {
+        use TokenKind as T;
+
+        match &self.ast[id] {
+            Node::Primary(PrimaryExpr { value: Token { kind, .. } }) => match kind {
+                T::Integer(n) => Atom::Int(*n),
+                T::True => Atom::Bool(true),
+                T::False => Atom::Bool(false),
+                T::String(s) => Atom::Str(*s),
+                T::Identifier(name) => Atom::Var(*name),
+                _ => unreachable!("Parser only produces literals and identifiers as primaries"),
+            },
+            node => unreachable!("Operand is not atomic: {:?}", node),
+        }
+    }
+
+    /// Expression computing one value from atoms
+    fn exp(&self, id: NodeId) -> Exp {
+        match &self.ast[id] {
+            Node::Primary(_) => Exp::Atom(self.atom(id)),
+            Node::Unary(u) => {
+                let op = match u.operator {
+                    TokenKind::Minus => UnaryOp::Neg,
+                    TokenKind::Tilde => UnaryOp::Not,
+                    _ => unreachable!("`{}` is not a unary operator", u.operator),
+                };
+                Exp::Unary(op, self.atom(u.operand))
+            }
+            Node::Binary(b) => Exp::Binary(binary_op(&b.operator), self.atom(b.left), self.atom(b.right)),
+            Node::Index(i) => Exp::Index(self.atom(i.tuple), self.atom(i.index), self.ast.span(id)),
+            Node::Len(l) => Exp::Len(self.atom(l.tuple), self.ast.span(id)),
+            Node::Allocate(a) => Exp::Allocate(a.len, a.tag),
+            Node::GlobalValue(name) => Exp::GlobalValue(*name),
+            Node::FunRef(name) => Exp::FunRef(*name),
+            Node::Call(c) => {
+                let callee = match &self.ast[c.callee] {
+                    Node::FunRef(name) => Callee::Direct(*name),
+                    _ => Callee::Indirect(self.atom(c.callee)),
+                };
+                Exp::Call(callee, c.args.iter().map(|a| self.atom(*a)).collect())
+            }
+            Node::Inject(i) => Exp::Inject(self.atom(i.expr), self.types.get(i.expr).clone()),
+            Node::Project(p) => Exp::Project(self.atom(p.expr), self.types.get(id).clone(), self.ast.span(id)),
+            Node::Is(i) => Exp::Is(self.atom(i.expr), annotated(self.ast, i.ty)),
+            Node::Proxy(p) => Exp::Proxy(self.atom(p.tuple), self.atom(p.reads), self.atom(p.writes)),
+            node => unreachable!("Not a simple expression: {:?}", node),
+        }
+    }
+
+    /// Code running the statement `id`, then `cont`
+    fn stmt(&mut self, id: NodeId, cont: Block) -> Block {
+        match &self.ast[id] {
+            Node::Let(l) => {
+                self.locals.push((l.name, self.types.get(l.init).clone()));
+                self.assign(l.init, l.name, cont)
+            }
+            Node::ExprStmt(e) => self.effect(e.expr, cont),
+            node => unreachable!("Not a statement: {:?}", node),
+        }
+    }
+
+    /// Code running `stmts` in order, then `cont`
+    fn stmts(&mut self, stmts: &[NodeId], cont: Block) -> Block {
+        let mut block = cont;
+        for stmt in stmts.iter().rev() {
+            block = self.stmt(*stmt, block);
+        }
+        block
+    }
+
+    /// Code returning the value of `id`
+    fn tail(&mut self, id: NodeId) -> Block {
+        match &self.ast[id] {
+            Node::Block(b) => {
+                let value = match b.value {
+                    Some(value) => self.tail(value),
+                    None => returning(Exp::Atom(Atom::Void)),
+                };
+                self.stmts(&b.stmts, value)
+            }
+            Node::If(i) => {
+                let then = self.tail(i.then);
+                let otherwise = match i.otherwise {
+                    Some(otherwise) => self.tail(otherwise),
+                    None => returning(Exp::Atom(Atom::Void)),
+                };
+                self.pred(i.cond, then, otherwise)
+            }
+            Node::While(_) | Node::Assign(_) | Node::Break | Node::Continue | Node::Collect(_) => {
+                self.effect(id, returning(Exp::Atom(Atom::Void)))
+            }
+            _ => returning(self.exp(id)),
+        }
+    }
+
+    /// Code assigning the value of `id` to `name`, then running `cont`
+    fn assign(&mut self, id: NodeId, name: Symbol, cont: Block) -> Block {
+        match &self.ast[id] {
+            Node::Block(b) => {
+                let value = match b.value {
+                    Some(value) => self.assign(value, name, cont),
+                    None => prepend(Stmt::Assign(name, Exp::Atom(Atom::Void)), cont),
+                };
+                self.stmts(&b.stmts, value)
+            }
+            Node::If(i) => {
+                let join = jump(self.label(cont));
+                let then = self.assign(i.then, name, join.clone());
+                let otherwise = match i.otherwise {
+                    Some(otherwise) => self.assign(otherwise, name, join),
+                    None => prepend(Stmt::Assign(name, Exp::Atom(Atom::Void)), join),
+                };
+                self.pred(i.cond, then, otherwise)
+            }
+            Node::While(_) | Node::Assign(_) | Node::Break | Node::Continue | Node::Collect(_) => {
+                let cont = prepend(Stmt::Assign(name, Exp::Atom(Atom::Void)), cont);
+                self.effect(id, cont)
+            }
+            _ => prepend(Stmt::Assign(name, self.exp(id)), cont),
+        }
+    }
+
+    /// Code evaluating `id` for its effects only, then running `cont`
+    fn effect(&mut self, id: NodeId, cont: Block) -> Block {
+        match &self.ast[id] {
+            Node::Block(b) => {
+                let value = match b.value {
+                    Some(value) => self.effect(value, cont),
+                    None => cont,
+                };
+                self.stmts(&b.stmts, value)
+            }
+            Node::If(i) => {
+                let join = jump(self.label(cont));
+                let then = self.effect(i.then, join.clone());
+                let otherwise = match i.otherwise {
+                    Some(otherwise) => self.effect(otherwise, join),
+                    None => join,
+                };
+                self.pred(i.cond, then, otherwise)
+            }
+            Node::While(w) => {
+                let head = Symbol::intern(&format!("loop.{}", self.counter));
+                self.counter += 1;
+                let after = self.label(cont);
+
+                self.loops.push((head, after));
+                let body = self.effect(w.body, jump(head));
+                self.loops.pop();
+
+                // The condition is checked before every iteration, `continue` included
+                let check = self.pred(w.cond, body, jump(after));
+                self.blocks.push((head, check));
+                jump(head)
+            }
+            Node::Assign(a) => match &self.ast[a.target] {
+                Node::Index(target) => {
+                    let span = self.ast.span(a.target);
+                    let stmt = Stmt::SetIndex(self.atom(target.tuple), self.atom(target.index), self.atom(a.value), span);
+                    prepend(stmt, cont)
+                }
+                Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => {
+                    self.assign(a.value, *name, cont)
+                }
+                node => unreachable!("Invalid assignment target: {:?}", node),
+            },
+            // What would run after the jump is unreachable
+            Node::Break => jump(self.loops.last().expect("`break` outside of a loop").1),
+            Node::Continue => jump(self.loops.last().expect("`continue` outside of a loop").0),
+            Node::Collect(c) => prepend(Stmt::Collect(c.bytes), cont),
+            Node::Primary(_) => cont,
+            _ => prepend(Stmt::Exp(self.exp(id)), cont),
+        }
+    }
+
+    /// Code evaluating the condition `id`, then running `then` or `otherwise`
+    fn pred(&mut self, id: NodeId, then: Block, otherwise: Block) -> Block {
+        use TokenKind as T;
+
+        match &self.ast[id] {
+            Node::Primary(PrimaryExpr { value: Token { kind: T::True, .. } }) => then,
+            Node::Primary(PrimaryExpr { value: Token { kind: T::False, .. } }) => otherwise,
+            Node::Primary(_) => {
+                let cond = self.atom(id);
+                self.branch(Cmp::Eq, cond, Atom::Bool(true), then, otherwise)
+            }
+            Node::Unary(u) if u.operator == T::Tilde => {
+                let cond = self.atom(u.operand);
+                self.branch(Cmp::Eq, cond, Atom::Bool(false), then, otherwise)
+            }
+            Node::Binary(b) => match binary_op(&b.operator) {
+                BinaryOp::Cmp(cmp) => {
+                    let (left, right) = (self.atom(b.left), self.atom(b.right));
+                    self.branch(cmp, left, right, then, otherwise)
+                }
+                _ => self.test(id, then, otherwise),
+            },
+            Node::Block(b) => {
+                let value = b.value.expect("Condition without a value");
+                let cond = self.pred(value, then, otherwise);
+                self.stmts(&b.stmts, cond)
+            }
+            // Both conditions jump to the same two blocks
+            Node::If(i) => {
+                let then = jump(self.label(then));
+                let otherwise = jump(self.label(otherwise));
+                let inner_then = self.pred(i.then, then.clone(), otherwise.clone());
+                let inner_otherwise = match i.otherwise {
+                    Some(inner) => self.pred(inner, then, otherwise),
+                    None => unreachable!("`if` without `else` used as a condition"),
+                };
+                self.pred(i.cond, inner_then, inner_otherwise)
+            }
+            _ => self.test(id, then, otherwise),
+        }
+    }
+
+    /// Branch on the value of `id` computed into a temporary
+    fn test(&mut self, id: NodeId, then: Block, otherwise: Block) -> Block {
+        let temp = self.renames.fresh("tmp");
+        self.locals.push((temp, Type::Bool));
+        let cond = self.branch(Cmp::Eq, Atom::Var(temp), Atom::Bool(true), then, otherwise);
+        prepend(Stmt::Assign(temp, self.exp(id)), cond)
+    }
+
+    fn branch(&mut self, cmp: Cmp, left: Atom, right: Atom, then: Block, otherwise: Block) -> Block {
+        let then = self.label(then);
+        let otherwise = self.label(otherwise);
+        let tail = Tail::If { cmp, left, right, then, otherwise };
+        Block { stmts: Vec::new(), tail }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::rco::remove_complex_operands;
+    use crate::types::type_check;
+    use crate::uniquify::uniquify;
+
+    /// Block IR of `source`, a program without functions or tuples
+    fn explicate(source: &str) -> Program {
+        let mut parser = Parser::from_source(source);
+        let root = parser.parse().expect("Parse error");
+        let mut ast = parser.ast;
+        let mut types = type_check(&ast, root).expect("Type error");
+        let mut renames = uniquify(&mut ast, root);
+        remove_complex_operands(&mut ast, root, &mut types, &mut renames);
+        explicate_control(&ast, root, &types, &mut renames)
+    }
+
+    #[test]
+    fn if_as_condition_jumps_to_the_outer_branches() {
+        let program = explicate("let a = 1;\nlet b = 2;\nif if a < b { b == 2 } else { false } { 10 } else { 20 }");
+        let main = &program.functions[0];
+
+        // The inner `if` picks a branch of the outer one, no bool is kept in between
+        assert!(main.locals.iter().all(|(_, ty)| *ty == Type::Int), "{:?}", main.locals);
+        assert_eq!(main.blocks.len(), 4);
+
+        let block = |label: Label| &main.blocks.iter().find(|(l, _)| *l == label).expect("Missing block").1;
+        let Tail::If { cmp: Cmp::Lt, then: inner, otherwise, .. } = main.blocks[0].1.tail else {
+            panic!("Entry block does not test `a < b`");
+        };
+        let Tail::If { cmp: Cmp::Eq, then, otherwise: inner_otherwise, .. } = block(inner).tail else {
+            panic!("Inner branch does not test `b == 2`");
+        };
+        // `else { false }` goes straight to the outer `else`
+        assert_eq!(inner_otherwise, otherwise);
+        assert_eq!(block(then).tail, Tail::Return(Exp::Atom(Atom::Int(10))));
+        assert_eq!(block(otherwise).tail, Tail::Return(Exp::Atom(Atom::Int(20))));
+    }
+}
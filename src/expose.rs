@@ -0,0 +1,181 @@
+use crate::ast::{
+    AllocateExpr, AssignExpr, Ast, BinaryExpr, BlockExpr, CollectExpr, ExprStmt, IfExpr, IndexExpr,
+    LetStmt, Node, NodeId,
+};
+use crate::intern::Symbol;
+use crate::lexer::TokenKind;
+use crate::source::Span;
+use crate::types::{Type, TypeTable};
+
+/// The pointer mask of a tag only has room for this many elements
+pub const MAX_TUPLE_LEN: usize = 50;
+
+/// Set in the header of a tuple proxy, which holds the proxied tuple followed by its
+/// read and write functions. Proxies are allocated by the runtime, the accesses to a
+/// tuple that may be one test this bit to go through the functions
+pub const PROXY_BIT: i64 = 1 << 57;
+
+/// A closure has the arity of its function in the header bits from this one on, so a
+/// call through an `Any` can check it
+pub const ARITY_SHIFT: i64 = 58;
+
+/// Header word of a heap allocated tuple. Bit 0 is set until the collector copies the
+/// tuple, bits 1 to 6 hold the length and bit `7 + i` is set when element `i` is a
+/// pointer the collector has to follow.
+pub fn tuple_tag(len: usize, pointer_mask: u64) -> i64 {
+    assert!(len <= MAX_TUPLE_LEN, "Tuple too long for its tag");
+    let tag = 1 | ((len as u64) << 1) | (pointer_mask << 7);
+    tag as i64
+}
+
+/// Bytes taken by a tuple on the heap, header included
+pub fn tuple_size(len: usize) -> i64 {
+    8 * (len as i64 + 1)
+}
+
+/// Lowers every tuple literal below `root` into an explicit allocation:
+///
+/// ```text
+/// {
+///     let tup.0 = e0; ..
+///     if (global free_ptr) + size < (global fromspace_end) {} else { (collect size) }
+///     let alloc = (allocate len tag);
+///     alloc[0] = tup.0; ..
+///     alloc
+/// }
+/// ```
+///
+/// Elements are evaluated before the check so a collection cannot happen with the
+/// tuple half initialized. The pointer mask of the tag comes from the element types, the
+/// tag of a closure also holds its arity, and every node created along the way gets its
+/// type recorded in `types`.
+pub fn expose_allocation(ast: &mut Ast, root: NodeId, types: &mut TypeTable) {
+    let mut counter = 0;
+
+    for id in ast.post_order(root) {
+        let elems = match &ast[id] {
+            Node::Tuple(t) => t.elems.clone(),
+            _ => continue,
+        };
+
+        let mask = elems.iter()
+            .enumerate()
+            .filter(|(_, e)| types.is_pointer(**e))
+            .fold(0u64, |mask, (i, _)| mask | (1 << i));
+
+        let span = ast.span(id);
+        let mut exposer = Exposer { ast, types, span };
+        let block = exposer.expose_tuple(id, &elems, mask, counter);
+        counter += 1;
+
+        ast.replace(id, block).expect("Stale tuple");
+    }
+}
+
+/// Adds nodes together with their type
+struct Exposer<'a> {
+    ast: &'a mut Ast,
+    types: &'a mut TypeTable,
+    span: Span,
+}
+
+impl Exposer<'_> {
+    fn add(&mut self, node: Node, ty: Type) -> NodeId {
+        let id = self.ast.add(node, self.span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn variable(&mut self, name: Symbol, ty: Type) -> NodeId {
+        let id = self.ast.add_variable(name, self.span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn integer(&mut self, value: i64) -> NodeId {
+        let id = self.ast.add_integer(value, self.span);
+        self.types.insert(id, Type::Int);
+        id
+    }
+
+    fn binary(&mut self, operator: TokenKind, left: NodeId, right: NodeId, ty: Type) -> NodeId {
+        self.add(Node::Binary(BinaryExpr {
+            operator,
+            left,
+            right,
+        }), ty)
+    }
+
+    fn expose_tuple(&mut self, id: NodeId, elems: &[NodeId], mask: u64, n: usize) -> Node {
+        let tuple_ty = self.types.get(id).clone();
+        let elem_types: Vec<Type> = elems.iter().map(|e| self.types.get(*e).clone()).collect();
+        let size = tuple_size(elems.len());
+        let mut stmts = Vec::new();
+
+        let temps: Vec<Symbol> = (0..elems.len())
+            .map(|i| Symbol::intern(&format!("tup.{}.{}", n, i)))
+            .collect();
+
+        for (temp, elem) in temps.iter().zip(elems) {
+            let elem_span = self.ast.span(*elem);
+            let bind = self.ast.add(Node::Let(LetStmt {
+                name: *temp,
+                name_span: elem_span,
+                ty: None,
+                init: *elem,
+            }), elem_span);
+            self.types.insert(bind, Type::Void);
+            stmts.push(bind);
+        }
+
+        let free_ptr = self.add(Node::GlobalValue(Symbol::intern("free_ptr")), Type::Int);
+        let fromspace_end = self.add(Node::GlobalValue(Symbol::intern("fromspace_end")), Type::Int);
+        let size_lit = self.integer(size);
+        let end = self.binary(TokenKind::Plus, free_ptr, size_lit, Type::Int);
+        let fits = self.binary(TokenKind::Lt, end, fromspace_end, Type::Bool);
+
+        let nothing = self.add(Node::Block(BlockExpr { stmts: Vec::new(), value: None }), Type::Void);
+        let collect = self.add(Node::Collect(CollectExpr { bytes: size }), Type::Void);
+        let collect = self.add(Node::ExprStmt(ExprStmt { expr: collect }), Type::Void);
+        let collect = self.add(Node::Block(BlockExpr { stmts: vec![collect], value: None }), Type::Void);
+
+        let check = self.add(Node::If(IfExpr {
+            cond: fits,
+            then: nothing,
+            otherwise: Some(collect),
+        }), Type::Void);
+        let check = self.add(Node::ExprStmt(ExprStmt { expr: check }), Type::Void);
+        stmts.push(check);
+
+        let mut tag = tuple_tag(elems.len(), mask);
+        if let Type::Fn(params, _) = &tuple_ty {
+            assert!(params.len() < 1 << (64 - ARITY_SHIFT), "Too many parameters for a closure tag");
+            tag |= (params.len() as i64) << ARITY_SHIFT;
+        }
+
+        let alloc = Symbol::intern(&format!("alloc.{}", n));
+        let allocate = self.add(Node::Allocate(AllocateExpr {
+            len: elems.len(),
+            tag,
+        }), tuple_ty.clone());
+        let bind = self.add(Node::Let(LetStmt {
+            name: alloc,
+            name_span: self.span,
+            ty: None,
+            init: allocate,
+        }), Type::Void);
+        stmts.push(bind);
+
+        for (i, (temp, elem_ty)) in temps.iter().zip(elem_types).enumerate() {
+            let tuple = self.variable(alloc, tuple_ty.clone());
+            let index = self.integer(i as i64);
+            let target = self.add(Node::Index(IndexExpr { tuple, index }), elem_ty.clone());
+            let value = self.variable(*temp, elem_ty);
+            let assign = self.add(Node::Assign(AssignExpr { target, value }), Type::Void);
+            stmts.push(self.add(Node::ExprStmt(ExprStmt { expr: assign }), Type::Void));
+        }
+
+        let value = self.variable(alloc, tuple_ty);
+        Node::Block(BlockExpr { stmts, value: Some(value) })
+    }
+}
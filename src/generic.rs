@@ -0,0 +1,603 @@
+use crate::ast::{
+    Ast, BlockExpr, CallExpr, IndexExpr, LambdaExpr, LetStmt, Node, NodeId, Param, PrimaryExpr, TupleExpr,
+};
+use crate::diagnostic::Diagnostic;
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::source::Span;
+use crate::types::{annotation, substitute, Type, TypeTable};
+use crate::Error;
+use std::collections::{HashMap, HashSet};
+
+/// Generic functions get specialized more than this many times only through polymorphic
+/// recursion, which would never end
+const MAX_INSTANCES: usize = 64;
+
+/// How generic functions are turned into code
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Strategy {
+    /// One copy of the function per list of type arguments it is used with
+    Monomorphize,
+    /// A single copy where scalar values of a type parameter are boxed in a 1-tuple
+    Box,
+}
+
+/// Specializations of generic functions made by monomorphization. Their symbols are
+/// numbered like `id.0` so the assembler accepts them, the readable name is kept here
+pub struct Instances {
+    names: Vec<(Symbol, String)>,
+}
+
+impl Instances {
+    /// One comment line per specialization in the order they were made, like
+    /// `; id.0 is id<Int>`
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for (name, readable) in &self.names {
+            out.push_str(&format!("; {} is {}\n", name, readable));
+        }
+        out
+    }
+}
+
+/// Lower the generic functions of `program` with `strategy`, the program has no
+/// `All` types left afterwards. Generic `let` bindings of lambdas are specialized
+/// whatever the strategy
+pub fn lower_generics(ast: &mut Ast, program: NodeId, types: &mut TypeTable, strategy: Strategy) -> Result<Instances, Diagnostic> {
+    let (functions, main) = match &ast[program] {
+        Node::Program(p) => (p.functions.clone(), p.main),
+        _ => panic!("Lowering generics expects a program"),
+    };
+
+    specialize_lets(ast, program, types);
+
+    let mut generic = HashMap::new();
+    for id in &functions {
+        if let Node::FnDecl(decl) = &ast[*id] {
+            if !decl.generics.is_empty() {
+                generic.insert(decl.name, *id);
+            }
+        }
+    }
+
+    let mut instances = Instances { names: Vec::new() };
+    if generic.is_empty() {
+        return Ok(instances);
+    }
+
+    match strategy {
+        Strategy::Monomorphize => instances.names = monomorphize(ast, program, types, generic)?,
+        Strategy::Box => {
+            let mut boxer = Boxer { ast, types, generic, counter: 0 };
+            for id in functions.iter().chain([&main]) {
+                boxer.box_uses(*id);
+            }
+        }
+    }
+    Ok(instances)
+}
+
+/// Readable name of the copy of `name` specialized for `args`, like `pair<Int, Bool>`
+fn readable_instance(name: Symbol, args: &[Type]) -> String {
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    format!("{}<{}>", name, args.join(", "))
+}
+
+/// Replace every generic `let` binding of a lambda by one copy per list of types it is
+/// used with, like `let id.0 = fn(x: Int) ..; let id.1 = fn(x: Bool) ..;`, and point
+/// each use at its copy. Values of a type variable could be integers as well as
+/// pointers, this way no code is shared between the two. A binding nobody uses only
+/// held a lambda, it is dropped.
+///
+/// A copy can contain uses of bindings before it, so later bindings go first. Bindings
+/// inside the lambda go before it as well, their copies are then copied along with it.
+fn specialize_lets(ast: &mut Ast, program: NodeId, types: &mut TypeTable) {
+    let mut lets = Vec::new();
+    let mut parents = HashMap::new();
+    let mut stack = vec![program];
+    while let Some(id) = stack.pop() {
+        if types.scheme(id).is_some() {
+            lets.push(id);
+        }
+        if let Node::Block(b) = &ast[id] {
+            for stmt in &b.stmts {
+                parents.insert(*stmt, id);
+            }
+        }
+        stack.extend(ast[id].children().into_iter().rev());
+    }
+
+    for binding in lets.into_iter().rev() {
+        let vars = types.scheme(binding).cloned().expect("Binding is not generic");
+        types.remove_scheme(binding);
+
+        let (name, init) = match &ast[binding] {
+            Node::Let(l) => (l.name, l.init),
+            _ => unreachable!("Generalized binding is not a `let`"),
+        };
+
+        // Specializations by type arguments, in order of first use
+        let mut instances: Vec<(Vec<Type>, Symbol)> = Vec::new();
+        for id in ast.post_order(program) {
+            let args = match types.let_instance(id) {
+                Some((b, args)) if *b == binding => args.clone(),
+                _ => continue,
+            };
+            let specialized = match instances.iter().find(|(a, _)| *a == args) {
+                Some((_, specialized)) => *specialized,
+                None => {
+                    let specialized = Symbol::intern(&format!("{}.{}", name, instances.len()));
+                    instances.push((args, specialized));
+                    specialized
+                }
+            };
+            let span = ast.span(id);
+            let token = Token::new(TokenKind::Identifier(specialized), span);
+            ast.replace(id, Node::Primary(PrimaryExpr { value: token })).expect("Stale use");
+        }
+
+        let mut copies = Vec::new();
+        for (args, specialized) in &instances {
+            let subst: Vec<(u32, Type)> = vars.iter().copied().zip(args.iter().cloned()).collect();
+            let span = ast.span(binding);
+            let name_span = match &ast[binding] {
+                Node::Let(l) => l.name_span,
+                _ => unreachable!("Generalized binding is not a `let`"),
+            };
+            let init = copy_at(ast, types, init, &subst);
+            let copy = ast.add(Node::Let(LetStmt {
+                name: *specialized,
+                name_span,
+                ty: None,
+                init,
+            }), span);
+            types.insert(copy, Type::Void);
+            copies.push(copy);
+        }
+
+        let parent = parents[&binding];
+        if let Node::Block(b) = &mut ast[parent] {
+            let at = b.stmts.iter().position(|s| *s == binding).expect("Binding is not in its block");
+            b.stmts.splice(at..at + 1, copies);
+        }
+        ast.free(binding).expect("Stale binding");
+    }
+}
+
+/// Copy of the tree rooted at `id` with the type variables replaced by `subst`, in the
+/// recorded types and type arguments
+fn copy_at(ast: &mut Ast, types: &mut TypeTable, id: NodeId, subst: &[(u32, Type)]) -> NodeId {
+    let span = ast.span(id);
+    let mut node = ast[id].clone();
+    for child in node.children_mut() {
+        *child = copy_at(ast, types, *child, subst);
+    }
+
+    let copy = ast.add(node, span);
+    if let Some(ty) = types.try_get(id) {
+        let ty = substitute(ty, subst);
+        types.insert(copy, ty);
+    }
+    if let Some(args) = types.instance(id) {
+        let args = args.iter().map(|a| substitute(a, subst)).collect();
+        types.set_instance(copy, args);
+    }
+    if let Some((binding, args)) = types.let_instance(id) {
+        let (binding, args) = (*binding, args.iter().map(|a| substitute(a, subst)).collect());
+        types.set_let_instance(copy, binding, args);
+    }
+    copy
+}
+
+fn identifier(ast: &Ast, id: NodeId) -> Option<Symbol> {
+    match &ast[id] {
+        Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => Some(*name),
+        _ => None,
+    }
+}
+
+/// Specialize the generic functions for the types they are used with, returns the
+/// symbols of the copies with their readable names
+fn monomorphize(ast: &mut Ast, program: NodeId, types: &mut TypeTable, generic: HashMap<Symbol, NodeId>) -> Result<Vec<(Symbol, String)>, Diagnostic> {
+    let (functions, main) = match &ast[program] {
+        Node::Program(p) => (p.functions.clone(), p.main),
+        _ => unreachable!("Checked by the caller"),
+    };
+
+    let mut mono = Mono {
+        ast,
+        types,
+        generic,
+        instances: HashMap::new(),
+        names: Vec::new(),
+        queue: Vec::new(),
+    };
+
+    let roots: Vec<NodeId> = functions.iter()
+        .copied()
+        .filter(|id| !mono.generic.values().any(|g| g == id))
+        .chain([main])
+        .collect();
+    for root in roots {
+        for id in mono.ast.post_order(root) {
+            mono.rename_use(id)?;
+        }
+    }
+
+    let mut specialized = Vec::new();
+    while let Some((name, decl, subst)) = mono.queue.pop() {
+        let copy = mono.copy(decl, &subst)?;
+        if let Node::FnDecl(f) = &mut mono.ast[copy] {
+            f.name = name;
+            f.generics.clear();
+        }
+        specialized.push(copy);
+    }
+
+    let mut kept = Vec::new();
+    for id in functions {
+        if mono.generic.values().any(|g| *g == id) {
+            mono.ast.free(id).expect("Stale generic function");
+        } else {
+            kept.push(id);
+        }
+    }
+    kept.extend(specialized);
+
+    if let Node::Program(p) = &mut mono.ast[program] {
+        p.functions = kept;
+    }
+    Ok(mono.names)
+}
+
+/// A specialization to create: its name, the generic declaration and the type arguments
+type Request = (Symbol, NodeId, Vec<(Symbol, Type)>);
+
+struct Mono<'a> {
+    ast: &'a mut Ast,
+    types: &'a mut TypeTable,
+    generic: HashMap<Symbol, NodeId>,
+    /// Specializations already requested, by function and type arguments
+    instances: HashMap<(Symbol, Vec<Type>), Symbol>,
+    /// Symbols of the specializations with their readable names, in the order requested
+    names: Vec<(Symbol, String)>,
+    /// Specializations left to create, with the substitution for their type parameters
+    queue: Vec<Request>,
+}
+
+impl Mono<'_> {
+    /// Point a use of a generic function at its specialization, requesting it when needed
+    fn rename_use(&mut self, id: NodeId) -> Result<(), Diagnostic> {
+        let args = match self.types.instance(id) {
+            Some(args) => args.clone(),
+            None => return Ok(()),
+        };
+        let (name, decl) = match identifier(self.ast, id) {
+            Some(name) if self.generic.contains_key(&name) => (name, self.generic[&name]),
+            _ => return Ok(()),
+        };
+
+        let key = (name, args.clone());
+        let specialized = match self.instances.get(&key) {
+            Some(specialized) => *specialized,
+            None => {
+                let count = self.instances.keys().filter(|(n, _)| *n == name).count();
+                if count >= MAX_INSTANCES {
+                    let diag = Diagnostic::error(Error::InstantiationLimit, self.ast.span(id))
+                        .with_message(format!("`{}` is instantiated more than {} times", name, MAX_INSTANCES))
+                        .with_note("a generic function calling itself with bigger types cannot be monomorphized")
+                        .with_suggestion("compile with `--generics=box`");
+                    return Err(diag);
+                }
+
+                let generics = match &self.ast[decl] {
+                    Node::FnDecl(f) => f.generics.clone(),
+                    _ => unreachable!("Generic item is not a function"),
+                };
+                let specialized = Symbol::intern(&format!("{}.{}", name, count));
+                self.names.push((specialized, readable_instance(name, &args)));
+                self.instances.insert(key, specialized);
+                self.queue.push((specialized, decl, generics.into_iter().zip(args).collect()));
+                specialized
+            }
+        };
+
+        let span = self.ast.span(id);
+        let token = Token::new(TokenKind::Identifier(specialized), span);
+        self.ast.replace(id, Node::Primary(PrimaryExpr { value: token })).expect("Stale use");
+        Ok(())
+    }
+
+    /// Copy of the tree rooted at `id` with the type parameters replaced by `subst`, in
+    /// annotations as well as in the recorded types
+    fn copy(&mut self, id: NodeId, subst: &[(Symbol, Type)]) -> Result<NodeId, Diagnostic> {
+        let span = self.ast.span(id);
+        let mut node = self.ast[id].clone();
+
+        if let Node::TypeName(t) = &node {
+            if let Some((_, ty)) = subst.iter().find(|(p, _)| *p == t.name) {
+                return Ok(annotation(self.ast, ty, span));
+            }
+        }
+
+        for child in node.children_mut() {
+            *child = self.copy(*child, subst)?;
+        }
+
+        let copy = self.ast.add(node, span);
+        if let Some(ty) = self.types.try_get(id) {
+            let ty = ty.instantiate(subst);
+            self.types.insert(copy, ty);
+        }
+        if let Some(args) = self.types.instance(id) {
+            let args = args.iter().map(|a| a.instantiate(subst)).collect();
+            self.types.set_instance(copy, args);
+            self.rename_use(copy)?;
+        }
+
+        Ok(copy)
+    }
+}
+
+/// Whether a value of type `inst` has to be rebuilt to be seen at the type `generic`.
+/// Only scalars are boxed, tuples and functions already are pointers, as are the values
+/// of a type parameter of the caller, so a generic function mutates the tuples it is
+/// given rather than copies of them
+fn needs_box(generic: &Type, inst: &Type) -> bool {
+    match (generic, inst) {
+        (Type::Param(_), inst) => !inst.is_pointer(),
+        (Type::Tuple(gen_elems), Type::Tuple(inst_elems)) => gen_elems.iter().zip(inst_elems).any(|(g, t)| needs_box(g, t)),
+        (Type::Fn(gen_params, gen_ret), Type::Fn(inst_params, inst_ret)) => {
+            gen_params.iter().zip(inst_params).any(|(g, t)| needs_box(g, t)) || needs_box(gen_ret, inst_ret)
+        }
+        _ => false,
+    }
+}
+
+/// Converts between the representation of values at their concrete type and inside
+/// generic functions, where anything of a type parameter is a pointer
+struct Boxer<'a> {
+    ast: &'a mut Ast,
+    types: &'a mut TypeTable,
+    generic: HashMap<Symbol, NodeId>,
+    counter: usize,
+}
+
+impl Boxer<'_> {
+    fn fresh(&mut self) -> Symbol {
+        let sym = Symbol::intern(&format!("box.{}", self.counter));
+        self.counter += 1;
+        sym
+    }
+
+    fn add(&mut self, node: Node, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add(node, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn variable(&mut self, name: Symbol, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add_variable(name, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    /// Generic signature of `name` and its instance for the type arguments of the use `id`
+    fn signatures(&self, id: NodeId, name: Symbol) -> (Type, Type) {
+        let decl = self.generic[&name];
+        let generics = match &self.ast[decl] {
+            Node::FnDecl(f) => f.generics.clone(),
+            _ => unreachable!("Generic item is not a function"),
+        };
+        let args = self.types.instance(id).expect("Use of a generic function without type arguments");
+        let subst: Vec<(Symbol, Type)> = generics.into_iter().zip(args.iter().cloned()).collect();
+
+        let generic = self.types.get(decl).clone();
+        let instance = generic.instantiate(&subst);
+        (generic, instance)
+    }
+
+    /// Add the conversions around every use of a generic function below `root`
+    fn box_uses(&mut self, root: NodeId) {
+        let nodes = self.ast.post_order(root);
+        let mut callees = HashSet::new();
+
+        for id in &nodes {
+            let call = match &self.ast[*id] {
+                Node::Call(call) => call.clone(),
+                _ => continue,
+            };
+            let name = match identifier(self.ast, call.callee) {
+                Some(name) if self.types.instance(call.callee).is_some() => name,
+                _ => continue,
+            };
+            callees.insert(call.callee);
+
+            let (generic, instance) = self.signatures(call.callee, name);
+            let (gen_params, gen_ret, inst_ret) = match (&generic, &instance) {
+                (Type::Fn(gen_params, gen_ret), Type::Fn(_, inst_ret)) => (gen_params.clone(), gen_ret, inst_ret),
+                _ => unreachable!("Function is not typed as one"),
+            };
+
+            let mut args = Vec::new();
+            for (arg, gen_param) in call.args.iter().zip(&gen_params) {
+                let inst_param = self.types.get(*arg).clone();
+                args.push(self.generalize_value(*arg, gen_param, &inst_param));
+            }
+            self.types.insert(call.callee, generic.clone());
+
+            let span = self.ast.span(*id);
+            let boxed = self.add(Node::Call(CallExpr { callee: call.callee, args }), span, (**gen_ret).clone());
+            let value = self.specialize_value(boxed, gen_ret, inst_ret);
+            self.ast.replace(*id, Node::Block(BlockExpr { stmts: Vec::new(), value: Some(value) })).expect("Stale call");
+        }
+
+        for id in &nodes {
+            if callees.contains(id) {
+                continue;
+            }
+            let name = match identifier(self.ast, *id) {
+                Some(name) if self.types.instance(*id).is_some() => name,
+                _ => continue,
+            };
+
+            let (generic, instance) = self.signatures(*id, name);
+            let span = self.ast.span(*id);
+            let var = self.variable(name, span, generic.clone());
+            let value = self.specialize_value(var, &generic, &instance);
+            self.ast.replace(*id, Node::Block(BlockExpr { stmts: Vec::new(), value: Some(value) })).expect("Stale use");
+        }
+    }
+
+    /// `{ let tmp = e; body(tmp) }`, so `e` is evaluated once however often `body` needs it
+    fn bind(&mut self, e: NodeId, result: Type, body: impl FnOnce(&mut Self, Symbol) -> NodeId) -> NodeId {
+        let span = self.ast.span(e);
+        let temp = self.fresh();
+        let bind = self.add(Node::Let(LetStmt {
+            name: temp,
+            name_span: span,
+            ty: None,
+            init: e,
+        }), span, Type::Void);
+
+        let value = body(self, temp);
+        self.add(Node::Block(BlockExpr { stmts: vec![bind], value: Some(value) }), span, result)
+    }
+
+    fn index(&mut self, name: Symbol, tuple_ty: Type, i: usize, ty: Type, span: Span) -> NodeId {
+        let tuple = self.variable(name, span, tuple_ty);
+        let index = self.ast.add_integer(i as i64, span);
+        self.types.insert(index, Type::Int);
+        self.add(Node::Index(IndexExpr { tuple, index }), span, ty)
+    }
+
+    /// Convert `e`, a value of type `inst`, to the representation of `generic`
+    fn generalize_value(&mut self, e: NodeId, generic: &Type, inst: &Type) -> NodeId {
+        self.convert(e, generic, inst, true)
+    }
+
+    /// Convert `e`, a value in the representation of `generic`, to the type `inst`
+    fn specialize_value(&mut self, e: NodeId, generic: &Type, inst: &Type) -> NodeId {
+        self.convert(e, generic, inst, false)
+    }
+
+    fn convert(&mut self, e: NodeId, generic: &Type, inst: &Type, boxing: bool) -> NodeId {
+        let span = self.ast.span(e);
+        let (from, to) = if boxing { (inst, generic) } else { (generic, inst) };
+
+        if !needs_box(generic, inst) {
+            // The value is passed by reference as it is, only its static type changes
+            return match generic == inst {
+                true => e,
+                false => self.add(Node::Block(BlockExpr { stmts: Vec::new(), value: Some(e) }), span, to.clone()),
+            };
+        }
+
+        match (generic, inst) {
+            (Type::Param(_), _) if boxing => self.add(Node::Tuple(TupleExpr { elems: vec![e] }), span, Type::Tuple(vec![inst.clone()])),
+            (Type::Param(_), _) => {
+                let index = self.ast.add_integer(0, span);
+                self.types.insert(index, Type::Int);
+                self.add(Node::Index(IndexExpr { tuple: e, index }), span, inst.clone())
+            }
+
+            (Type::Tuple(gen_elems), Type::Tuple(inst_elems)) => {
+                let (from_elems, to_ty) = match boxing {
+                    true => (inst_elems.clone(), generic.clone()),
+                    false => (gen_elems.clone(), inst.clone()),
+                };
+                self.bind(e, to_ty.clone(), |this, temp| {
+                    let mut elems = Vec::new();
+                    for (i, (g, t)) in gen_elems.iter().zip(inst_elems).enumerate() {
+                        let elem = this.index(temp, from.clone(), i, from_elems[i].clone(), span);
+                        elems.push(this.convert(elem, g, t, boxing));
+                    }
+                    this.add(Node::Tuple(TupleExpr { elems }), span, to_ty)
+                })
+            }
+
+            // A function is wrapped into one converting its arguments the other way around
+            (Type::Fn(gen_params, gen_ret), Type::Fn(inst_params, inst_ret)) => {
+                let (from_ret, to_params, to_ret) = match boxing {
+                    true => (inst_ret, gen_params, gen_ret),
+                    false => (gen_ret, inst_params, inst_ret),
+                };
+                self.bind(e, to.clone(), |this, temp| {
+                    let mut params = Vec::new();
+                    let mut args = Vec::new();
+                    for (i, to_param) in to_params.iter().enumerate() {
+                        let name = this.fresh();
+                        params.push(Param { name, name_span: span, ty: None });
+                        let param = this.variable(name, span, to_param.clone());
+                        args.push(this.convert(param, &gen_params[i], &inst_params[i], !boxing));
+                    }
+
+                    let callee = this.variable(temp, span, from.clone());
+                    let call = this.add(Node::Call(CallExpr { callee, args }), span, (**from_ret).clone());
+                    let value = this.convert(call, gen_ret, inst_ret, boxing);
+                    let body = this.add(Node::Block(BlockExpr { stmts: Vec::new(), value: Some(value) }), span, (**to_ret).clone());
+
+                    this.add(Node::Lambda(LambdaExpr {
+                        params,
+                        ret: None,
+                        body,
+                    }), span, to.clone())
+                })
+            }
+
+            _ => unreachable!("`{}` is not an instance of `{}`", inst, generic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::types::type_check;
+
+    /// Arguments of the calls in the program after lowering its generics with `strategy`,
+    /// as the expressions they end up being once the empty blocks around them are skipped
+    fn call_args(source: &str, strategy: Strategy) -> Vec<String> {
+        let mut parser = Parser::from_source(source);
+        let root = parser.parse().expect("Parse error");
+        let mut ast = parser.ast;
+        let mut types = type_check(&ast, root).expect("Type error");
+        lower_generics(&mut ast, root, &mut types, strategy).expect("Generic error");
+
+        let mut args = Vec::new();
+        for id in ast.post_order(root) {
+            let call = match &ast[id] {
+                Node::Call(call) => call.clone(),
+                _ => continue,
+            };
+            for mut arg in call.args {
+                while let Node::Block(BlockExpr { stmts, value: Some(value) }) = &ast[arg] {
+                    if !stmts.is_empty() {
+                        break;
+                    }
+                    arg = *value;
+                }
+                args.push(ast.dump(arg));
+            }
+        }
+        args
+    }
+
+    #[test]
+    fn tuple_is_mutated_in_place_whatever_the_strategy() {
+        let source = "fn swap<T>(t: (T, T)) -> Void { let x = t[0]; t[0] = t[1]; t[1] = x; }
+                      let p = ((1,), (2,));
+                      swap(p);
+                      p[0][0]";
+        let mono = call_args(source, Strategy::Monomorphize);
+        assert_eq!(mono, ["p"]);
+        assert_eq!(call_args(source, Strategy::Box), mono);
+    }
+
+    #[test]
+    fn scalar_is_boxed() {
+        let args = call_args("fn id<T>(x: T) -> T { x } id(1)", Strategy::Box);
+        assert_eq!(args, ["(tuple 1)"]);
+    }
+}
@@ -0,0 +1,761 @@
+use crate::ast::{
+    AssignExpr, Ast, BinaryExpr, BlockExpr, CallExpr, CastExpr, ExprStmt, IfExpr, IndexExpr, LambdaExpr,
+    LetStmt, Node, NodeId, Param, PrimaryExpr, ProxyExpr, TupleExpr, UnaryExpr, WhileExpr,
+};
+use crate::diagnostic::Diagnostic;
+use crate::dynamic::{dispatch, inject, project};
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::source::Span;
+use crate::types::{annotation, check_tuple_len, Type, TypeTable};
+use crate::Error;
+use std::collections::HashMap;
+
+/// Checks a program mixing typed and untyped code. Parameters and return types without an
+/// annotation have the dynamic type `?`, which is consistent with every type: a value of
+/// type `?` can be used where an `Int` is expected and the other way around. Where the two
+/// meet a cast is inserted, checked at runtime:
+///
+/// ```text
+/// fn inc(x: Int) -> Int { x + 1 }
+/// fn twice(y) { inc(inc(y)) }     =>  (call inc (call inc (cast y Any Int)))
+/// ```
+///
+/// The result of `twice` is cast back from `Int` to `?`. A `let` without an annotation
+/// takes the type of its initializer.
+///
+/// Types that are not consistent, like `Int` and `Bool`, are still compile errors. A
+/// program without annotations behaves like one compiled with `--dynamic`, and type
+/// parameters of generic functions are checked as `?`. The casts are removed by
+/// `lower_casts` afterwards.
+pub fn check_gradual(ast: &mut Ast, program: NodeId) -> Result<TypeTable, Diagnostic> {
+    let (functions, main) = match &ast[program] {
+        Node::Program(p) => (p.functions.clone(), p.main),
+        _ => panic!("Gradual checking expects a program"),
+    };
+
+    let mut checker = Checker {
+        ast,
+        types: TypeTable::new(),
+        functions: HashMap::new(),
+        locals: Vec::new(),
+        generics: Vec::new(),
+        counter: 0,
+    };
+
+    // Signatures first, functions can be called before they are declared. Type parameters
+    // are checked as `?`, so the functions stop being generic
+    let mut generics = Vec::new();
+    for id in &functions {
+        let mut decl = match &checker.ast[*id] {
+            Node::FnDecl(decl) => decl.clone(),
+            _ => unreachable!("Top-level item is not a function"),
+        };
+        checker.generics = std::mem::take(&mut decl.generics);
+        let ty = checker.signature(&mut decl.params, &mut decl.ret, decl.name_span)?;
+        generics.push(std::mem::take(&mut checker.generics));
+
+        checker.functions.insert(decl.name, ty.clone());
+        checker.types.insert(*id, ty);
+        checker.ast.replace(*id, Node::FnDecl(decl)).expect("Stale function");
+    }
+
+    for (id, generics) in functions.iter().zip(generics) {
+        let decl = match &checker.ast[*id] {
+            Node::FnDecl(decl) => decl.clone(),
+            _ => unreachable!("Top-level item is not a function"),
+        };
+        let (params, ret) = match checker.types.get(*id).clone() {
+            Type::Fn(params, ret) => (params, *ret),
+            _ => unreachable!("Function is not typed as a function"),
+        };
+
+        checker.locals = decl.params.iter().map(|p| p.name).zip(params).collect();
+        checker.generics = generics;
+        let body = checker.expect(decl.body, &ret)?;
+        checker.generics.clear();
+        checker.locals.clear();
+
+        if let Node::FnDecl(decl) = &mut checker.ast[*id] {
+            decl.body = body;
+        }
+    }
+
+    checker.check(main)?;
+    checker.types.insert(program, Type::Void);
+    Ok(checker.types)
+}
+
+/// Whether a value of type `a` can be used where a `b` is expected, `?` standing for
+/// any type
+pub fn consistent(a: &Type, b: &Type) -> bool {
+    match (a, b) {
+        (Type::Any, _) | (_, Type::Any) => true,
+        (Type::Tuple(xs), Type::Tuple(ys)) => xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| consistent(x, y)),
+        (Type::Fn(xs, x), Type::Fn(ys, y)) => {
+            xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| consistent(x, y)) && consistent(x, y)
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Most precise type consistent with both `a` and `b`, which have to be consistent
+fn meet(a: &Type, b: &Type) -> Type {
+    match (a, b) {
+        (Type::Any, ty) | (ty, Type::Any) => ty.clone(),
+        (Type::Tuple(xs), Type::Tuple(ys)) => Type::Tuple(xs.iter().zip(ys).map(|(x, y)| meet(x, y)).collect()),
+        (Type::Fn(xs, x), Type::Fn(ys, y)) => {
+            Type::Fn(xs.iter().zip(ys).map(|(x, y)| meet(x, y)).collect(), Box::new(meet(x, y)))
+        }
+        (ty, _) => ty.clone(),
+    }
+}
+
+/// Function type with only `?` in it, the one untyped functions of `arity` have
+fn untyped_fn(arity: usize) -> Type {
+    Type::Fn(vec![Type::Any; arity], Box::new(Type::Any))
+}
+
+fn mismatch(span: Span, expected: &Type, found: &Type) -> Diagnostic {
+    Diagnostic::error(Error::TypeMismatch, span)
+        .with_message(format!("expected `{}`, found `{}`", expected, found))
+}
+
+struct Checker<'a> {
+    ast: &'a mut Ast,
+    types: TypeTable,
+    functions: HashMap<Symbol, Type>,
+    locals: Vec<(Symbol, Type)>,
+    /// Type parameters of the function being checked
+    generics: Vec<Symbol>,
+    counter: usize,
+}
+
+impl Checker<'_> {
+    fn add(&mut self, node: Node, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add(node, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn lookup(&self, name: Symbol) -> Option<Type> {
+        match self.locals.iter().rev().find(|(n, _)| *n == name) {
+            Some((_, ty)) => Some(ty.clone()),
+            None => self.functions.get(&name).cloned(),
+        }
+    }
+
+    /// Type written in an annotation, `?` is `Any`
+    fn resolve(&self, id: NodeId) -> Result<Type, Diagnostic> {
+        match &self.ast[id] {
+            Node::TypeName(t) if self.generics.contains(&t.name) => Ok(Type::Any),
+            Node::TypeName(t) => match t.name.as_str() {
+                "Int" => Ok(Type::Int),
+                "Bool" => Ok(Type::Bool),
+                "Void" => Ok(Type::Void),
+                "Str" => Ok(Type::Str),
+                "?" => Ok(Type::Any),
+                _ => {
+                    let diag = Diagnostic::error(Error::UnknownType, self.ast.span(id))
+                        .with_message(format!("cannot find type `{}`", t.name))
+                        .with_note("the types are `Int`, `Bool`, `Void`, `Str`, `?`, tuples and functions");
+                    Err(diag)
+                }
+            },
+
+            Node::TupleType(t) => {
+                check_tuple_len(t.elems.len(), self.ast.span(id))?;
+                let elems = t.elems.iter().map(|e| self.resolve(*e)).collect::<Result<_, _>>()?;
+                Ok(Type::Tuple(elems))
+            }
+
+            Node::FnType(f) => {
+                let params = f.params.iter().map(|p| self.resolve(*p)).collect::<Result<_, _>>()?;
+                let ret = match f.ret {
+                    Some(ret) => self.resolve(ret)?,
+                    None => Type::Void,
+                };
+                Ok(Type::Fn(params, Box::new(ret)))
+            }
+
+            _ => unreachable!("Node is not a type annotation"),
+        }
+    }
+
+    /// Annotation for `ty` in place of `annotation`, which is missing or names type parameters
+    fn written(&mut self, annotation_id: Option<NodeId>, ty: &Type, span: Span) -> NodeId {
+        match annotation_id {
+            Some(id) if self.generics.is_empty() => id,
+            Some(id) => {
+                let span = self.ast.span(id);
+                self.ast.free(id).expect("Stale annotation");
+                annotation(self.ast, ty, span)
+            }
+            None => annotation(self.ast, ty, span),
+        }
+    }
+
+    /// Function type of a signature, the annotations left out are written down as `?`
+    fn signature(&mut self, params: &mut [Param], ret: &mut Option<NodeId>, span: Span) -> Result<Type, Diagnostic> {
+        let mut param_types = Vec::new();
+        for param in params.iter_mut() {
+            let ty = match param.ty {
+                Some(ty) => self.resolve(ty)?,
+                None => Type::Any,
+            };
+            param.ty = Some(self.written(param.ty, &ty, param.name_span));
+            param_types.push(ty);
+        }
+
+        let ret_type = match *ret {
+            Some(ret) => self.resolve(ret)?,
+            None => Type::Any,
+        };
+        *ret = Some(self.written(*ret, &ret_type, span));
+        Ok(Type::Fn(param_types, Box::new(ret_type)))
+    }
+
+    /// `id`, a value of type `from`, converted to the consistent type `to`
+    fn cast(&mut self, id: NodeId, from: &Type, to: &Type) -> NodeId {
+        if from == to {
+            return id;
+        }
+        let span = self.ast.span(id);
+        let from = annotation(self.ast, from, span);
+        let to_annotation = annotation(self.ast, to, span);
+        self.add(Node::Cast(CastExpr { expr: id, from, to: to_annotation }), span, to.clone())
+    }
+
+    /// Check `id` where a value of type `expected` is needed, casting it when its type is
+    /// only consistent with `expected`. Returns the node standing for `id` from now on
+    fn expect(&mut self, id: NodeId, expected: &Type) -> Result<NodeId, Diagnostic> {
+        // A tuple literal is built with the expected element types right away instead of
+        // being proxied
+        if let Node::Tuple(t) = &self.ast[id] {
+            let elems = t.elems.clone();
+            let built = match expected {
+                Type::Tuple(elem_types) if elem_types.len() == elems.len() => Some(expected.clone()),
+                Type::Any => Some(Type::Tuple(vec![Type::Any; elems.len()])),
+                _ => None,
+            };
+            if let Some(Type::Tuple(elem_types)) = &built {
+                check_tuple_len(elems.len(), self.ast.span(id))?;
+                let mut checked = Vec::new();
+                for (elem, ty) in elems.iter().zip(elem_types) {
+                    checked.push(self.expect(*elem, ty)?);
+                }
+                self.ast.replace(id, Node::Tuple(TupleExpr { elems: checked })).expect("Stale tuple");
+                self.types.insert(id, Type::Tuple(elem_types.clone()));
+                return Ok(self.cast(id, &Type::Tuple(elem_types.clone()), expected));
+            }
+        }
+
+        let found = self.check(id)?;
+        if !consistent(&found, expected) {
+            return Err(mismatch(self.ast.span(id), expected, &found));
+        }
+        Ok(self.cast(id, &found, expected))
+    }
+
+    /// Check `id` where its value is discarded, an untyped value is dropped as it is
+    fn effect(&mut self, id: NodeId) -> Result<NodeId, Diagnostic> {
+        let ty = self.check(id)?;
+        match ty {
+            Type::Void => return Ok(id),
+            Type::Any => {}
+            ty => return Err(mismatch(self.ast.span(id), &Type::Void, &ty)),
+        }
+
+        let span = self.ast.span(id);
+        let value = match &mut self.ast[id] {
+            // The value of the block becomes its last statement
+            Node::Block(block) => block.value.take(),
+            _ => None,
+        };
+        if let Some(value) = value {
+            let stmt = self.add(Node::ExprStmt(ExprStmt { expr: value }), span, Type::Void);
+            if let Node::Block(block) = &mut self.ast[id] {
+                block.stmts.push(stmt);
+            }
+            self.types.insert(id, Type::Void);
+            return Ok(id);
+        }
+
+        let stmt = self.add(Node::ExprStmt(ExprStmt { expr: id }), span, Type::Void);
+        Ok(self.add(Node::Block(BlockExpr { stmts: vec![stmt], value: None }), span, Type::Void))
+    }
+
+    /// `&`, `|` or `~` on operands of types `found`, decided at runtime when none of them
+    /// says whether they are integers or booleans
+    fn logical(&mut self, id: NodeId, operator: TokenKind, operands: &[NodeId], found: &[Type]) -> Result<(Node, Type), Diagnostic> {
+        let span = self.ast.span(id);
+        let known = found.iter().find(|ty| **ty != Type::Any).cloned();
+
+        match known {
+            Some(ty @ (Type::Int | Type::Bool)) => {
+                let mut args = Vec::new();
+                for (operand, found) in operands.iter().zip(found) {
+                    if !consistent(found, &ty) {
+                        return Err(mismatch(self.ast.span(*operand), &ty, found));
+                    }
+                    args.push(self.cast(*operand, found, &ty));
+                }
+                let node = match args[..] {
+                    [operand] => Node::Unary(UnaryExpr { operator, operand }),
+                    [left, right] => Node::Binary(BinaryExpr { operator, left, right }),
+                    _ => unreachable!("Operators take one or two operands"),
+                };
+                Ok((node, ty))
+            }
+
+            Some(ty) => {
+                let diag = Diagnostic::error(Error::TypeMismatch, span)
+                    .with_message(format!("cannot apply `{}` to `{}`", operator, ty));
+                Err(diag)
+            }
+
+            None => {
+                let mut stmts = Vec::new();
+                let mut temps = Vec::new();
+                for operand in operands {
+                    let temp = Symbol::intern(&format!("dyn.{}", self.counter));
+                    self.counter += 1;
+                    let operand_span = self.ast.span(*operand);
+                    stmts.push(self.add(Node::Let(LetStmt {
+                        name: temp,
+                        name_span: operand_span,
+                        ty: None,
+                        init: *operand,
+                    }), operand_span, Type::Void));
+                    temps.push((temp, operand_span));
+                }
+                let choice = dispatch(self.ast, &mut self.types, operator, &temps, span);
+                Ok((Node::Block(BlockExpr { stmts, value: Some(choice) }), Type::Any))
+            }
+        }
+    }
+
+    /// Rewrite the node behind `id` in place with casts where they are needed, and record
+    /// its type
+    fn check(&mut self, id: NodeId) -> Result<Type, Diagnostic> {
+        use TokenKind as T;
+
+        let span = self.ast.span(id);
+        let scope = self.locals.len();
+
+        let (node, ty) = match self.ast[id].clone() {
+            Node::Primary(PrimaryExpr { value: Token { kind, .. } }) => {
+                let ty = match kind {
+                    T::Integer(_) => Type::Int,
+                    T::True | T::False => Type::Bool,
+                    T::String(_) => Type::Str,
+                    T::Identifier(name) => match self.lookup(name) {
+                        Some(ty) => ty,
+                        None => {
+                            let diag = Diagnostic::error(Error::UnknownVariable, span)
+                                .with_message(format!("cannot find `{}` in this scope", name));
+                            return Err(diag);
+                        }
+                    },
+                    _ => unreachable!("Parser only produces literals and identifiers as primaries"),
+                };
+                self.types.insert(id, ty.clone());
+                return Ok(ty);
+            }
+
+            Node::Unary(u) => match u.operator {
+                T::Minus => {
+                    let operand = self.expect(u.operand, &Type::Int)?;
+                    (Node::Unary(UnaryExpr { operator: u.operator, operand }), Type::Int)
+                }
+                _ => {
+                    let found = self.check(u.operand)?;
+                    self.logical(id, u.operator, &[u.operand], &[found])?
+                }
+            },
+
+            Node::Binary(b) => match b.operator {
+                T::And | T::Or => {
+                    let left = self.check(b.left)?;
+                    let right = self.check(b.right)?;
+                    self.logical(id, b.operator, &[b.left, b.right], &[left, right])?
+                }
+                T::Equal | T::NotEqual => {
+                    let left = self.check(b.left)?;
+                    let right = self.check(b.right)?;
+                    if !consistent(&left, &right) {
+                        let diag = mismatch(self.ast.span(b.right), &left, &right)
+                            .with_label(self.ast.span(b.left), "expected because of this operand");
+                        return Err(diag);
+                    }
+                    // Values of different types are compared tagged
+                    let operand = match left == right {
+                        true => left.clone(),
+                        false => Type::Any,
+                    };
+                    if !matches!(operand, Type::Int | Type::Bool | Type::Any) {
+                        let diag = Diagnostic::error(Error::TypeMismatch, span)
+                            .with_message(format!("cannot apply `{}` to `{}`", b.operator, operand));
+                        return Err(diag);
+                    }
+                    let left_id = self.cast(b.left, &left, &operand);
+                    let right_id = self.cast(b.right, &right, &operand);
+                    (Node::Binary(BinaryExpr { operator: b.operator, left: left_id, right: right_id }), Type::Bool)
+                }
+                operator => {
+                    let left = self.expect(b.left, &Type::Int)?;
+                    let right = self.expect(b.right, &Type::Int)?;
+                    let ty = match operator {
+                        T::Lt | T::LtEq | T::Gt | T::GtEq => Type::Bool,
+                        _ => Type::Int,
+                    };
+                    (Node::Binary(BinaryExpr { operator, left, right }), ty)
+                }
+            },
+
+            Node::Block(b) => {
+                for stmt in &b.stmts {
+                    self.check(*stmt)?;
+                }
+                let ty = match b.value {
+                    Some(value) => self.check(value)?,
+                    None => Type::Void,
+                };
+                (Node::Block(b), ty)
+            }
+
+            Node::If(i) => {
+                let cond = self.expect(i.cond, &Type::Bool)?;
+                match i.otherwise {
+                    Some(otherwise) => {
+                        let then_ty = self.check(i.then)?;
+                        let otherwise_ty = self.check(otherwise)?;
+                        if !consistent(&then_ty, &otherwise_ty) {
+                            let diag = mismatch(self.ast.span(otherwise), &then_ty, &otherwise_ty)
+                                .with_label(self.ast.span(i.then), "expected because of this branch");
+                            return Err(diag);
+                        }
+                        let ty = meet(&then_ty, &otherwise_ty);
+                        let then = self.cast(i.then, &then_ty, &ty);
+                        let otherwise = self.cast(otherwise, &otherwise_ty, &ty);
+                        (Node::If(IfExpr { cond, then, otherwise: Some(otherwise) }), ty)
+                    }
+                    None => {
+                        let then = self.effect(i.then)
+                            .map_err(|diag| diag.with_note("an `if` without `else` evaluates to `Void`"))?;
+                        (Node::If(IfExpr { cond, then, otherwise: None }), Type::Void)
+                    }
+                }
+            }
+
+            Node::While(w) => {
+                let cond = self.expect(w.cond, &Type::Bool)?;
+                let body = self.effect(w.body)?;
+                (Node::While(WhileExpr { cond, body }), Type::Void)
+            }
+
+            Node::Assign(a) => {
+                let target = self.check(a.target)?;
+                let value = self.expect(a.value, &target)
+                    .map_err(|diag| diag.with_label(span, "expected because of this place"))?;
+                (Node::Assign(AssignExpr { target: a.target, value }), Type::Void)
+            }
+
+            Node::Break => (Node::Break, Type::Void),
+            Node::Continue => (Node::Continue, Type::Void),
+
+            Node::Call(c) => {
+                let found = self.check(c.callee)?;
+                let (callee, params, ret) = match found {
+                    Type::Fn(params, ret) => (c.callee, params, *ret),
+                    Type::Any => {
+                        let ty = untyped_fn(c.args.len());
+                        let callee = self.cast(c.callee, &Type::Any, &ty);
+                        (callee, vec![Type::Any; c.args.len()], Type::Any)
+                    }
+                    other => {
+                        let diag = Diagnostic::error(Error::NotCallable, self.ast.span(c.callee))
+                            .with_message(format!("expected function, found `{}`", other));
+                        return Err(diag);
+                    }
+                };
+
+                if params.len() != c.args.len() {
+                    let plural = if params.len() == 1 { "" } else { "s" };
+                    let diag = Diagnostic::error(Error::ArityMismatch, span)
+                        .with_message(format!(
+                            "function takes {} argument{} but {} {} supplied",
+                            params.len(), plural, c.args.len(),
+                            if c.args.len() == 1 { "was" } else { "were" },
+                        ));
+                    return Err(diag);
+                }
+
+                let mut args = Vec::new();
+                for (arg, param) in c.args.iter().zip(&params) {
+                    args.push(self.expect(*arg, param)?);
+                }
+                (Node::Call(CallExpr { callee, args }), ret)
+            }
+
+            Node::Tuple(t) => {
+                check_tuple_len(t.elems.len(), span)?;
+                let mut elems = Vec::new();
+                for elem in &t.elems {
+                    elems.push(self.check(*elem)?);
+                }
+                (Node::Tuple(t), Type::Tuple(elems))
+            }
+
+            Node::Index(i) => match self.check(i.tuple)? {
+                Type::Tuple(elems) => {
+                    let index = match &self.ast[i.index] {
+                        Node::Primary(PrimaryExpr { value: Token { kind: T::Integer(n), .. } }) => *n,
+                        _ => {
+                            let diag = Diagnostic::error(Error::InvalidTupleIndex, self.ast.span(i.index))
+                                .with_message("tuple index must be an integer literal")
+                                .with_note("elements can have different types, so the index has to be known");
+                            return Err(diag);
+                        }
+                    };
+                    self.types.insert(i.index, Type::Int);
+
+                    match usize::try_from(index).ok().and_then(|n| elems.get(n)) {
+                        Some(elem) => (Node::Index(i), elem.clone()),
+                        None => {
+                            let diag = Diagnostic::error(Error::InvalidTupleIndex, self.ast.span(i.index))
+                                .with_message(format!("index {} is out of bounds for `{}`", index, Type::Tuple(elems.clone())))
+                                .with_label(self.ast.span(i.tuple), format!("has {} elements", elems.len()));
+                            return Err(diag);
+                        }
+                    }
+                }
+                // Checked at runtime, like in dynamically typed programs
+                Type::Any => {
+                    let index = self.expect(i.index, &Type::Int)?;
+                    (Node::Index(IndexExpr { tuple: i.tuple, index }), Type::Any)
+                }
+                other => {
+                    let diag = Diagnostic::error(Error::TypeMismatch, self.ast.span(i.tuple))
+                        .with_message(format!("expected tuple, found `{}`", other));
+                    return Err(diag);
+                }
+            },
+
+            Node::Len(l) => match self.check(l.tuple)? {
+                Type::Tuple(_) | Type::Any => (Node::Len(l), Type::Int),
+                other => {
+                    let diag = Diagnostic::error(Error::TypeMismatch, self.ast.span(l.tuple))
+                        .with_message(format!("expected tuple, found `{}`", other));
+                    return Err(diag);
+                }
+            },
+
+            Node::Lambda(mut lambda) => {
+                let ty = self.signature(&mut lambda.params, &mut lambda.ret, span)?;
+                let (params, ret) = match &ty {
+                    Type::Fn(params, ret) => (params.clone(), (**ret).clone()),
+                    _ => unreachable!("Signature is not a function type"),
+                };
+                self.locals.extend(lambda.params.iter().map(|p| p.name).zip(params));
+                let body = self.expect(lambda.body, &ret)?;
+                (Node::Lambda(LambdaExpr { body, ..lambda }), ty)
+            }
+
+            Node::Let(l) => {
+                // Without an annotation the binding gets the type of its initializer
+                let (ty, init, annotation) = match l.ty {
+                    Some(annotation) => {
+                        let ty = self.resolve(annotation)?;
+                        let init = self.expect(l.init, &ty).map_err(|diag| {
+                            diag.with_label(self.ast.span(annotation), "expected because of this annotation")
+                        })?;
+                        (ty.clone(), init, Some(self.written(Some(annotation), &ty, l.name_span)))
+                    }
+                    None => (self.check(l.init)?, l.init, None),
+                };
+                self.ast.replace(id, Node::Let(LetStmt { ty: annotation, init, ..l })).expect("Stale let");
+                self.types.insert(id, Type::Void);
+
+                // Stays in scope until the enclosing block ends
+                self.locals.push((l.name, ty));
+                return Ok(Type::Void);
+            }
+
+            Node::ExprStmt(e) => {
+                self.check(e.expr)?;
+                (Node::ExprStmt(e), Type::Void)
+            }
+
+            node => unreachable!("Unexpected node before lowering: {:?}", node),
+        };
+
+        self.locals.truncate(scope);
+        self.ast.replace(id, node).expect("Stale node");
+        self.types.insert(id, ty.clone());
+        Ok(ty)
+    }
+}
+
+/// Replaces the casts inserted by `check_gradual` with what they do at runtime:
+///
+/// - to `?`, the value gets injected, from `?` it gets projected and traps when it
+///   has another type
+/// - between function types, the function is wrapped in a lambda casting the arguments
+///   one way and the result the other way
+/// - between tuple types, the tuple is wrapped in a proxy whose functions cast each
+///   element when it is read or written
+///
+/// Casts between a function or tuple type and `?` go through the untyped function or
+/// tuple of the same size. Every node created keeps the span of its cast, so a failing
+/// projection blames the code the cast was inserted for.
+pub fn lower_casts(ast: &mut Ast, program: NodeId, types: &mut TypeTable) {
+    let mut lowering = CastLowering { ast, types, counter: 0 };
+
+    for id in lowering.ast.post_order(program) {
+        let cast = match &lowering.ast[id] {
+            Node::Cast(cast) => cast.clone(),
+            _ => continue,
+        };
+
+        lowering.ast.free(cast.from).expect("Stale annotation");
+        lowering.ast.free(cast.to).expect("Stale annotation");
+        let from = lowering.types.get(cast.expr).clone();
+        let to = lowering.types.get(id).clone();
+        let span = lowering.ast.span(id);
+
+        // The conversion takes the place of the cast, so the nodes using it see the new value
+        let value = lowering.convert(cast.expr, &from, &to, span);
+        let node = lowering.ast.replace(value, Node::Break).expect("Stale conversion");
+        lowering.ast.free(value).expect("Stale conversion");
+        lowering.ast.replace(id, node).expect("Stale cast");
+    }
+}
+
+/// Tuple of `?` with as many elements as `ty`, or function of `?` with as many parameters,
+/// that is the type `ty` goes through to and from `?`
+fn ground(ty: &Type) -> Type {
+    match ty {
+        Type::Tuple(elems) => Type::Tuple(vec![Type::Any; elems.len()]),
+        Type::Fn(params, _) => untyped_fn(params.len()),
+        ty => ty.clone(),
+    }
+}
+
+struct CastLowering<'a> {
+    ast: &'a mut Ast,
+    types: &'a mut TypeTable,
+    counter: usize,
+}
+
+impl CastLowering<'_> {
+    fn add(&mut self, node: Node, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add(node, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn variable(&mut self, name: Symbol, span: Span, ty: Type) -> NodeId {
+        let id = self.ast.add_variable(name, span);
+        self.types.insert(id, ty);
+        id
+    }
+
+    fn fresh(&mut self) -> Symbol {
+        let name = Symbol::intern(&format!("cast.{}", self.counter));
+        self.counter += 1;
+        name
+    }
+
+    /// `expr` converted from `from` to `to`, two consistent types
+    fn convert(&mut self, expr: NodeId, from: &Type, to: &Type, span: Span) -> NodeId {
+        if from == to {
+            return expr;
+        }
+
+        match (from, to) {
+            (from, Type::Any) => {
+                let ground = ground(from);
+                let value = self.convert(expr, from, &ground, span);
+                inject(self.ast, self.types, value, &ground)
+            }
+
+            (Type::Any, to) => {
+                let ground = ground(to);
+                let value = project(self.ast, self.types, expr, &ground);
+                self.ast.set_span(value, span);
+                self.convert(value, &ground, to, span)
+            }
+
+            (Type::Fn(from_params, from_ret), Type::Fn(to_params, to_ret)) => {
+                let function = self.fresh();
+                let bind = self.add(Node::Let(LetStmt {
+                    name: function,
+                    name_span: span,
+                    ty: None,
+                    init: expr,
+                }), span, Type::Void);
+
+                let mut params = Vec::new();
+                let mut args = Vec::new();
+                for (i, (from_param, to_param)) in from_params.iter().zip(to_params).enumerate() {
+                    let name = Symbol::intern(&format!("{}.{}", function, i));
+                    params.push(Param {
+                        name,
+                        name_span: span,
+                        ty: Some(annotation(self.ast, to_param, span)),
+                    });
+                    let arg = self.variable(name, span, to_param.clone());
+                    args.push(self.convert(arg, to_param, from_param, span));
+                }
+
+                let callee = self.variable(function, span, from.clone());
+                let call = self.add(Node::Call(CallExpr { callee, args }), span, (**from_ret).clone());
+                let result = self.convert(call, from_ret, to_ret, span);
+                let body = self.add(Node::Block(BlockExpr { stmts: Vec::new(), value: Some(result) }), span, (**to_ret).clone());
+
+                let ret = annotation(self.ast, to_ret, span);
+                let lambda = self.add(Node::Lambda(LambdaExpr {
+                    params,
+                    ret: Some(ret),
+                    body,
+                }), span, to.clone());
+                self.add(Node::Block(BlockExpr { stmts: vec![bind], value: Some(lambda) }), span, to.clone())
+            }
+
+            (Type::Tuple(from_elems), Type::Tuple(to_elems)) => {
+                let mut reads = Vec::new();
+                let mut writes = Vec::new();
+                for (from_elem, to_elem) in from_elems.iter().zip(to_elems) {
+                    reads.push(self.element_cast(from_elem, to_elem, span));
+                    writes.push(self.element_cast(to_elem, from_elem, span));
+                }
+
+                let types = reads.iter().map(|r| self.types.get(*r).clone()).collect();
+                let reads = self.add(Node::Tuple(TupleExpr { elems: reads }), span, Type::Tuple(types));
+                let types = writes.iter().map(|w| self.types.get(*w).clone()).collect();
+                let writes = self.add(Node::Tuple(TupleExpr { elems: writes }), span, Type::Tuple(types));
+                self.add(Node::Proxy(ProxyExpr { tuple: expr, reads, writes }), span, to.clone())
+            }
+
+            _ => unreachable!("Cast between `{}` and `{}`, which are not consistent", from, to),
+        }
+    }
+
+    /// `fn(v: from) -> to { v as to }`, converting an element of a proxied tuple
+    fn element_cast(&mut self, from: &Type, to: &Type, span: Span) -> NodeId {
+        let name = self.fresh();
+        let param = Param {
+            name,
+            name_span: span,
+            ty: Some(annotation(self.ast, from, span)),
+        };
+        let value = self.variable(name, span, from.clone());
+        let value = self.convert(value, from, to, span);
+        let body = self.add(Node::Block(BlockExpr { stmts: Vec::new(), value: Some(value) }), span, to.clone());
+
+        let ret = annotation(self.ast, to, span);
+        let ty = Type::Fn(vec![from.clone()], Box::new(to.clone()));
+        self.add(Node::Lambda(LambdaExpr { params: vec![param], ret: Some(ret), body }), span, ty)
+    }
+}
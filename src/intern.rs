@@ -0,0 +1,66 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Interned string, comparing and hashing symbols is O(1)
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+/// Maps strings to symbols and back. Interned strings are leaked, they are expected to
+/// live as long as the compiler does.
+pub struct Interner {
+    map: HashMap<&'static str, Symbol>,
+    strings: Vec<&'static str>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            map: HashMap::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    pub fn intern(&mut self, s: &str) -> Symbol {
+        if let Some(sym) = self.map.get(s) {
+            return *sym;
+        }
+
+        let s: &'static str = Box::leak(s.to_string().into_boxed_str());
+        let sym = Symbol(u32::try_from(self.strings.len()).expect("Too many symbols"));
+        self.strings.push(s);
+        self.map.insert(s, sym);
+        sym
+    }
+
+    pub fn resolve(&self, sym: Symbol) -> &'static str {
+        self.strings[sym.0 as usize]
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::new());
+}
+
+impl Symbol {
+    /// Intern into the interner shared by the whole compilation
+    pub fn intern(s: &str) -> Symbol {
+        INTERNER.with(|interner| interner.borrow_mut().intern(s))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        INTERNER.with(|interner| interner.borrow().resolve(self))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
@@ -1,30 +1,41 @@
+use crate::source::Span;
+use crate::diagnostic::Diagnostic;
 use crate::Error;
+use crate::intern::Symbol;
+use std::fmt;
 
-pub struct Lexer {
-    pub source: Vec<char>,
+/// Scans tokens straight out of the source bytes, offsets are byte offsets
+pub struct Lexer<'src> {
+    pub source: &'src str,
     pub current: usize,
+    /// Turn errors into `TokenKind::Error` and keep scanning instead of failing
+    pub recover: bool,
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Token {
     pub kind: TokenKind,
-    pub offset: usize,
+    pub span: Span,
+    /// Span of the `///` doc comments right before the token, if any
+    pub doc: Option<Span>,
 }
 
 impl Token {
-    pub fn new(kind: TokenKind, offset: usize) -> Token {
+    pub fn new(kind: TokenKind, span: Span) -> Token {
         Token {
-            kind: kind,
-            offset: offset,
+            kind,
+            span,
+            doc: None,
         }
     }
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum TokenKind {
-    Identifier(String),
-    String(String),
+    Identifier(Symbol),
+    String(Symbol),
     Integer(i64),
+    #[allow(dead_code, reason = "reserved for named constants, the lexer does not produce it yet")]
     Constant,
 
     Plus,
@@ -51,6 +62,9 @@ pub enum TokenKind {
     Semicolon,
     Colon,
     Comma,
+    Arrow,
+    /// `?`, the dynamic type of gradual typing
+    Question,
 
     ParenOpen,
     ParenClose,
@@ -63,144 +77,347 @@ pub enum TokenKind {
     If,
     Else,
     Let,
+    True,
+    False,
+    While,
+    Break,
+    Continue,
+    Len,
+
+    /// Produced in recovery mode where the source could not be tokenized
+    Error(Box<Diagnostic>),
 
     EndOfFile,
 }
 
-fn is_part_of_identifier(c: char) -> bool {
-    match c {
-        'a'..='z' | 'A'..='Z' | '0'..'9' | '_' => true,
-        _ => false,
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TokenKind as T;
+
+        let s = match self {
+            T::Identifier(name) => return write!(f, "{}", name),
+            T::String(s) => return write!(f, "{:?}", s.as_str()),
+            T::Integer(n) => return write!(f, "{}", n),
+            T::Constant => "constant",
+
+            T::Plus => "+",
+            T::Minus => "-",
+            T::Star => "*",
+            T::Slash => "/",
+            T::Modulo => "%",
+
+            T::ShiftRight => ">>",
+            T::ShiftLeft => "<<",
+            T::Tilde => "~",
+            T::And => "&",
+            T::Or => "|",
+
+            T::Equal => "==",
+            T::NotEqual => "!=",
+            T::Gt => ">",
+            T::GtEq => ">=",
+            T::Lt => "<",
+            T::LtEq => "<=",
+
+            T::Assign => "=",
+            T::Dot => ".",
+            T::Semicolon => ";",
+            T::Colon => ":",
+            T::Comma => ",",
+            T::Arrow => "->",
+            T::Question => "?",
+
+            T::ParenOpen => "(",
+            T::ParenClose => ")",
+            T::SquareOpen => "[",
+            T::SquareClose => "]",
+            T::CurlyOpen => "{",
+            T::CurlyClose => "}",
+
+            T::Fn => "fn",
+            T::If => "if",
+            T::Else => "else",
+            T::Let => "let",
+            T::True => "true",
+            T::False => "false",
+            T::While => "while",
+            T::Break => "break",
+            T::Continue => "continue",
+            T::Len => "len",
+
+            T::Error(_) => "<error>",
+            T::EndOfFile => "<eof>",
+        };
+
+        write!(f, "{}", s)
     }
 }
 
-impl Lexer {
-    pub fn new(source: &str) -> Lexer {
+fn is_part_of_identifier(c: char) -> bool {
+    matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_')
+}
+
+impl<'src> Lexer<'src> {
+    pub fn new(source: &'src str) -> Lexer<'src> {
         Lexer {
-            source: source.chars().collect(),
+            source,
             current: 0,
+            recover: false,
+        }
+    }
+
+    pub fn with_recovery(source: &'src str) -> Lexer<'src> {
+        Lexer {
+            recover: true,
+            ..Lexer::new(source)
         }
     }
 
-    fn make_lexeme(&self, start: usize, end: usize) -> String {
-        (&self.source[start..end]).iter().collect()
+    fn make_lexeme(&self, start: usize, end: usize) -> &'src str {
+        &self.source[start..end]
     }
 
     fn advance(&mut self) -> Option<char> {
-        if self.current >= self.source.len(){
-            return None;
-        }
-        self.current += 1;
-        return Some(self.source[self.current - 1]);
+        let c = self.peek()?;
+        self.current += c.len_utf8();
+        Some(c)
     }
 
     fn peek(&self) -> Option<char> {
-        if self.current >= self.source.len(){
-            return None;
+        self.source[self.current..].chars().next()
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.source[self.current..].chars().nth(n)
+    }
+
+    /// Skip whitespace and comments, returns the span of the doc comments found along the way
+    fn skip_trivia(&mut self) -> Result<Option<Span>, Diagnostic> {
+        let mut doc: Option<Span> = None;
+
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.current += c.len_utf8();
+            }
+            else if c == '/' && self.peek_nth(1) == Some('/') {
+                // `////` and longer are regular comments, like in Rust
+                let is_doc = self.peek_nth(2) == Some('/') && self.peek_nth(3) != Some('/');
+                let start = self.current;
+                self.skip_line_comment();
+
+                if is_doc {
+                    let span = Span::new(start, self.current);
+                    doc = Some(match doc {
+                        Some(prev) => prev.to(span),
+                        None => span,
+                    });
+                }
+            }
+            else if c == '/' && self.peek_nth(1) == Some('*') {
+                self.skip_block_comment()?;
+            }
+            else {
+                break;
+            }
         }
-        return Some(self.source[self.current]);
+
+        Ok(doc)
     }
 
-    fn skip_whitespace(&mut self){
+    fn skip_line_comment(&mut self) {
         loop {
-            let c = match self.advance() {
-                Some(c) => c,
-                None => break,
-            };
+            match self.peek() {
+                None | Some('\n') => break,
+                Some(c) => self.current += c.len_utf8(),
+            }
+        }
+    }
 
-            if !c.is_whitespace(){
-                self.current -= 1;
-                break;
+    /// Block comments nest, so `/* a /* b */ c */` is a single comment
+    fn skip_block_comment(&mut self) -> Result<(), Diagnostic> {
+        let start = self.current;
+        let mut depth = 0;
+
+        loop {
+            match (self.peek(), self.peek_nth(1)) {
+                (Some('/'), Some('*')) => {
+                    self.current += 2;
+                    depth += 1;
+                }
+                (Some('*'), Some('/')) => {
+                    self.current += 2;
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                (Some(c), _) => self.current += c.len_utf8(),
+                (None, _) => break,
             }
         }
+
+        let mut diag = Diagnostic::error(Error::UnterminatedBlockComment, Span::new(start, start + 2));
+        if depth == 2 {
+            diag = diag.with_note("a nested comment is still open");
+        } else if depth > 2 {
+            diag = diag.with_note(format!("{} nested comments are still open", depth - 1));
+        }
+        Err(diag)
+    }
+
+    /// Text of a doc comment, without the `///` markers
+    #[allow(dead_code, reason = "doc comments are kept as tokens for tools, the compiler never reads them")]
+    pub fn doc_text(&self, doc: Span) -> String {
+        let raw = self.make_lexeme(doc.start, doc.end);
+        let lines: Vec<&str> = raw.lines()
+            .map(|line| line.trim_start())
+            .filter_map(|line| line.strip_prefix("///"))
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .collect();
+        lines.join("\n")
     }
 
     fn scan_identifier(&mut self) -> Token {
         let start = self.current;
 
         loop {
-            let c = match self.advance(){
-                Some(c) => c,
-                None => break,
-            };
-
-            if !is_part_of_identifier(c){
-                self.current -= 1;
-                break;
+            match self.peek() {
+                Some(c) if is_part_of_identifier(c) => self.current += 1,
+                _ => break,
             }
         }
 
         assert!(self.current > start, "Invalid identifier length");
 
-        let lexeme: String = self.make_lexeme(start, self.current);
-        let kind = match as_keyword(&lexeme){
+        let lexeme = self.make_lexeme(start, self.current);
+        let kind = match as_keyword(lexeme){
             Some(kw) => kw,
-            None => TokenKind::Identifier(lexeme),
+            None => TokenKind::Identifier(Symbol::intern(lexeme)),
         };
 
-        return Token { kind, offset: start };
+        Token::new(kind, Span::new(start, self.current))
     }
 
-    fn scan_string(&mut self) -> Result<Token, Error> {
+    fn scan_string(&mut self) -> Result<Token, Diagnostic> {
+        let offset = self.current;
         assert!(self.advance() == Some('"'), "Invalid lexer position");
 
-        let mut buf = String::new();
-        let offset = self.current;
+        let contents = self.current;
+        // Only allocated once the first escape sequence shows up
+        let mut buf: Option<String> = None;
 
         loop {
+            let before = self.current;
             let c = match self.advance() {
                 Some(c) => c,
-                None => return Err(Error::UnterminatedString),
+                None => return Err(Diagnostic::error(Error::UnterminatedString, Span::new(offset, self.current))),
             };
 
             if c == '\\' {
                 let next_char = match self.advance() {
                     Some(c) => c,
-                    None => return Err(Error::UnterminatedString),
+                    None => return Err(Diagnostic::error(Error::UnterminatedString, Span::new(offset, self.current))),
                 };
 
                 let escaped = match escape_sequence(next_char) {
                     Some(c) => c,
-                    None => return Err(Error::InvalidEscapeSequence),
+                    None => return Err(Diagnostic::error(Error::InvalidEscapeSequence, Span::new(before, self.current))),
                 };
 
-                buf.push(escaped);
+                buf.get_or_insert_with(|| self.source[contents..before].to_string()).push(escaped);
             }
             else if c == '\n' || c == '\r' {
-                return Err(Error::InvalidMultiLineString);
+                return Err(Diagnostic::error(Error::InvalidMultiLineString, Span::new(offset, before)));
             }
             else if c == '"' {
                 break;
             }
-            else {
+            else if let Some(buf) = &mut buf {
                 buf.push(c);
             }
         }
 
-        let kind = TokenKind::String(buf);
-        return Ok(Token::new(kind, offset));
+        let text = match buf {
+            Some(buf) => Symbol::intern(&buf),
+            None => Symbol::intern(self.make_lexeme(contents, self.current - 1)),
+        };
+
+        Ok(Token::new(TokenKind::String(text), Span::new(offset, self.current)))
     }
 
-    fn scan_decimal_integer(&mut self) -> Result<Token, Error>{
+    /// Scans decimal, `0x` hexadecimal, `0o` octal and `0b` binary integers, `_` can be
+    /// used anywhere after the prefix to separate digits
+    fn scan_integer(&mut self) -> Result<Token, Diagnostic>{
         let offset = self.current;
 
+        let radix = match (self.peek(), self.peek_nth(1)) {
+            (Some('0'), Some('x')) => 16,
+            (Some('0'), Some('o')) => 8,
+            (Some('0'), Some('b')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.current += 2;
+        }
+
+        let mut value: Option<u64> = Some(0);
+        let mut digits = 0;
+        let mut invalid_digit: Option<(char, usize)> = None;
+
+        // Consume the whole literal even after an error, so `12ab` is reported as one bad literal
         loop {
-            let c = match self.advance(){
-                Some(c) => c,
-                None => break,
+            let c = match self.peek() {
+                Some(c) if is_part_of_identifier(c) => c,
+                _ => break,
             };
+            self.current += 1;
 
-            if !c.is_numeric() && c != '_' {
-                self.current -= 1;
-                break;
+            if c == '_' {
+                continue;
             }
+
+            let digit = match c.to_digit(radix) {
+                Some(d) => d as u64,
+                None => {
+                    if invalid_digit.is_none() {
+                        invalid_digit = Some((c, self.current - 1));
+                    }
+                    continue;
+                }
+            };
+
+            digits += 1;
+            value = value
+                .and_then(|v| v.checked_mul(radix as u64))
+                .and_then(|v| v.checked_add(digit));
+        }
+
+        let span = Span::new(offset, self.current);
+
+        if let Some((c, at)) = invalid_digit {
+            let diag = Diagnostic::error(Error::InvalidDigit, Span::new(at, at + 1))
+                .with_message(format!("invalid digit `{}` in base {} literal", c.escape_debug(), radix))
+                .with_label(span, "in this literal");
+            return Err(diag);
+        }
+
+        if digits == 0 {
+            let prefix = self.make_lexeme(offset, offset + 2);
+            let diag = Diagnostic::error(Error::MissingDigits, span)
+                .with_message(format!("expected digits after `{}`", prefix));
+            return Err(diag);
         }
 
-        let lexeme = self.make_lexeme(offset, self.current);
-        let num = lexeme.parse::<i64>().expect("Invalid integer");
-        let kind = TokenKind::Integer(num);
+        let num = match value.and_then(|v| i64::try_from(v).ok()) {
+            Some(num) => num,
+            None => {
+                let diag = Diagnostic::error(Error::IntegerOverflow, span)
+                    .with_note(format!("the largest integer is {}", i64::MAX));
+                return Err(diag);
+            }
+        };
 
-        return Ok(Token::new(kind, offset));
+        Ok(Token::new(TokenKind::Integer(num), span))
     }
 
     fn match_advance(&mut self, target: char) -> bool {
@@ -210,30 +427,81 @@ impl Lexer {
             }
             return c == target;
         }
-        return false;
+        false
+    }
+
+    pub fn next(&mut self) -> Result<Token, Diagnostic> {
+        let start;
+        let scanned = match self.skip_trivia() {
+            Ok(doc) => {
+                start = self.current;
+                self.scan_token().map(|tk| Token { doc, ..tk })
+            }
+            Err(diag) => {
+                start = diag.span.start;
+                Err(diag)
+            }
+        };
+
+        let diag = match scanned {
+            Ok(tk) => return Ok(tk),
+            Err(diag) => diag,
+        };
+
+        if !self.recover {
+            return Err(diag);
+        }
+
+        self.resync(diag.kind, start);
+        let span = Span::new(start, self.current);
+        Ok(Token::new(TokenKind::Error(Box::new(diag)), span))
     }
 
-    pub fn get_token(&mut self) -> Result<Token, Error> {
-        let restore = self.current;
-        let res = self.next();
-        self.current = restore;
-        return res;
+    /// Move past the bad input so scanning can continue after an error
+    fn resync(&mut self, kind: Error, start: usize) {
+        match kind {
+            // Close the string at the first quote or the end of its line, whatever comes first
+            Error::InvalidEscapeSequence | Error::UnterminatedString | Error::InvalidMultiLineString => {
+                self.current = start + 1;
+                loop {
+                    match self.peek() {
+                        None | Some('\n') | Some('\r') => break,
+                        Some('"') => {
+                            self.current += 1;
+                            break;
+                        }
+                        Some('\\') => {
+                            self.current += 1;
+                            if let Some(c) = self.peek() {
+                                self.current += c.len_utf8();
+                            }
+                        }
+                        Some(c) => self.current += c.len_utf8(),
+                    }
+                }
+            }
+
+            // Skip the offending codepoint
+            _ => {
+                if self.current == start {
+                    self.advance();
+                }
+            }
+        }
     }
 
-    pub fn next(&mut self) -> Result<Token, Error> {
+    fn scan_token(&mut self) -> Result<Token, Diagnostic> {
         use TokenKind as T;
 
-        self.skip_whitespace();
-
         let offset = self.current;
 
         let c = match self.peek() {
             Some(c) => c,
-            None => return Ok(Token::new(T::EndOfFile, offset)),
+            None => return Ok(Token::new(T::EndOfFile, Span::at(offset))),
         };
 
-        if c.is_numeric(){
-            return self.scan_decimal_integer();
+        if c.is_ascii_digit(){
+            return self.scan_integer();
         }
 
         if is_part_of_identifier(c){
@@ -251,6 +519,7 @@ impl Lexer {
             ':' => Ok(T::Colon),
             ',' => Ok(T::Comma),
             ';' => Ok(T::Semicolon),
+            '?' => Ok(T::Question),
 
             '(' => Ok(T::ParenOpen),
             ')' => Ok(T::ParenClose),
@@ -260,7 +529,11 @@ impl Lexer {
             '}' => Ok(T::CurlyClose),
 
             '+' => Ok(T::Plus),
-            '-' => Ok(T::Minus),
+            '-' => if self.match_advance('>') {
+                Ok(T::Arrow)
+            } else {
+                Ok(T::Minus)
+            },
             '*' => Ok(T::Star),
             '/' => Ok(T::Slash),
             '%' => Ok(T::Modulo),
@@ -271,7 +544,10 @@ impl Lexer {
             '!' => if self.match_advance('='){
                 Ok(T::NotEqual)
             } else {
-                Err(Error::InvalidOperator)
+                let span = Span::new(offset, self.current);
+                Err(Diagnostic::error(Error::InvalidOperator, span)
+                    .with_message("invalid operator `!`")
+                    .with_suggestion("use `~` for negation or `!=` for inequality"))
             }
             '=' => if self.match_advance('='){
                 Ok(T::Equal)
@@ -295,20 +571,30 @@ impl Lexer {
                 Ok(T::Lt)
             },
 
-            _ => Err(Error::UnknownCodepoint),
+            _ => {
+                let span = Span::new(offset, self.current);
+                Err(Diagnostic::error(Error::UnknownCodepoint, span)
+                    .with_message(format!("unknown character `{}`", c.escape_debug())))
+            }
         }?;
 
-        return Ok(Token::new(kind, offset));
+        Ok(Token::new(kind, Span::new(offset, self.current)))
     }
 }
 
 fn as_keyword(s: &str) -> Option<TokenKind> {
     use TokenKind as T;
 
-    static KEYWORDS: [(&'static str, TokenKind); 4] = [
+    static KEYWORDS: [(&str, TokenKind); 10] = [
         ("if", T::If),
         ("else", T::Else),
         ("let", T::Let),
+        ("true", T::True),
+        ("false", T::False),
+        ("while", T::While),
+        ("break", T::Break),
+        ("continue", T::Continue),
+        ("len", T::Len),
         ("fn", T::Fn),
     ];
 
@@ -318,7 +604,7 @@ fn as_keyword(s: &str) -> Option<TokenKind> {
         }
     }
 
-    return None;
+    None
 }
 
 fn escape_sequence(c: char) -> Option<char> {
@@ -334,3 +620,107 @@ fn escape_sequence(c: char) -> Option<char> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every token of `source` up to the end of the file, errors included
+    fn tokens(source: &str) -> Vec<Token> {
+        let mut lexer = Lexer::with_recovery(source);
+        let mut tokens = Vec::new();
+        loop {
+            let tk = lexer.next().expect("Lexer in recovery mode cannot fail");
+            if tk.kind == TokenKind::EndOfFile {
+                return tokens;
+            }
+            tokens.push(tk);
+        }
+    }
+
+    #[test]
+    fn bad_character_does_not_stop_the_lexer() {
+        let tokens = tokens("let x = 1 $ 2;\ny");
+        let kinds: Vec<String> = tokens.iter().map(|tk| tk.kind.to_string()).collect();
+        assert_eq!(kinds.len(), 8);
+        assert_eq!(&kinds[..4], ["let", "x", "=", "1"]);
+        assert_eq!(&kinds[5..], ["2", ";", "y"]);
+
+        let TokenKind::Error(diag) = &tokens[4].kind else {
+            panic!("Expected an error token, found {}", tokens[4].kind);
+        };
+        assert_eq!(diag.code(), "E0001");
+        assert_eq!(diag.span, Span::new(10, 11));
+        assert_eq!(tokens[4].span, Span::new(10, 11));
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let tokens = tokens("a # b \"open\nc @");
+        let errors: Vec<&str> = tokens.iter()
+            .filter_map(|tk| match &tk.kind {
+                TokenKind::Error(diag) => Some(diag.code()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, ["E0001", "E0005", "E0001"]);
+
+        // The string broken by a newline ends with its line, `c` is lexed again
+        let names: Vec<String> = tokens.iter()
+            .filter(|tk| matches!(tk.kind, TokenKind::Identifier(_)))
+            .map(|tk| tk.kind.to_string())
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn without_recovery_the_first_error_is_returned() {
+        let mut lexer = Lexer::new("1 $ 2");
+        assert!(matches!(lexer.next(), Ok(Token { kind: TokenKind::Integer(1), .. })));
+        assert_eq!(lexer.next().expect_err("Invalid character accepted").code(), "E0001");
+    }
+
+    /// Value of the integer literal `source`, or why it is invalid
+    fn integer(source: &str) -> Result<i64, Diagnostic> {
+        match Lexer::new(source).next()?.kind {
+            TokenKind::Integer(n) => Ok(n),
+            kind => panic!("Expected an integer, found {}", kind),
+        }
+    }
+
+    #[test]
+    fn literals_in_every_base() {
+        assert_eq!(integer("1_000"), Ok(1000));
+        assert_eq!(integer("0x1F"), Ok(31));
+        assert_eq!(integer("0o17"), Ok(15));
+        assert_eq!(integer("0b1010_1010"), Ok(170));
+        assert_eq!(integer("9223372036854775807"), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn overflowing_literal() {
+        let diag = integer("99999999999999999999").expect_err("Overflow accepted");
+        assert_eq!(diag.code(), "E0007");
+        assert_eq!(diag.span, Span::new(0, 20));
+        assert_eq!(diag.notes, ["the largest integer is 9223372036854775807"]);
+
+        assert_eq!(integer("0x8000000000000000").expect_err("Overflow accepted").code(), "E0007");
+    }
+
+    #[test]
+    fn invalid_digit_points_at_the_digit() {
+        let diag = integer("0b102").expect_err("Invalid digit accepted");
+        assert_eq!(diag.code(), "E0008");
+        assert_eq!(diag.span, Span::new(4, 5));
+        assert_eq!(diag.message, "invalid digit `2` in base 2 literal");
+        assert_eq!(diag.labels[0].span, Span::new(0, 5));
+    }
+
+    #[test]
+    fn prefix_without_digits() {
+        let diag = integer("0x").expect_err("Empty literal accepted");
+        assert_eq!(diag.code(), "E0009");
+        assert_eq!(diag.span, Span::new(0, 2));
+        assert_eq!(integer("0b_").expect_err("Empty literal accepted").code(), "E0009");
+    }
+}
@@ -0,0 +1,305 @@
+use crate::cir::Label;
+use crate::dataflow::{self, Cfg};
+use crate::intern::Symbol;
+use crate::x86::{Arg, Function, Instr, Reg, ARG_REGS, CALLER_SAVED};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Place a value can live in, before registers are allocated
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Location {
+    Reg(Reg),
+    Var(Symbol),
+}
+
+pub type LiveSet = BTreeSet<Location>;
+
+/// Live locations of a function, from `liveness`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Liveness {
+    /// Locations live at the start of each block
+    pub before: HashMap<Label, LiveSet>,
+    /// Locations live after each instruction of each block
+    pub after: HashMap<Label, Vec<LiveSet>>,
+}
+
+/// Location an operand names. Memory is not a location, and neither are `rsp` and `rbp`
+/// since they hold the frame and are never allocated
+fn location(arg: &Arg) -> Option<Location> {
+    let reg = match arg {
+        Arg::Reg(reg) => *reg,
+        Arg::ByteReg(reg) => reg.reg(),
+        Arg::Var(name) => return Some(Location::Var(*name)),
+        Arg::Imm(_) | Arg::Deref(..) | Arg::Global(_) => return None,
+    };
+    match reg {
+        Reg::Rsp | Reg::Rbp => None,
+        _ => Some(Location::Reg(reg)),
+    }
+}
+
+/// Locations read to evaluate an operand, including the base of an address
+fn read(arg: &Arg, out: &mut Vec<Location>) {
+    match arg {
+        Arg::Deref(reg, _) => out.extend(location(&Arg::Reg(*reg))),
+        _ => out.extend(location(arg)),
+    }
+}
+
+/// Locations written through a destination operand. Storing to memory only reads the
+/// base of the address
+fn write(arg: &Arg, reads: &mut Vec<Location>, writes: &mut Vec<Location>) {
+    match arg {
+        Arg::Deref(..) => read(arg, reads),
+        _ => writes.extend(location(arg)),
+    }
+}
+
+/// Locations an instruction reads and the ones it writes. A call reads the registers its
+/// arguments are passed in and overwrites every caller-saved register
+pub fn reads_writes(instr: &Instr) -> (Vec<Location>, Vec<Location>) {
+    let mut reads = Vec::new();
+    let mut writes = Vec::new();
+    match instr {
+        Instr::Movq(src, dst) | Instr::Movzbq(src, dst) | Instr::Leaq(src, dst) => {
+            read(src, &mut reads);
+            write(dst, &mut reads, &mut writes);
+        }
+        Instr::Addq(src, dst)
+        | Instr::Subq(src, dst)
+        | Instr::Imulq(src, dst)
+        | Instr::Andq(src, dst)
+        | Instr::Orq(src, dst)
+        | Instr::Xorq(src, dst)
+        | Instr::Salq(src, dst)
+        | Instr::Sarq(src, dst) => {
+            read(src, &mut reads);
+            read(dst, &mut reads);
+            write(dst, &mut reads, &mut writes);
+        }
+        Instr::Negq(dst) | Instr::Notq(dst) => {
+            read(dst, &mut reads);
+            write(dst, &mut reads, &mut writes);
+        }
+        Instr::Cmpq(a, b) => {
+            read(a, &mut reads);
+            read(b, &mut reads);
+        }
+        Instr::Set(_, dst) | Instr::Popq(dst) => write(dst, &mut reads, &mut writes),
+        Instr::Pushq(src) => read(src, &mut reads),
+        Instr::Cqto => {
+            reads.push(Location::Reg(Reg::Rax));
+            writes.push(Location::Reg(Reg::Rdx));
+        }
+        Instr::Idivq(src) => {
+            read(src, &mut reads);
+            reads.push(Location::Reg(Reg::Rax));
+            reads.push(Location::Reg(Reg::Rdx));
+            writes.push(Location::Reg(Reg::Rax));
+            writes.push(Location::Reg(Reg::Rdx));
+        }
+        Instr::Callq(_, arity) | Instr::IndirectCallq(_, arity) => {
+            if let Instr::IndirectCallq(target, _) = instr {
+                read(target, &mut reads);
+            }
+            reads.extend(ARG_REGS[..*arity].iter().map(|reg| Location::Reg(*reg)));
+            writes.extend(CALLER_SAVED.iter().map(|reg| Location::Reg(*reg)));
+        }
+        Instr::Retq => reads.push(Location::Reg(Reg::Rax)),
+        Instr::Jmp(_) | Instr::JmpIf(..) => {}
+    }
+    (reads, writes)
+}
+
+/// Successors of each block, the labels it jumps to
+pub fn cfg(function: &Function) -> Cfg {
+    let mut cfg = Vec::new();
+    for (label, block) in &function.blocks {
+        let mut succs = Vec::new();
+        for instr in &block.instrs {
+            if let Instr::Jmp(target) | Instr::JmpIf(_, target) = instr {
+                if !succs.contains(target) {
+                    succs.push(*target);
+                }
+            }
+        }
+        cfg.push((*label, succs));
+    }
+    cfg
+}
+
+/// Live sets after each instruction of a block, given the set live after the block, and
+/// the set live before it. Jumps read nothing, so what is live after a conditional jump
+/// in the middle of a block also counts as live at the end. Such a jump either comes
+/// right before the last one or goes to a trap stub where nothing is live, so the extra
+/// locations never interfere with anything
+fn walk(instrs: &[Instr], live: &LiveSet) -> (Vec<LiveSet>, LiveSet) {
+    let mut after = vec![LiveSet::new(); instrs.len()];
+    let mut live = live.clone();
+    for (i, instr) in instrs.iter().enumerate().rev() {
+        after[i] = live.clone();
+        let (reads, writes) = reads_writes(instr);
+        for location in writes {
+            live.remove(&location);
+        }
+        live.extend(reads);
+    }
+    (after, live)
+}
+
+/// Locations live after every instruction of a function. Returns jump to the conclusion
+/// with the result in `rax`
+pub fn liveness(function: &Function) -> Liveness {
+    let cfg = cfg(function);
+    let blocks: HashMap<Label, &[Instr]> = function.blocks.iter().map(|(label, block)| (*label, &block.instrs[..])).collect();
+    let conclusion = function.conclusion();
+    let exit = |label: Label| -> LiveSet {
+        let mut live = LiveSet::new();
+        if label == conclusion {
+            live.insert(Location::Reg(Reg::Rax));
+        }
+        live
+    };
+    let join = |a: &LiveSet, b: &LiveSet| -> LiveSet { a.union(b).cloned().collect() };
+
+    let before = dataflow::backward(&cfg, LiveSet::new(), exit, join, |label, live| walk(blocks[&label], live).1);
+
+    let mut after = HashMap::new();
+    for (label, succs) in &cfg {
+        let mut live = LiveSet::new();
+        for succ in succs {
+            match before.get(succ) {
+                Some(succ_before) => live.extend(succ_before.iter().cloned()),
+                None => live.extend(exit(*succ)),
+            }
+        }
+        after.insert(*label, walk(blocks[label], &live).0);
+    }
+    Liveness { before, after }
+}
+
+impl Liveness {
+    /// The function with the locations live at the start of each block next to its label,
+    /// and the ones live after each instruction next to it
+    pub fn dump(&self, function: &Function) -> String {
+        let mut out = format!("    .globl {}\n{}:\n", function.name, function.name);
+        for (label, block) in &function.blocks {
+            let label_line = format!("{}:", label);
+            out.push_str(&format!("{:<40}# {}\n", label_line, LiveSetDisplay(&self.before[label])));
+            for (instr, live) in block.instrs.iter().zip(&self.after[label]) {
+                let instr_line = format!("    {}", instr);
+                out.push_str(&format!("{:<40}# {}\n", instr_line, LiveSetDisplay(live)));
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Reg(reg) => write!(f, "{}", reg),
+            Location::Var(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+struct LiveSetDisplay<'a>(&'a LiveSet);
+
+impl fmt::Display for LiveSetDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let locations: Vec<String> = self.0.iter().map(|location| location.to_string()).collect();
+        write!(f, "{{{}}}", locations.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::{Block, Cc};
+
+    fn sym(name: &str) -> Symbol {
+        Symbol::intern(name)
+    }
+
+    fn var(name: &str) -> Arg {
+        Arg::Var(sym(name))
+    }
+
+    fn reg(reg: Reg) -> Location {
+        Location::Reg(reg)
+    }
+
+    fn function(name: &str, blocks: Vec<(&str, Vec<Instr>)>) -> Function {
+        let blocks = blocks.into_iter().map(|(label, instrs)| (sym(label), Block { instrs })).collect();
+        Function { name: sym(name), vars: Vec::new(), blocks }
+    }
+
+    #[test]
+    fn loop_variable_stays_live_across_the_back_edge() {
+        // `x` is only read in the body, it is live at the head because the body jumps back
+        let f = function("count", vec![
+            ("count.start", vec![
+                Instr::Movq(Arg::Imm(0), var("n")),
+                Instr::Movq(Arg::Imm(1), var("x")),
+                Instr::Jmp(sym("loop.0")),
+            ]),
+            ("loop.0", vec![
+                Instr::Cmpq(Arg::Imm(10), var("n")),
+                Instr::JmpIf(Cc::L, sym("body.0")),
+                Instr::Jmp(sym("after.0")),
+            ]),
+            ("body.0", vec![
+                Instr::Addq(var("x"), var("n")),
+                Instr::Jmp(sym("loop.0")),
+            ]),
+            ("after.0", vec![
+                Instr::Movq(var("n"), Arg::Reg(Reg::Rax)),
+                Instr::Jmp(sym("count.conclusion")),
+            ]),
+        ]);
+        let live = liveness(&f);
+
+        let x = Location::Var(sym("x"));
+        assert!(live.before[&sym("loop.0")].contains(&x));
+        assert!(live.after[&sym("body.0")].iter().all(|set| set.contains(&x)));
+        assert!(!live.before[&sym("after.0")].contains(&x));
+        assert!(!live.before[&sym("count.start")].contains(&x));
+        assert_eq!(live.before[&sym("after.0")], LiveSet::from([Location::Var(sym("n"))]));
+    }
+
+    #[test]
+    fn division_uses_rax_and_rdx() {
+        assert_eq!(reads_writes(&Instr::Cqto), (vec![reg(Reg::Rax)], vec![reg(Reg::Rdx)]));
+
+        let (reads, writes) = reads_writes(&Instr::Idivq(var("d")));
+        assert_eq!(reads, vec![Location::Var(sym("d")), reg(Reg::Rax), reg(Reg::Rdx)]);
+        assert_eq!(writes, vec![reg(Reg::Rax), reg(Reg::Rdx)]);
+    }
+
+    #[test]
+    fn call_reads_its_arguments_and_kills_caller_saved_registers() {
+        let (reads, writes) = reads_writes(&Instr::Callq(sym("f"), 2));
+        assert_eq!(reads, vec![reg(Reg::Rdi), reg(Reg::Rsi)]);
+        assert_eq!(writes, CALLER_SAVED.iter().map(|r| reg(*r)).collect::<Vec<_>>());
+
+        // `rdx` is read after the call, which writes it, so it is not live before
+        let f = function("g", vec![
+            ("g.start", vec![
+                Instr::Movq(Arg::Imm(2), var("y")),
+                Instr::Callq(sym("f"), 0),
+                Instr::Addq(Arg::Reg(Reg::Rdx), Arg::Reg(Reg::Rax)),
+                Instr::Addq(var("y"), Arg::Reg(Reg::Rax)),
+                Instr::Jmp(sym("g.conclusion")),
+            ]),
+        ]);
+        let live = liveness(&f);
+        let after = &live.after[&sym("g.start")];
+
+        assert!(after[1].contains(&reg(Reg::Rdx)));
+        assert!(!after[0].contains(&reg(Reg::Rdx)));
+        assert!(!live.before[&sym("g.start")].contains(&reg(Reg::Rdx)));
+        // Variables are not registers yet, they survive the call
+        assert!(after[0].contains(&Location::Var(sym("y"))));
+    }
+}
@@ -1,138 +1,378 @@
-mod lexer;
-
-use lexer::{Lexer, Token, TokenKind};
-use std::num::NonZeroU32;
-use std::mem::size_of;
-
 macro_rules! const_assert {
     ($x:expr, $msg:expr) => {
         const _: () = ::core::assert!($x, $msg);
     };
 }
 
-#[derive(Debug)]
+mod ast;
+mod cir;
+mod closure;
+mod dataflow;
+mod diagnostic;
+mod dynamic;
+mod explicate;
+mod expose;
+mod generic;
+mod gradual;
+mod intern;
+mod lexer;
+mod liveness;
+mod parser;
+mod rco;
+mod select;
+mod source;
+mod types;
+mod uniquify;
+mod x86;
+
+use ast::Ast;
+use diagnostic::Diagnostic;
+use lexer::{Lexer, TokenKind};
+use parser::Parser;
+use source::SourceMap;
+use std::io::IsTerminal;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Error {
     UnknownCodepoint,
     UnterminatedString,
     InvalidEscapeSequence,
     InvalidOperator,
     InvalidMultiLineString,
-}
+    UnterminatedBlockComment,
+    IntegerOverflow,
+    InvalidDigit,
+    MissingDigits,
 
-#[derive(Copy, Clone)]
-struct NodeId {
-    pub gen: NonZeroU32,
-    pub offset: u32,
-}
+    UnexpectedToken,
+    ExpectedExpression,
+    UnclosedParen,
+    NonAssociativeOperator,
+    MissingSemicolon,
+    ExpectedIdentifier,
+    UnclosedBrace,
+    ExpectedType,
+    DuplicateParameter,
+    DuplicateFunction,
+    ArityMismatch,
+    NestedFunction,
+    InvalidAssignTarget,
+    InvalidAssignment,
+    BreakOutsideLoop,
+    ExpectedTupleIndex,
 
-struct PrimaryExpr {
-    value: Token,
-}
+    TypeMismatch,
+    UnknownType,
+    UnknownVariable,
+    NotCallable,
+    InvalidTupleIndex,
+    TupleTooLong,
+    InfiniteType,
+    TypeAnnotationNeeded,
+    InstantiationLimit,
+    UnexpectedAnnotation,
 
-struct UnaryExpr {
-    operator: TokenKind,
-    operand: NodeId,
+    StaleNodeId,
+    ComplexOperand,
 }
 
-struct BinaryExpr {
-    operator: TokenKind,
-    left: NodeId,
-    right: NodeId,
-}
+impl Error {
+    /// Stable identifier, lexer errors are `E00xx`, parser errors `E01xx`, type errors
+    /// `E02xx` and internal compiler errors `E9xxx`
+    pub fn code(&self) -> &'static str {
+        use Error as E;
 
-enum Node {
-    Primary(PrimaryExpr),
-    Unary(UnaryExpr),
-    Binary(BinaryExpr),
-    // Block()
-}
+        match self {
+            E::UnknownCodepoint => "E0001",
+            E::UnterminatedString => "E0002",
+            E::InvalidEscapeSequence => "E0003",
+            E::InvalidOperator => "E0004",
+            E::InvalidMultiLineString => "E0005",
+            E::UnterminatedBlockComment => "E0006",
+            E::IntegerOverflow => "E0007",
+            E::InvalidDigit => "E0008",
+            E::MissingDigits => "E0009",
 
-impl Node {
-    pub fn is_expression(&self) -> bool {
-        use Node as T;
+            E::UnexpectedToken => "E0100",
+            E::ExpectedExpression => "E0101",
+            E::UnclosedParen => "E0102",
+            E::NonAssociativeOperator => "E0103",
+            E::MissingSemicolon => "E0104",
+            E::ExpectedIdentifier => "E0105",
+            E::UnclosedBrace => "E0106",
+            E::ExpectedType => "E0107",
+            E::DuplicateParameter => "E0108",
+            E::DuplicateFunction => "E0109",
+            E::ArityMismatch => "E0110",
+            E::NestedFunction => "E0111",
+            E::InvalidAssignTarget => "E0112",
+            E::InvalidAssignment => "E0113",
+            E::BreakOutsideLoop => "E0114",
+            E::ExpectedTupleIndex => "E0115",
 
-        match &self {
-            | T::Primary(_)
-            | T::Unary(_)
-            | T::Binary(_) => true,
+            E::TypeMismatch => "E0200",
+            E::UnknownType => "E0201",
+            E::UnknownVariable => "E0202",
+            E::NotCallable => "E0203",
+            E::InvalidTupleIndex => "E0204",
+            E::TupleTooLong => "E0205",
+            E::InfiniteType => "E0206",
+            E::TypeAnnotationNeeded => "E0207",
+            E::InstantiationLimit => "E0208",
+            E::UnexpectedAnnotation => "E0209",
 
-            _ => false,
+            E::StaleNodeId => "E9000",
+            E::ComplexOperand => "E9001",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        use Error as E;
+
+        match self {
+            E::UnknownCodepoint => "unknown character",
+            E::UnterminatedString => "unterminated string literal",
+            E::InvalidEscapeSequence => "invalid escape sequence",
+            E::InvalidOperator => "invalid operator",
+            E::InvalidMultiLineString => "string literals cannot span multiple lines",
+            E::UnterminatedBlockComment => "unterminated block comment",
+            E::IntegerOverflow => "integer literal is too large",
+            E::InvalidDigit => "invalid digit in integer literal",
+            E::MissingDigits => "integer literal has no digits",
+
+            E::UnexpectedToken => "unexpected token",
+            E::ExpectedExpression => "expected expression",
+            E::UnclosedParen => "unclosed parenthesis",
+            E::NonAssociativeOperator => "comparison operators cannot be chained",
+            E::MissingSemicolon => "expected `;`",
+            E::ExpectedIdentifier => "expected identifier",
+            E::UnclosedBrace => "unclosed brace",
+            E::ExpectedType => "expected type",
+            E::DuplicateParameter => "duplicate parameter",
+            E::DuplicateFunction => "duplicate function",
+            E::ArityMismatch => "wrong number of arguments",
+            E::NestedFunction => "nested function declaration",
+            E::InvalidAssignTarget => "invalid assignment target",
+            E::InvalidAssignment => "cannot assign to this value",
+            E::BreakOutsideLoop => "`break` or `continue` outside of a loop",
+            E::ExpectedTupleIndex => "expected tuple index",
+
+            E::TypeMismatch => "mismatched types",
+            E::UnknownType => "unknown type",
+            E::UnknownVariable => "unknown variable",
+            E::NotCallable => "call of a value that is not a function",
+            E::InvalidTupleIndex => "invalid tuple index",
+            E::TupleTooLong => "tuple has too many elements",
+            E::InfiniteType => "infinite type",
+            E::TypeAnnotationNeeded => "type annotation needed",
+            E::InstantiationLimit => "too many instances of a generic function",
+            E::UnexpectedAnnotation => "type annotation in a dynamically typed program",
+
+            E::StaleNodeId => "use of a node that has been freed",
+            E::ComplexOperand => "operand is not atomic",
         }
     }
 }
 
-const_assert!(size_of::<NodeId>() == 8, "Unexpected layout for NodeId");
+const EXAMPLE: &str = "69 + 7 / (5 + 420)";
 
-// pub enum AstNode {
-//     Expr(AstExpr),
-//     Scope(AstScope),
-// }
+/// Passes whose output can be printed with `--dump=<pass>`, in the order they run
+const PASSES: &[&str] = &["ast", "types", "casts", "generics", "closures", "allocation", "uniquify", "rco", "explicate", "select", "liveness"];
 
-// pub struct Ast {
-//     nodes: Vec<AstNode>,
-// }
+struct Options {
+    path: Option<String>,
+    json: bool,
+    color: bool,
+    /// Pass after which to print the program and stop, the last one by default
+    dump: &'static str,
+    generics: generic::Strategy,
+    typing: Typing,
+}
 
-pub struct Parser {
-    lexer: Lexer,
+/// How much of the program is checked before it runs
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Typing {
+    /// Every type known at compile time
+    Static,
+    /// No type annotations, types checked at runtime
+    Dynamic,
+    /// Annotations where the program has them, casts where typed and untyped code meet
+    Gradual,
 }
 
-impl Parser {
-    pub fn from_source(source: &str) -> Parser {
-        Parser::from_lexer(Lexer::new(source))
-    }
+fn parse_options() -> Options {
+    let mut opts = Options {
+        path: None,
+        json: false,
+        color: std::io::stdout().is_terminal(),
+        dump: PASSES[PASSES.len() - 1],
+        generics: generic::Strategy::Monomorphize,
+        typing: Typing::Static,
+    };
 
-    pub fn from_lexer(lexer: Lexer) -> Parser {
-        Parser {
-            lexer: lexer,
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => opts.json = true,
+            "--dynamic" => opts.typing = Typing::Dynamic,
+            "--gradual" => opts.typing = Typing::Gradual,
+            "--color=always" => opts.color = true,
+            "--color=never" => opts.color = false,
+            "--color=auto" => {}
+            "--generics=mono" => opts.generics = generic::Strategy::Monomorphize,
+            "--generics=box" => opts.generics = generic::Strategy::Box,
+            _ if arg.starts_with("--dump=") => {
+                let pass = &arg["--dump=".len()..];
+                match PASSES.iter().find(|p| **p == pass) {
+                    Some(pass) => opts.dump = pass,
+                    None => {
+                        println!("Error: unknown pass {}, expected one of {}", pass, PASSES.join(", "));
+                        std::process::exit(2);
+                    }
+                }
+            }
+            _ if arg.starts_with("--") => {
+                println!("Error: unknown option {}", arg);
+                std::process::exit(2);
+            }
+            _ => opts.path = Some(arg),
         }
     }
 
-    fn advance(&mut self) -> Option<Token> {
-        match self.lexer.next() {
-            Ok(tk) => Some(tk),
-            Err(e) => {
-                println!("Error: {:?} at {}", e, self.lexer.current);
-                None
-            },
-        }
-    }
+    opts
+}
 
-    fn peek(&mut self) -> Option<Token> {
-        match self.lexer.get_token() {
-            Ok(tk) => Some(tk),
-            Err(e) => {
-                println!("Error: {:?} at {}", e, self.lexer.current);
-                None
-            },
-        }
+fn report(diag: &Diagnostic, map: &SourceMap, opts: &Options) {
+    if opts.json {
+        println!("{}", diag.to_json(map));
+    } else {
+        print!("{}", diag.render(map, opts.color));
     }
 }
 
 fn main() {
-    let source = r#"
-    69 + 7 / (5 + 420)
-    "#.trim();
+    let opts = parse_options();
 
+    let (name, source) = match &opts.path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(source) => (path.clone(), source),
+            Err(e) => {
+                println!("Error: could not read {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => ("<example>".to_string(), EXAMPLE.to_string()),
+    };
+    let source = source.as_str();
 
-    let mut lex = Lexer::new(source);
-    println!("{}", source.trim());
+    let map = SourceMap::new(&name, source);
 
+    // Lex the whole file first so every lexical error gets reported in one go
+    let mut lexer = Lexer::with_recovery(source);
+    let mut lex_errors = 0;
     loop {
-        let tk = match lex.next() {
-            Ok(v) => v,
-            Err(e) => {
-                println!("[{}] Error {:?}", lex.current, e);
-                break;
-            },
-        };
+        let tk = lexer.next().expect("Lexer in recovery mode cannot fail");
+        match tk.kind {
+            TokenKind::Error(diag) => {
+                report(&diag, &map, &opts);
+                lex_errors += 1;
+            }
+            TokenKind::EndOfFile => break,
+            _ => {}
+        }
+    }
+
+    if lex_errors > 0 {
+        std::process::exit(1);
+    }
 
-        if tk.kind == TokenKind::EndOfFile {
-            break;
+    let mut parser = Parser::from_source(source);
+    let root = match parser.parse() {
+        Ok(root) => root,
+        Err(diag) => {
+            report(&diag, &map, &opts);
+            std::process::exit(1);
         }
+    };
+    let mut ast = parser.ast;
 
-        println!("{:?}", tk);
+    let dump = |pass: &str, ast: &Ast| {
+        if opts.dump == pass {
+            println!("{}", ast.dump(root));
+            std::process::exit(0);
+        }
+    };
+    dump("ast", &ast);
+
+    let typed = match opts.typing {
+        Typing::Static => types::type_check(&ast, root),
+        Typing::Dynamic => dynamic::lower_dynamic(&mut ast, root),
+        Typing::Gradual => gradual::check_gradual(&mut ast, root),
+    };
+    let mut types = match typed {
+        Ok(types) => types,
+        Err(diag) => {
+            report(&diag, &map, &opts);
+            std::process::exit(1);
+        }
+    };
+    if opts.dump == "types" {
+        // Every node the checker typed is shown with its type
+        println!("{}", ast.dump_with(root, &|id| types.try_get(id).map(|ty| ty.to_string())));
+        std::process::exit(0);
     }
 
-    println!("Reg: {}, Opt: {}", size_of::<NodeId>(), size_of::<Option<NodeId>>());
-}
+    gradual::lower_casts(&mut ast, root, &mut types);
+    dump("casts", &ast);
 
+    let instances = match generic::lower_generics(&mut ast, root, &mut types, opts.generics) {
+        Ok(instances) => instances,
+        Err(diag) => {
+            report(&diag, &map, &opts);
+            std::process::exit(1);
+        }
+    };
+    if opts.dump == "generics" {
+        // Readable names of the specialized functions come before the program
+        print!("{}", instances.dump());
+    }
+    dump("generics", &ast);
+
+    closure::convert_closures(&mut ast, root, &mut types);
+    dump("closures", &ast);
+
+    expose::expose_allocation(&mut ast, root, &mut types);
+    dump("allocation", &ast);
+
+    let mut renames = uniquify::uniquify(&mut ast, root);
+    if opts.dump == "uniquify" {
+        // Comments mapping the fresh names back to the source come before the program
+        print!("{}", renames.dump(&map));
+    }
+    dump("uniquify", &ast);
+
+    rco::remove_complex_operands(&mut ast, root, &mut types, &mut renames);
+    if let Err(diag) = rco::check_monadic(&ast, root) {
+        report(&diag, &map, &opts);
+        std::process::exit(1);
+    }
+    dump("rco", &ast);
+
+    let program = explicate::explicate_control(&ast, root, &types, &mut renames);
+    if opts.dump == "explicate" {
+        println!("{}", program);
+        std::process::exit(0);
+    }
+
+    let program = select::select_instructions(&program, &map);
+    if opts.dump == "select" {
+        println!("{}", program);
+        std::process::exit(0);
+    }
+
+    let live: Vec<liveness::Liveness> = program.functions.iter().map(liveness::liveness).collect();
+    if opts.dump == "liveness" {
+        let functions: Vec<String> = program.functions.iter().zip(&live).map(|(func, live)| live.dump(func)).collect();
+        println!("{}", functions.join("\n"));
+        std::process::exit(0);
+    }
+}
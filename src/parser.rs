@@ -0,0 +1,882 @@
+use crate::lexer::{Lexer, Token, TokenKind};
+use crate::ast::{
+    AssignExpr, Ast, BinaryExpr, BlockExpr, CallExpr, ExprStmt, FnDecl, FnType, IfExpr, IndexExpr,
+    LambdaExpr, LenExpr, LetStmt, Node, NodeId, Param, PrimaryExpr, Program, TupleExpr, TupleType,
+    TypeName, UnaryExpr, WhileExpr,
+};
+use crate::intern::Symbol;
+use crate::diagnostic::Diagnostic;
+use crate::source::Span;
+use crate::Error;
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Assoc {
+    Left,
+    /// Chaining is an error, `a < b < c` must be written with parenthesis
+    None,
+}
+
+/// Binding power of unary prefix operators, binds tighter than every infix operator
+const PREFIX_PRECEDENCE: u8 = 8;
+
+/// Precedence (higher binds tighter) and associativity of infix operators
+pub fn infix_binding(kind: &TokenKind) -> Option<(u8, Assoc)> {
+    use TokenKind as T;
+
+    let binding = match kind {
+        T::Or => (1, Assoc::Left),
+        T::And => (2, Assoc::Left),
+
+        T::Equal | T::NotEqual => (3, Assoc::None),
+
+        T::Gt | T::GtEq | T::Lt | T::LtEq => (4, Assoc::None),
+
+        T::ShiftLeft | T::ShiftRight => (5, Assoc::Left),
+
+        T::Plus | T::Minus => (6, Assoc::Left),
+
+        T::Star | T::Slash | T::Modulo => (7, Assoc::Left),
+
+        _ => return None,
+    };
+
+    Some(binding)
+}
+
+pub fn is_prefix_operator(kind: &TokenKind) -> bool {
+    matches!(kind, TokenKind::Minus | TokenKind::Tilde)
+}
+
+pub struct Parser<'src> {
+    lexer: Lexer<'src>,
+    /// Tokens already scanned but not consumed yet, front is the next token
+    lookahead: VecDeque<Token>,
+    pub ast: Ast,
+}
+
+impl<'src> Parser<'src> {
+    pub fn from_source(source: &'src str) -> Parser<'src> {
+        Parser::from_lexer(Lexer::new(source))
+    }
+
+    pub fn from_lexer(mut lexer: Lexer<'src>) -> Parser<'src> {
+        // Lexical errors are buffered as tokens and only reported once the parser reaches them
+        lexer.recover = true;
+
+        Parser {
+            lexer,
+            lookahead: VecDeque::new(),
+            ast: Ast::new(),
+        }
+    }
+
+    fn advance(&mut self) -> Result<Token, Diagnostic> {
+        self.peek_nth(0);
+        let tk = self.lookahead.pop_front().unwrap();
+
+        match tk.kind {
+            TokenKind::Error(diag) => Err(*diag),
+            _ => Ok(tk),
+        }
+    }
+
+    fn peek(&mut self) -> &Token {
+        self.peek_nth(0)
+    }
+
+    /// Token `k` positions ahead of the current one, every token is only scanned once
+    pub fn peek_nth(&mut self, k: usize) -> &Token {
+        while self.lookahead.len() <= k {
+            let tk = self.lexer.next().expect("Lexer in recovery mode cannot fail");
+            self.lookahead.push_back(tk);
+        }
+        &self.lookahead[k]
+    }
+
+    fn expect(&mut self, kind: TokenKind, err: Error) -> Result<Token, Diagnostic> {
+        let tk = self.advance()?;
+        if tk.kind != kind {
+            let diag = Diagnostic::error(err, tk.span)
+                .with_message(format!("expected `{}`, found `{}`", kind, tk.kind));
+            return Err(diag);
+        }
+        Ok(tk)
+    }
+
+    /// Parse the whole source into a `Program`. Top-level functions are collected on the
+    /// side, every other top-level statement is part of the body of `main`
+    pub fn parse(&mut self) -> Result<NodeId, Diagnostic> {
+        let start = self.peek().span;
+        let mut functions = Vec::new();
+        let (stmts, value) = self.parse_block_body(&TokenKind::EndOfFile, Some(&mut functions))?;
+        let end = self.expect(TokenKind::EndOfFile, Error::UnexpectedToken)?;
+        let span = start.to(end.span);
+
+        let main = self.ast.add(Node::Block(BlockExpr { stmts, value }), span);
+        check_functions(&self.ast, &functions, main)?;
+
+        let node = Node::Program(Program {
+            functions,
+            main,
+        });
+        Ok(self.ast.add(node, span))
+    }
+
+    /// Comma separated items up to `close`, allowing a trailing comma. Returns the items and
+    /// the closing token
+    fn parse_list<T>(
+        &mut self,
+        open: Span,
+        close: TokenKind,
+        mut item: impl FnMut(&mut Self) -> Result<T, Diagnostic>,
+    ) -> Result<(Vec<T>, Token), Diagnostic> {
+        let mut items = Vec::new();
+
+        loop {
+            if self.peek().kind == close {
+                let tk = self.advance()?;
+                return Ok((items, tk));
+            }
+
+            items.push(item(self)?);
+
+            let next = self.peek();
+            if next.kind == TokenKind::Comma {
+                self.advance()?;
+            }
+            else if next.kind != close {
+                let diag = Diagnostic::error(Error::UnexpectedToken, next.span)
+                    .with_message(format!("expected `,` or `{}`, found `{}`", close, next.kind))
+                    .with_label(open, "list starts here");
+                return Err(diag);
+            }
+        }
+    }
+
+    fn expect_identifier(&mut self) -> Result<(Symbol, Span), Diagnostic> {
+        let tk = self.advance()?;
+        match tk.kind {
+            TokenKind::Identifier(name) => Ok((name, tk.span)),
+            _ => {
+                let diag = Diagnostic::error(Error::ExpectedIdentifier, tk.span)
+                    .with_message(format!("expected identifier, found `{}`", tk.kind));
+                Err(diag)
+            }
+        }
+    }
+
+    /// Statements up to `end`, a trailing expression without `;` becomes the value of the block.
+    /// Function declarations are only accepted where `functions` is given
+    fn parse_block_body(
+        &mut self,
+        end: &TokenKind,
+        mut functions: Option<&mut Vec<NodeId>>,
+    ) -> Result<(Vec<NodeId>, Option<NodeId>), Diagnostic> {
+        use TokenKind as T;
+
+        let mut stmts = Vec::new();
+
+        loop {
+            let kind = self.peek().kind.clone();
+
+            if &kind == end || kind == T::EndOfFile {
+                return Ok((stmts, None));
+            }
+
+            match kind {
+                T::Semicolon => {
+                    self.advance()?;
+                    continue;
+                }
+                T::Let => {
+                    stmts.push(self.parse_let()?);
+                    continue;
+                }
+                T::Fn if matches!(self.peek_nth(1).kind, T::Identifier(_)) => {
+                    let decl = self.parse_fn()?;
+                    match functions.as_mut() {
+                        Some(functions) => functions.push(decl),
+                        None => {
+                            let diag = Diagnostic::error(Error::NestedFunction, self.ast.span(decl))
+                                .with_note("functions can only be declared at the top level");
+                            return Err(diag);
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            // Like in Rust, a statement starting with a block ends with it
+            let block_like = matches!(kind, T::If | T::While | T::CurlyOpen);
+            let expr = if block_like {
+                self.parse_block_like()?
+            } else {
+                self.parse_assignment()?
+            };
+
+            let next = self.peek();
+            if next.kind == T::Semicolon {
+                let semi = self.advance()?;
+                stmts.push(self.expr_stmt(expr, semi.span));
+            }
+            else if &next.kind == end || next.kind == T::EndOfFile {
+                return Ok((stmts, Some(expr)));
+            }
+            else if block_like {
+                let span = self.ast.span(expr);
+                stmts.push(self.expr_stmt(expr, span));
+            }
+            else {
+                let diag = Diagnostic::error(Error::MissingSemicolon, next.span)
+                    .with_message(format!("expected `;`, found `{}`", next.kind))
+                    .with_label(self.ast.span(expr), "after this expression");
+                return Err(diag);
+            }
+        }
+    }
+
+    /// Expression, or an assignment when followed by `=`. Assignments are only allowed as statements
+    fn parse_assignment(&mut self) -> Result<NodeId, Diagnostic> {
+        let target = self.parse_expression()?;
+
+        if self.peek().kind != TokenKind::Assign {
+            return Ok(target);
+        }
+
+        let is_place = matches!(
+            &self.ast[target],
+            Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(_), .. } }) | Node::Index(_)
+        );
+        if !is_place {
+            let diag = Diagnostic::error(Error::InvalidAssignTarget, self.ast.span(target))
+                .with_message("invalid left-hand side of assignment")
+                .with_note("only variables and tuple elements can be assigned to");
+            return Err(diag);
+        }
+
+        self.advance()?;
+        let value = self.parse_expression()?;
+        let span = self.ast.span(target).to(self.ast.span(value));
+
+        Ok(self.ast.add(Node::Assign(AssignExpr { target, value }), span))
+    }
+
+    fn expr_stmt(&mut self, expr: NodeId, end: Span) -> NodeId {
+        let span = self.ast.span(expr).to(end);
+        self.ast.add(Node::ExprStmt(ExprStmt { expr }), span)
+    }
+
+    fn parse_let(&mut self) -> Result<NodeId, Diagnostic> {
+        let kw = self.expect(TokenKind::Let, Error::UnexpectedToken)?;
+        let (name, name_span) = self.expect_identifier()?;
+
+        let ty = if self.peek().kind == TokenKind::Colon {
+            self.advance()?;
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+
+        self.expect(TokenKind::Assign, Error::UnexpectedToken)?;
+        let init = self.parse_expression()?;
+        let semi = self.expect(TokenKind::Semicolon, Error::MissingSemicolon)?;
+
+        let node = Node::Let(LetStmt {
+            name,
+            name_span,
+            ty,
+            init,
+        });
+        Ok(self.ast.add(node, kw.span.to(semi.span)))
+    }
+
+    fn parse_type(&mut self) -> Result<NodeId, Diagnostic> {
+        let tk = self.advance()?;
+
+        match tk.kind {
+            TokenKind::Identifier(name) => Ok(self.ast.add(Node::TypeName(TypeName { name }), tk.span)),
+            TokenKind::Question => Ok(self.ast.add(Node::TypeName(TypeName { name: Symbol::intern("?") }), tk.span)),
+
+            TokenKind::ParenOpen => {
+                let (elems, is_tuple, close) = self.parse_parenthesized(tk.span, |p| p.parse_type())?;
+                let span = tk.span.to(close.span);
+                if !is_tuple {
+                    self.ast.set_span(elems[0], span);
+                    return Ok(elems[0]);
+                }
+                Ok(self.ast.add(Node::TupleType(TupleType { elems }), span))
+            }
+
+            TokenKind::Fn => {
+                let open = self.expect(TokenKind::ParenOpen, Error::UnexpectedToken)?;
+                let (params, close) = self.parse_list(open.span, TokenKind::ParenClose, |p| p.parse_type())?;
+
+                let (ret, end) = if self.peek().kind == TokenKind::Arrow {
+                    self.advance()?;
+                    let ret = self.parse_type()?;
+                    (Some(ret), self.ast.span(ret))
+                } else {
+                    (None, close.span)
+                };
+
+                Ok(self.ast.add(Node::FnType(FnType { params, ret }), tk.span.to(end)))
+            }
+
+            _ => {
+                let diag = Diagnostic::error(Error::ExpectedType, tk.span)
+                    .with_message(format!("expected type, found `{}`", tk.kind));
+                Err(diag)
+            }
+        }
+    }
+
+    /// `(name: type, ..)` followed by an optional `-> type`, shared by functions and lambdas.
+    /// Parameter types can be left out, the type checker decides whether that is fine
+    fn parse_signature(&mut self) -> Result<(Vec<Param>, Option<NodeId>), Diagnostic> {
+        let open = self.expect(TokenKind::ParenOpen, Error::UnexpectedToken)?;
+
+        let (params, _) = self.parse_list(open.span, TokenKind::ParenClose, |p| {
+            let (name, name_span) = p.expect_identifier()?;
+            let ty = if p.peek().kind != TokenKind::Colon {
+                None
+            } else {
+                p.expect(TokenKind::Colon, Error::UnexpectedToken)?;
+                Some(p.parse_type()?)
+            };
+            Ok(Param { name, name_span, ty })
+        })?;
+
+        for (i, param) in params.iter().enumerate() {
+            if let Some(first) = params[..i].iter().find(|p| p.name == param.name) {
+                let diag = Diagnostic::error(Error::DuplicateParameter, param.name_span)
+                    .with_message(format!("parameter `{}` is declared twice", param.name))
+                    .with_label(first.name_span, "first declared here");
+                return Err(diag);
+            }
+        }
+
+        let ret = if self.peek().kind == TokenKind::Arrow {
+            self.advance()?;
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+
+        Ok((params, ret))
+    }
+
+    fn parse_fn(&mut self) -> Result<NodeId, Diagnostic> {
+        let kw = self.expect(TokenKind::Fn, Error::UnexpectedToken)?;
+        let (name, name_span) = self.expect_identifier()?;
+
+        let mut generics = Vec::new();
+        if self.peek().kind == TokenKind::Lt {
+            let open = self.advance()?;
+            let (names, _) = self.parse_list(open.span, TokenKind::Gt, |p| p.expect_identifier())?;
+            for (i, (generic, span)) in names.iter().enumerate() {
+                if let Some((_, first)) = names[..i].iter().find(|(g, _)| g == generic) {
+                    let diag = Diagnostic::error(Error::DuplicateParameter, *span)
+                        .with_message(format!("type parameter `{}` is declared twice", generic))
+                        .with_label(*first, "first declared here");
+                    return Err(diag);
+                }
+                generics.push(*generic);
+            }
+        }
+
+        let (params, ret) = self.parse_signature()?;
+        let body = self.parse_block()?;
+        let span = kw.span.to(self.ast.span(body));
+
+        let node = Node::FnDecl(FnDecl {
+            name,
+            name_span,
+            generics,
+            params,
+            ret,
+            body,
+        });
+        Ok(self.ast.add(node, span))
+    }
+
+    fn parse_block_like(&mut self) -> Result<NodeId, Diagnostic> {
+        match self.peek().kind {
+            TokenKind::If => self.parse_if(),
+            TokenKind::While => self.parse_while(),
+            _ => self.parse_block(),
+        }
+    }
+
+    fn parse_block(&mut self) -> Result<NodeId, Diagnostic> {
+        let open = self.expect(TokenKind::CurlyOpen, Error::UnexpectedToken)?;
+        let (stmts, value) = self.parse_block_body(&TokenKind::CurlyClose, None)?;
+        let close = self.expect(TokenKind::CurlyClose, Error::UnclosedBrace)
+            .map_err(|diag| diag.with_label(open.span, "unclosed brace"))?;
+
+        let node = Node::Block(BlockExpr { stmts, value });
+        Ok(self.ast.add(node, open.span.to(close.span)))
+    }
+
+    fn parse_while(&mut self) -> Result<NodeId, Diagnostic> {
+        let kw = self.expect(TokenKind::While, Error::UnexpectedToken)?;
+        let cond = self.parse_expression()?;
+        let body = self.parse_block()?;
+        let span = kw.span.to(self.ast.span(body));
+
+        Ok(self.ast.add(Node::While(WhileExpr { cond, body }), span))
+    }
+
+    fn parse_if(&mut self) -> Result<NodeId, Diagnostic> {
+        let kw = self.expect(TokenKind::If, Error::UnexpectedToken)?;
+        let cond = self.parse_expression()?;
+        let then = self.parse_block()?;
+
+        let otherwise = if self.peek().kind == TokenKind::Else {
+            self.advance()?;
+            match self.peek().kind {
+                TokenKind::If => Some(self.parse_if()?),
+                _ => Some(self.parse_block()?),
+            }
+        } else {
+            None
+        };
+
+        let end = self.ast.span(otherwise.unwrap_or(then));
+        let node = Node::If(IfExpr {
+            cond,
+            then,
+            otherwise,
+        });
+        Ok(self.ast.add(node, kw.span.to(end)))
+    }
+
+    pub fn parse_expression(&mut self) -> Result<NodeId, Diagnostic> {
+        self.parse_binary(1)
+    }
+
+    /// Precedence climbing, only consumes operators that bind at least as tight as `min_prec`
+    fn parse_binary(&mut self, min_prec: u8) -> Result<NodeId, Diagnostic> {
+        let mut left = self.parse_unary()?;
+        let mut chained: Option<(u8, Span)> = None;
+
+        loop {
+            let (prec, assoc) = match infix_binding(&self.peek().kind) {
+                Some((prec, assoc)) if prec >= min_prec => (prec, assoc),
+                _ => break,
+            };
+
+            if let Some((chained_prec, chained_span)) = chained {
+                if chained_prec == prec {
+                    let diag = Diagnostic::error(Error::NonAssociativeOperator, self.peek().span)
+                        .with_label(chained_span, "previous operator")
+                        .with_suggestion("split the comparison with `&` or add parentheses");
+                    return Err(diag);
+                }
+            }
+
+            let op = self.advance()?;
+            let right = self.parse_binary(prec + 1)?;
+            let span = self.ast.span(left).to(self.ast.span(right));
+
+            left = self.ast.add(Node::Binary(BinaryExpr {
+                operator: op.kind,
+                left,
+                right,
+            }), span);
+
+            chained = match assoc {
+                Assoc::Left => None,
+                Assoc::None => Some((prec, op.span)),
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<NodeId, Diagnostic> {
+        if !is_prefix_operator(&self.peek().kind) {
+            return self.parse_postfix();
+        }
+
+        let op = self.advance()?;
+        let operand = self.parse_binary(PREFIX_PRECEDENCE)?;
+        let span = op.span.to(self.ast.span(operand));
+
+        let node = Node::Unary(UnaryExpr {
+            operator: op.kind,
+            operand,
+        });
+        Ok(self.ast.add(node, span))
+    }
+
+    /// After an opening `(`, `()` and `(a, ..)` are tuples while `(a)` only groups `a`. Returns
+    /// the items, whether they form a tuple and the closing token
+    fn parse_parenthesized(
+        &mut self,
+        open: Span,
+        mut item: impl FnMut(&mut Self) -> Result<NodeId, Diagnostic>,
+    ) -> Result<(Vec<NodeId>, bool, Token), Diagnostic> {
+        if self.peek().kind == TokenKind::ParenClose {
+            let close = self.advance()?;
+            return Ok((Vec::new(), true, close));
+        }
+
+        let first = item(self)?;
+
+        if self.peek().kind == TokenKind::Comma {
+            self.advance()?;
+            let (mut items, close) = self.parse_list(open, TokenKind::ParenClose, item)?;
+            items.insert(0, first);
+            return Ok((items, true, close));
+        }
+
+        let close = self.expect(TokenKind::ParenClose, Error::UnclosedParen)
+            .map_err(|diag| diag.with_label(open, "unclosed parenthesis"))?;
+        Ok((vec![first], false, close))
+    }
+
+    /// Calls and tuple indexing, they bind tighter than prefix operators so `-f(x)` is `-(f(x))`
+    fn parse_postfix(&mut self) -> Result<NodeId, Diagnostic> {
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            match self.peek().kind {
+                TokenKind::ParenOpen => {
+                    let open = self.advance()?;
+                    let (args, close) = self.parse_list(open.span, TokenKind::ParenClose, |p| p.parse_expression())?;
+                    let span = self.ast.span(expr).to(close.span);
+                    expr = self.ast.add(Node::Call(CallExpr { callee: expr, args }), span);
+                }
+
+                TokenKind::SquareOpen => {
+                    let open = self.advance()?;
+                    let index = self.parse_expression()?;
+                    let close = self.expect(TokenKind::SquareClose, Error::UnexpectedToken)
+                        .map_err(|diag| diag.with_label(open.span, "unclosed bracket"))?;
+                    let span = self.ast.span(expr).to(close.span);
+                    expr = self.ast.add(Node::Index(IndexExpr { tuple: expr, index }), span);
+                }
+
+                // `t.0` is sugar for `t[0]`
+                TokenKind::Dot => {
+                    self.advance()?;
+                    let tk = self.advance()?;
+                    if !matches!(tk.kind, TokenKind::Integer(_)) {
+                        let diag = Diagnostic::error(Error::ExpectedTupleIndex, tk.span)
+                            .with_message(format!("expected tuple index, found `{}`", tk.kind));
+                        return Err(diag);
+                    }
+
+                    let span = self.ast.span(expr).to(tk.span);
+                    let index = self.ast.add(Node::Primary(PrimaryExpr { value: tk.clone() }), tk.span);
+                    expr = self.ast.add(Node::Index(IndexExpr { tuple: expr, index }), span);
+                }
+
+                _ => break,
+            }
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<NodeId, Diagnostic> {
+        use TokenKind as T;
+
+        if matches!(self.peek().kind, T::If | T::While | T::CurlyOpen) {
+            return self.parse_block_like();
+        }
+
+        let tk = self.advance()?;
+
+        match tk.kind {
+            T::Integer(_) | T::Identifier(_) | T::String(_) | T::True | T::False => {
+                let span = tk.span;
+                Ok(self.ast.add(Node::Primary(PrimaryExpr { value: tk }), span))
+            }
+
+            T::Fn => {
+                let (params, ret) = self.parse_signature()?;
+                let body = self.parse_block()?;
+                let span = tk.span.to(self.ast.span(body));
+
+                let node = Node::Lambda(LambdaExpr {
+                    params,
+                    ret,
+                    body,
+                });
+                Ok(self.ast.add(node, span))
+            }
+
+            T::Break => Ok(self.ast.add(Node::Break, tk.span)),
+            T::Continue => Ok(self.ast.add(Node::Continue, tk.span)),
+
+            T::Len => {
+                let open = self.expect(T::ParenOpen, Error::UnexpectedToken)?;
+                let tuple = self.parse_expression()?;
+                let close = self.expect(T::ParenClose, Error::UnclosedParen)
+                    .map_err(|diag| diag.with_label(open.span, "unclosed parenthesis"))?;
+                Ok(self.ast.add(Node::Len(LenExpr { tuple }), tk.span.to(close.span)))
+            }
+
+            T::ParenOpen => {
+                let (elems, is_tuple, close) = self.parse_parenthesized(tk.span, |p| p.parse_expression())?;
+                let span = tk.span.to(close.span);
+                if !is_tuple {
+                    self.ast.set_span(elems[0], span);
+                    return Ok(elems[0]);
+                }
+                Ok(self.ast.add(Node::Tuple(TupleExpr { elems }), span))
+            }
+
+            _ => {
+                let diag = Diagnostic::error(Error::ExpectedExpression, tk.span)
+                    .with_message(format!("expected expression, found `{}`", tk.kind));
+                Err(diag)
+            }
+        }
+    }
+}
+
+/// How a name in scope was introduced
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Binding {
+    Let,
+    Param,
+}
+
+/// Rejects duplicate functions, calls to top-level functions with the wrong number of
+/// arguments, assignments to anything but `let` variables and `break`/`continue` outside
+/// of loops. Names are resolved lexically, so locals shadow functions.
+fn check_functions(ast: &Ast, functions: &[NodeId], main: NodeId) -> Result<(), Diagnostic> {
+    let mut decls: HashMap<Symbol, &FnDecl> = HashMap::new();
+
+    for id in functions {
+        let decl = match &ast[*id] {
+            Node::FnDecl(decl) => decl,
+            _ => unreachable!("Top-level item is not a function"),
+        };
+
+        if decl.name.as_str() == "main" {
+            let diag = Diagnostic::error(Error::DuplicateFunction, decl.name_span)
+                .with_message("function `main` is already defined")
+                .with_note("`main` is made of the statements outside of functions");
+            return Err(diag);
+        }
+
+        if let Some(first) = decls.get(&decl.name) {
+            let diag = Diagnostic::error(Error::DuplicateFunction, decl.name_span)
+                .with_message(format!("function `{}` is defined twice", decl.name))
+                .with_label(first.name_span, "first defined here");
+            return Err(diag);
+        }
+
+        decls.insert(decl.name, decl);
+    }
+
+    let mut checker = Checker {
+        ast,
+        decls,
+        locals: Vec::new(),
+        loops: 0,
+    };
+
+    for id in functions {
+        checker.check(*id)?;
+    }
+    checker.check(main)
+}
+
+struct Checker<'a> {
+    ast: &'a Ast,
+    decls: HashMap<Symbol, &'a FnDecl>,
+    locals: Vec<(Symbol, Binding)>,
+    /// Number of loops around the current node
+    loops: usize,
+}
+
+impl Checker<'_> {
+    fn lookup(&self, name: Symbol) -> Option<Binding> {
+        self.locals.iter().rev().find(|(n, _)| *n == name).map(|(_, b)| *b)
+    }
+
+    fn check(&mut self, id: NodeId) -> Result<(), Diagnostic> {
+        let ast = self.ast;
+        let scope = self.locals.len();
+
+        match &ast[id] {
+            Node::FnDecl(decl) => {
+                // Loops don't extend into function bodies
+                let loops = std::mem::replace(&mut self.loops, 0);
+                self.locals.extend(decl.params.iter().map(|p| (p.name, Binding::Param)));
+                self.check(decl.body)?;
+                self.loops = loops;
+            }
+
+            Node::Lambda(lambda) => {
+                let loops = std::mem::replace(&mut self.loops, 0);
+                self.locals.extend(lambda.params.iter().map(|p| (p.name, Binding::Param)));
+                self.check(lambda.body)?;
+                self.loops = loops;
+            }
+
+            Node::Let(l) => {
+                self.check(l.init)?;
+                // Stays in scope until the enclosing block ends
+                self.locals.push((l.name, Binding::Let));
+                return Ok(());
+            }
+
+            Node::While(w) => {
+                self.check(w.cond)?;
+                self.loops += 1;
+                self.check(w.body)?;
+                self.loops -= 1;
+            }
+
+            Node::Break | Node::Continue => {
+                if self.loops == 0 {
+                    let keyword = if matches!(ast[id], Node::Break) { "break" } else { "continue" };
+                    let diag = Diagnostic::error(Error::BreakOutsideLoop, ast.span(id))
+                        .with_message(format!("`{}` outside of a loop", keyword));
+                    return Err(diag);
+                }
+            }
+
+            Node::Assign(assign) => {
+                self.check(assign.value)?;
+
+                let name = match &ast[assign.target] {
+                    Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => *name,
+                    Node::Index(_) => {
+                        self.check(assign.target)?;
+                        return Ok(());
+                    }
+                    _ => unreachable!("Parser only accepts variables and tuple elements as assignment targets"),
+                };
+
+                let span = ast.span(assign.target);
+                match self.lookup(name) {
+                    Some(Binding::Let) => {}
+                    Some(Binding::Param) => {
+                        let diag = Diagnostic::error(Error::InvalidAssignment, span)
+                            .with_message(format!("cannot assign to parameter `{}`", name))
+                            .with_suggestion(format!("make a mutable copy with `let {} = {};`", name, name));
+                        return Err(diag);
+                    }
+                    None => {
+                        let diag = Diagnostic::error(Error::InvalidAssignment, span)
+                            .with_message(format!("cannot assign to `{}`", name))
+                            .with_note("only variables declared with `let` can be assigned to");
+                        return Err(diag);
+                    }
+                }
+            }
+
+            Node::Call(call) => {
+                let callee = match &ast[call.callee] {
+                    Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => {
+                        match self.lookup(*name) {
+                            Some(_) => None,
+                            None => self.decls.get(name).copied(),
+                        }
+                    }
+                    _ => None,
+                };
+
+                if let Some(decl) = callee {
+                    if decl.params.len() != call.args.len() {
+                        let plural = if decl.params.len() == 1 { "" } else { "s" };
+                        let diag = Diagnostic::error(Error::ArityMismatch, ast.span(id))
+                            .with_message(format!(
+                                "function `{}` takes {} argument{} but {} {} supplied",
+                                decl.name, decl.params.len(), plural, call.args.len(),
+                                if call.args.len() == 1 { "was" } else { "were" },
+                            ))
+                            .with_label(decl.name_span, "defined here");
+                        return Err(diag);
+                    }
+                }
+
+                for child in ast[id].children() {
+                    self.check(child)?;
+                }
+            }
+
+            node => {
+                for child in node.children() {
+                    self.check(child)?;
+                }
+            }
+        }
+
+        self.locals.truncate(scope);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tree of the expression in `source`, as dumped by the AST
+    fn parse(source: &str) -> String {
+        let mut parser = Parser::from_source(source);
+        let expr = parser.parse_expression().expect("Parse error");
+        parser.ast.dump(expr)
+    }
+
+    fn parse_error(source: &str) -> Diagnostic {
+        Parser::from_source(source).parse_expression().expect_err("Parsed an invalid expression")
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(parse("69 + 7 / (5 + 420)"), "(+ 69 (/ 7 (+ 5 420)))");
+        assert_eq!(parse("1 * 2 + 3 % 4"), "(+ (* 1 2) (% 3 4))");
+    }
+
+    #[test]
+    fn same_precedence_associates_to_the_left() {
+        assert_eq!(parse("a - b - c"), "(- (- a b) c)");
+        assert_eq!(parse("a / b * c"), "(* (/ a b) c)");
+    }
+
+    #[test]
+    fn shifts_bind_looser_than_addition() {
+        assert_eq!(parse("1 << 2 + 3"), "(<< 1 (+ 2 3))");
+        assert_eq!(parse("1 + 2 >> 3"), "(>> (+ 1 2) 3)");
+    }
+
+    #[test]
+    fn prefix_operators_bind_tighter_than_infix_ones() {
+        assert_eq!(parse("-a * b"), "(* (- a) b)");
+        assert_eq!(parse("~a << 1"), "(<< (~ a) 1)");
+        assert_eq!(parse("a - -b"), "(- a (- b))");
+        assert_eq!(parse("-~a"), "(- (~ a))");
+    }
+
+    #[test]
+    fn chained_comparisons_are_rejected() {
+        let diag = parse_error("a < b < c");
+        assert_eq!(diag.code(), "E0103");
+        assert_eq!(diag.span, Span::new(6, 7));
+        assert_eq!(diag.labels[0].span, Span::new(2, 3));
+
+        assert_eq!(parse_error("a == b != c").code(), "E0103");
+
+        // Comparisons of different precedence, and parenthesized ones, can be mixed
+        assert_eq!(parse("a < b == c"), "(== (< a b) c)");
+        assert_eq!(parse("(a < b) < c"), "(< (< a b) c)");
+    }
+
+    #[test]
+    fn unclosed_paren_points_at_the_open_one() {
+        let diag = parse_error("(1 + 2");
+        assert_eq!(diag.code(), "E0102");
+        assert_eq!(diag.labels[0].span, Span::new(0, 1));
+
+        assert_eq!(parse_error("7 * (1 + (2)").code(), "E0102");
+    }
+}
@@ -0,0 +1,370 @@
+use crate::ast::{Ast, BlockExpr, LetStmt, Node, NodeId, PrimaryExpr};
+use crate::diagnostic::Diagnostic;
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::types::{Type, TypeTable};
+use crate::uniquify::Renames;
+use crate::Error;
+use std::collections::HashSet;
+
+/// Operands have to be atoms after `remove_complex_operands`, literals or variables
+pub fn is_atom(ast: &Ast, id: NodeId) -> bool {
+    matches!(ast[id], Node::Primary(_))
+}
+
+/// Puts the program in monadic normal form, where the operands of operators, calls,
+/// tuple reads and writes and tags are atoms. Complex operands are evaluated first into
+/// a temporary bound by `let`:
+///
+/// ```text
+/// 69 + 7 / (5 + 420)    =>  { let tmp.0 = 5 + 420; let tmp.1 = 7 / tmp.0; 69 + tmp.1 }
+/// ```
+///
+/// Atoms are left where they are and the statements of a block used as an operand are
+/// moved in front of it, so only values that need a name get one. Calls to a `fun-ref`
+/// stay direct. Names are unique by now, so moving a `let` out of its block cannot
+/// shadow anything. A variable that is assigned somewhere is copied into a temporary
+/// when an operand after it is complex, as that operand could change it before the
+/// operator runs.
+pub fn remove_complex_operands(ast: &mut Ast, program: NodeId, types: &mut TypeTable, renames: &mut Renames) {
+    let mut assigned = HashSet::new();
+    for id in ast.post_order(program) {
+        if let Node::Assign(assign) = &ast[id] {
+            if let Some(name) = variable(ast, assign.target) {
+                assigned.insert(name);
+            }
+        }
+    }
+
+    let mut flattener = Flattener { ast, types, renames, assigned };
+    flattener.exp(program);
+}
+
+/// Checks that `remove_complex_operands` left only atoms as operands
+pub fn check_monadic(ast: &Ast, program: NodeId) -> Result<(), Diagnostic> {
+    for id in ast.post_order(program) {
+        let (what, operands) = match &ast[id] {
+            Node::Unary(u) => (format!("`{}`", u.operator), vec![u.operand]),
+            Node::Binary(b) => (format!("`{}`", b.operator), vec![b.left, b.right]),
+            Node::Index(i) => ("a tuple index".to_string(), vec![i.tuple, i.index]),
+            Node::Len(l) => ("`len`".to_string(), vec![l.tuple]),
+            Node::Inject(i) => ("an injection".to_string(), vec![i.expr]),
+            Node::Project(p) => ("a projection".to_string(), vec![p.expr]),
+            Node::Is(i) => ("a tag test".to_string(), vec![i.expr]),
+            Node::Proxy(p) => ("a proxy".to_string(), vec![p.tuple, p.reads, p.writes]),
+            Node::Assign(a) => match &ast[a.target] {
+                Node::Index(_) => ("a tuple write".to_string(), vec![a.value]),
+                _ => continue,
+            },
+            Node::Call(c) => {
+                let callee = match ast[c.callee] {
+                    Node::FunRef(_) => None,
+                    _ => Some(c.callee),
+                };
+                ("a call".to_string(), callee.into_iter().chain(c.args.iter().copied()).collect())
+            }
+            _ => continue,
+        };
+
+        if let Some(operand) = operands.iter().find(|o| !is_atom(ast, **o)) {
+            let diag = Diagnostic::error(Error::ComplexOperand, ast.span(*operand))
+                .with_message(format!("operand of {} is not atomic", what))
+                .with_label(ast.span(id), "in this expression")
+                .with_note("operands are literals or variables after removing complex operands");
+            return Err(diag);
+        }
+    }
+    Ok(())
+}
+
+fn variable(ast: &Ast, id: NodeId) -> Option<Symbol> {
+    match &ast[id] {
+        Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => Some(*name),
+        _ => None,
+    }
+}
+
+struct Flattener<'a> {
+    ast: &'a mut Ast,
+    types: &'a mut TypeTable,
+    renames: &'a mut Renames,
+    /// Variables assigned somewhere in the program
+    assigned: HashSet<Symbol>,
+}
+
+impl Flattener<'_> {
+    /// Bind the value of `expr` to a temporary and return a use of it
+    fn bind(&mut self, expr: NodeId, bindings: &mut Vec<NodeId>) -> NodeId {
+        let span = self.ast.span(expr);
+        let ty = self.types.get(expr).clone();
+        let temp = self.renames.fresh("tmp");
+
+        let bind = self.ast.add(Node::Let(LetStmt {
+            name: temp,
+            name_span: span,
+            ty: None,
+            init: expr,
+        }), span);
+        self.types.insert(bind, Type::Void);
+        bindings.push(bind);
+
+        let var = self.ast.add_variable(temp, span);
+        self.types.insert(var, ty);
+        var
+    }
+
+    /// Atom standing for `id`, with what has to be evaluated first pushed to `bindings`
+    fn atom(&mut self, id: NodeId, bindings: &mut Vec<NodeId>) -> NodeId {
+        match self.ast[id].clone() {
+            Node::Primary(_) => id,
+
+            // The statements run before the operand, the value is the operand
+            Node::Block(BlockExpr { stmts, value: Some(value) }) => {
+                for stmt in stmts {
+                    self.stmt(stmt, bindings);
+                }
+                self.ast.replace(id, Node::Break).expect("Stale block");
+                self.ast.free(id).expect("Stale block");
+                self.atom(value, bindings)
+            }
+
+            _ => {
+                self.flatten(id, bindings);
+                self.bind(id, bindings)
+            }
+        }
+    }
+
+    /// Flatten the statement `id` and push it to `stmts`, after its temporaries
+    fn stmt(&mut self, id: NodeId, stmts: &mut Vec<NodeId>) {
+        match self.ast[id].clone() {
+            Node::Let(l) => self.flatten(l.init, stmts),
+            Node::ExprStmt(e) => self.flatten(e.expr, stmts),
+            _ => self.exp(id),
+        }
+        stmts.push(id);
+    }
+
+    /// Atoms for `operands`, evaluated in order before the expressions in `after`
+    fn atoms(&mut self, operands: &[NodeId], after: &[NodeId], bindings: &mut Vec<NodeId>) -> Vec<NodeId> {
+        let mut atoms = Vec::new();
+        for (i, operand) in operands.iter().enumerate() {
+            let later_complex = operands[i + 1..].iter().chain(after).any(|o| !is_atom(self.ast, *o));
+            let atom = match variable(self.ast, *operand) {
+                Some(name) if later_complex && self.assigned.contains(&name) => self.bind(*operand, bindings),
+                _ => self.atom(*operand, bindings),
+            };
+            atoms.push(atom);
+        }
+        atoms
+    }
+
+    /// Flatten the node behind `id` in place, where it is evaluated on its own. Temporaries
+    /// it needs are bound in a block around it
+    fn exp(&mut self, id: NodeId) {
+        let mut bindings = Vec::new();
+        self.flatten(id, &mut bindings);
+        if bindings.is_empty() {
+            return;
+        }
+
+        // The node moves behind its bindings, `id` becomes the block holding both
+        let span = self.ast.span(id);
+        let ty = self.types.get(id).clone();
+        let node = self.ast.replace(id, Node::Break).expect("Stale node");
+        let inner = self.ast.add(node, span);
+        self.types.insert(inner, ty);
+        self.ast.replace(id, Node::Block(BlockExpr { stmts: bindings, value: Some(inner) })).expect("Stale node");
+    }
+
+    /// Flatten the node behind `id` in place, its operands become atoms and what has to be
+    /// evaluated before it is pushed to `bindings`
+    fn flatten(&mut self, id: NodeId, bindings: &mut Vec<NodeId>) {
+        let node = match self.ast[id].clone() {
+            Node::Unary(mut u) => {
+                u.operand = self.atom(u.operand, bindings);
+                Node::Unary(u)
+            }
+            Node::Binary(mut b) => {
+                let atoms = self.atoms(&[b.left, b.right], &[], bindings);
+                b.left = atoms[0];
+                b.right = atoms[1];
+                Node::Binary(b)
+            }
+            Node::Index(mut i) => {
+                let atoms = self.atoms(&[i.tuple, i.index], &[], bindings);
+                i.tuple = atoms[0];
+                i.index = atoms[1];
+                Node::Index(i)
+            }
+            Node::Len(mut l) => {
+                l.tuple = self.atom(l.tuple, bindings);
+                Node::Len(l)
+            }
+            Node::Inject(mut i) => {
+                i.expr = self.atom(i.expr, bindings);
+                Node::Inject(i)
+            }
+            Node::Project(mut p) => {
+                p.expr = self.atom(p.expr, bindings);
+                Node::Project(p)
+            }
+            Node::Is(mut i) => {
+                i.expr = self.atom(i.expr, bindings);
+                Node::Is(i)
+            }
+            Node::Proxy(mut p) => {
+                let atoms = self.atoms(&[p.tuple, p.reads, p.writes], &[], bindings);
+                p.tuple = atoms[0];
+                p.reads = atoms[1];
+                p.writes = atoms[2];
+                Node::Proxy(p)
+            }
+            Node::Call(mut c) => {
+                // Calls to a known function stay direct
+                if !matches!(self.ast[c.callee], Node::FunRef(_)) {
+                    c.callee = self.atoms(&[c.callee], &c.args, bindings)[0];
+                }
+                c.args = self.atoms(&c.args, &[], bindings);
+                Node::Call(c)
+            }
+
+            // A write to a tuple takes atoms like an operator, the value is evaluated before
+            // the tuple and the index. A variable can be assigned any expression
+            Node::Assign(mut a) => {
+                match self.ast[a.target].clone() {
+                    Node::Index(mut target) => {
+                        let atoms = self.atoms(&[a.value, target.tuple, target.index], &[], bindings);
+                        a.value = atoms[0];
+                        target.tuple = atoms[1];
+                        target.index = atoms[2];
+                        self.ast.replace(a.target, Node::Index(target)).expect("Stale target");
+                    }
+                    _ => self.flatten(a.value, bindings),
+                }
+                Node::Assign(a)
+            }
+
+            Node::Lambda(_) | Node::Tuple(_) | Node::Cast(_) => {
+                unreachable!("Lambdas, tuples and casts are lowered before removing complex operands")
+            }
+
+            // The temporaries of a statement go right before it
+            Node::Block(mut b) => {
+                let mut stmts = Vec::new();
+                for stmt in &b.stmts {
+                    self.stmt(*stmt, &mut stmts);
+                }
+                if let Some(value) = b.value {
+                    self.flatten(value, &mut stmts);
+                }
+                b.stmts = stmts;
+                Node::Block(b)
+            }
+
+            // Complex expressions are fine where a value is expected
+            node => {
+                for child in node.children() {
+                    self.exp(child);
+                }
+                return;
+            }
+        };
+
+        self.ast.replace(id, node).expect("Stale node");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{
+        AssignExpr, BinaryExpr, CallExpr, IndexExpr, InjectExpr, IsExpr, LenExpr, ProjectExpr, ProxyExpr,
+        UnaryExpr,
+    };
+    use crate::parser::Parser;
+    use crate::source::Span;
+    use crate::types::{annotation, type_check};
+    use crate::uniquify::uniquify;
+
+    /// `check_monadic` has to reject the expression `build` makes around a complex operand,
+    /// blaming that operand. The other operands are variables
+    fn rejects(build: impl FnOnce(&mut Ast, NodeId, NodeId) -> Node) {
+        let mut ast = Ast::new();
+        let left = ast.add_integer(1, Span::new(10, 11));
+        let right = ast.add_integer(2, Span::new(14, 15));
+        let complex = ast.add(Node::Binary(BinaryExpr {
+            operator: TokenKind::Plus,
+            left,
+            right,
+        }), Span::new(10, 15));
+        let atom = ast.add_variable(Symbol::intern("x"), Span::new(0, 1));
+
+        let node = build(&mut ast, complex, atom);
+        let root = ast.add(node, Span::new(0, 20));
+        let diag = check_monadic(&ast, root).expect_err("Complex operand accepted");
+        assert_eq!(diag.kind, Error::ComplexOperand);
+        assert_eq!(diag.span, Span::new(10, 15));
+    }
+
+    fn int(ast: &mut Ast) -> NodeId {
+        annotation(ast, &Type::Int, Span::new(0, 1))
+    }
+
+    #[test]
+    fn rejects_complex_operator_operands() {
+        rejects(|_, complex, _| Node::Unary(UnaryExpr { operator: TokenKind::Minus, operand: complex }));
+        rejects(|_, complex, atom| Node::Binary(BinaryExpr { operator: TokenKind::Star, left: complex, right: atom }));
+        rejects(|_, complex, atom| Node::Binary(BinaryExpr { operator: TokenKind::Star, left: atom, right: complex }));
+    }
+
+    #[test]
+    fn rejects_complex_tuple_operands() {
+        rejects(|_, complex, atom| Node::Index(IndexExpr { tuple: complex, index: atom }));
+        rejects(|_, complex, atom| Node::Index(IndexExpr { tuple: atom, index: complex }));
+        rejects(|_, complex, _| Node::Len(LenExpr { tuple: complex }));
+        rejects(|ast, complex, atom| {
+            let index = ast.add_integer(0, Span::new(2, 3));
+            let target = ast.add(Node::Index(IndexExpr { tuple: atom, index }), Span::new(0, 4));
+            Node::Assign(AssignExpr { target, value: complex })
+        });
+    }
+
+    #[test]
+    fn rejects_complex_tag_operands() {
+        rejects(|ast, complex, _| Node::Inject(InjectExpr { expr: complex, ty: int(ast) }));
+        rejects(|ast, complex, _| Node::Project(ProjectExpr { expr: complex, ty: int(ast) }));
+        rejects(|ast, complex, _| Node::Is(IsExpr { expr: complex, ty: int(ast) }));
+    }
+
+    #[test]
+    fn rejects_complex_proxy_operands() {
+        rejects(|_, complex, atom| Node::Proxy(ProxyExpr { tuple: complex, reads: atom, writes: atom }));
+        rejects(|_, complex, atom| Node::Proxy(ProxyExpr { tuple: atom, reads: complex, writes: atom }));
+        rejects(|_, complex, atom| Node::Proxy(ProxyExpr { tuple: atom, reads: atom, writes: complex }));
+    }
+
+    #[test]
+    fn rejects_complex_call_operands() {
+        rejects(|_, complex, atom| Node::Call(CallExpr { callee: complex, args: vec![atom] }));
+        rejects(|_, complex, atom| Node::Call(CallExpr { callee: atom, args: vec![atom, complex] }));
+    }
+
+    #[test]
+    fn flattens_with_a_temporary_per_complex_operand() {
+        let mut parser = Parser::from_source("69 + 7 / (5 + 420)");
+        let root = parser.parse().expect("Parse error");
+        let mut ast = parser.ast;
+        let mut types = type_check(&ast, root).expect("Type error");
+        let mut renames = uniquify(&mut ast, root);
+
+        remove_complex_operands(&mut ast, root, &mut types, &mut renames);
+        check_monadic(&ast, root).expect("Operands left complex");
+
+        let temps = ast.post_order(root)
+            .into_iter()
+            .filter(|id| matches!(&ast[*id], Node::Let(l) if l.name.as_str().starts_with("tmp.")))
+            .count();
+        assert_eq!(temps, 2);
+    }
+}
@@ -0,0 +1,750 @@
+use crate::cir::{self, Atom, BinaryOp, Callee, Exp, Stmt, Tail, UnaryOp};
+use crate::dynamic::{any_tag, Trap, TRAP_EXIT_CODE};
+use crate::expose::{ARITY_SHIFT, PROXY_BIT};
+use crate::intern::Symbol;
+use crate::source::{SourceMap, Span};
+use crate::types::Type;
+use crate::x86::{Arg, Block, ByteReg, Cc, Function, Instr, Program, Reg, ARG_REGS};
+use std::collections::{HashMap, HashSet};
+
+/// Lowers the block IR into x86 instructions, variables staying pseudo-registers until
+/// registers are allocated. Every statement becomes a few instructions:
+///
+/// ```text
+/// x = a / b;    =>  movq a, %rax; cqto; idivq b; movq %rax, x
+/// x = a < b;    =>  cmpq b, a; setl %al; movzbq %al, x
+/// x = a << b;   =>  movq b, %rcx; movq a, x; salq %cl, x
+/// ```
+///
+/// Parameters are moved out of the argument registers at the start of a function and
+/// returns jump to its conclusion with the value in `rax`. `rax` and `r11` are scratch
+/// registers, `r15` points to the top of the root stack the collector is given.
+/// Proxies are allocated by the runtime, injections and tag tests are done inline on the
+/// tag in the low 3 bits. Projections and the accesses to tuples held in an `Any` check
+/// the tag, length and arity inline too. A failed check jumps to a stub at the end of the
+/// function, which prints the message of the `Trap` with the location from `map` and
+/// exits with `TRAP_EXIT_CODE`.
+///
+/// A tuple of a type some proxy has may be a proxy itself, so the accesses to it test the
+/// proxy bit of its header first. Reads and writes of a proxy call the runtime, which
+/// applies its functions, through as many proxies as there are. The length of a proxy
+/// is the one of its tuple of read functions:
+///
+/// Tuples held in an `Any` may be proxies too when the program proxies a tuple of `?`.
+///
+/// ```text
+/// x = t[0];    =>      movq t, %r11; movq 0(%r11), %rax; sarq $57, %rax; andq $1, %rax
+///                      cmpq $0, %rax; jne proxy.0; jmp direct.0
+///              proxy.0:  movq %r15, %rdi; movq %r11, %rsi; movq $0, %rdx; callq proxy_read
+///                        movq %rax, x; jmp after.0
+///              direct.0: movq 8(%r11), x; jmp after.0
+///              after.0:  ..
+/// ```
+pub fn select_instructions(program: &cir::Program, map: &SourceMap) -> Program {
+    let mut selector = Selector {
+        map,
+        strings: Vec::new(),
+        proxied: proxied_types(program),
+        types: HashMap::new(),
+        allocated: HashSet::new(),
+        label: Symbol::intern("start"),
+        blocks: Vec::new(),
+        traps: Vec::new(),
+        trap_count: 0,
+        split_count: 0,
+    };
+
+    let functions = program.functions.iter().map(|f| selector.function(f)).collect();
+    Program { functions, strings: selector.strings }
+}
+
+/// Types of the proxies the program creates, the only types a proxy can be seen at
+fn proxied_types(program: &cir::Program) -> Vec<Type> {
+    let mut proxied = Vec::new();
+    for f in &program.functions {
+        for (_, block) in &f.blocks {
+            for stmt in &block.stmts {
+                if let Stmt::Assign(name, Exp::Proxy(..)) = stmt {
+                    let (_, ty) = f.locals.iter().find(|(local, _)| local == name).expect("Variable without a type");
+                    proxied.push(ty.clone());
+                }
+            }
+            if let Tail::Return(Exp::Proxy(..)) = block.tail {
+                proxied.push(f.ret.clone());
+            }
+        }
+    }
+    proxied
+}
+
+fn rax() -> Arg {
+    Arg::Reg(Reg::Rax)
+}
+
+fn r11() -> Arg {
+    Arg::Reg(Reg::R11)
+}
+
+/// Move `src` to `dest` unless it is there already
+fn mov(src: Arg, dest: Arg, out: &mut Vec<Instr>) {
+    if src != dest {
+        out.push(Instr::Movq(src, dest));
+    }
+}
+
+/// Tuples, functions and strings are pointers, they keep their bits when tagged
+fn tagged_in_place(ty: &Type) -> bool {
+    matches!(ty, Type::Tuple(_) | Type::Fn(..) | Type::Str)
+}
+
+struct Selector<'a> {
+    map: &'a SourceMap<'a>,
+    strings: Vec<(cir::Label, Symbol)>,
+    proxied: Vec<Type>,
+    /// Types of the variables of the current function
+    types: HashMap<Symbol, Type>,
+    /// Variables of the current function holding a tuple it allocated, never a proxy
+    allocated: HashSet<Symbol>,
+    /// Block the instructions being selected go to, and the ones before it
+    label: cir::Label,
+    blocks: Vec<(cir::Label, Block)>,
+    /// Stubs the current function jumps to when a check fails
+    traps: Vec<(Trap, Span, cir::Label)>,
+    trap_count: usize,
+    split_count: usize,
+}
+
+impl Selector<'_> {
+    fn function(&mut self, f: &cir::Function) -> Function {
+        let vars: Vec<(Symbol, Type)> = f.params.iter().chain(&f.locals).cloned().collect();
+        self.types = vars.iter().cloned().collect();
+        self.allocated = f.blocks.iter()
+            .flat_map(|(_, block)| &block.stmts)
+            .filter_map(|stmt| match stmt {
+                Stmt::Assign(name, Exp::Allocate(..)) => Some(*name),
+                _ => None,
+            })
+            .collect();
+        self.traps.clear();
+
+        let mut function = Function { name: f.name, vars, blocks: Vec::new() };
+        let conclusion = function.conclusion();
+
+        for (i, (label, block)) in f.blocks.iter().enumerate() {
+            self.label = *label;
+            let mut instrs = Vec::new();
+            // Parameters after the sixth were pushed by the caller, above the return address
+            if i == 0 {
+                for (n, (param, _)) in f.params.iter().enumerate() {
+                    let src = match ARG_REGS.get(n) {
+                        Some(reg) => Arg::Reg(*reg),
+                        None => Arg::Deref(Reg::Rbp, 16 + 8 * (n - ARG_REGS.len()) as i64),
+                    };
+                    instrs.push(Instr::Movq(src, Arg::Var(*param)));
+                }
+            }
+
+            for stmt in &block.stmts {
+                self.stmt(stmt, &mut instrs);
+            }
+            self.tail(&block.tail, &f.ret, conclusion, &mut instrs);
+            self.blocks.push((self.label, Block { instrs }));
+        }
+
+        function.blocks = std::mem::take(&mut self.blocks);
+        for (trap, span, label) in self.traps.clone() {
+            function.blocks.push((label, self.trap_stub(trap, span)));
+        }
+        function
+    }
+
+    /// End the block being selected with the instructions in `out`, the ones that follow
+    /// go to the block `label`
+    fn start_block(&mut self, label: cir::Label, out: &mut Vec<Instr>) {
+        let instrs = std::mem::take(out);
+        self.blocks.push((self.label, Block { instrs }));
+        self.label = label;
+    }
+
+    /// Whether `atom` is a variable holding a tuple that may be a proxy
+    fn maybe_proxy(&self, atom: Atom) -> bool {
+        match atom {
+            Atom::Var(name) => !self.allocated.contains(&name) && self.proxied.contains(&self.types[&name]),
+            _ => false,
+        }
+    }
+
+    /// Whether a tuple held in an `Any` may be a proxy, which takes a proxy of a tuple of `?`
+    fn any_proxied(&self) -> bool {
+        self.proxied.iter().any(|ty| matches!(ty, Type::Tuple(elems) if elems.iter().all(|e| *e == Type::Any)))
+    }
+
+    /// Tests the proxy bit of the tuple `r11` points to, then runs `proxy` or `direct`.
+    /// Both go on in the same block, with `r11` still pointing to the tuple. Only `direct`
+    /// runs when the tuple cannot be `proxied`
+    fn dispatch<P, D>(&mut self, proxied: bool, out: &mut Vec<Instr>, proxy: P, direct: D)
+    where
+        P: FnOnce(&mut Self, &mut Vec<Instr>),
+        D: FnOnce(&mut Self, &mut Vec<Instr>),
+    {
+        if !proxied {
+            direct(self, out);
+            return;
+        }
+
+        let n = self.split_count;
+        self.split_count += 1;
+        let through = Symbol::intern(&format!("proxy.{}", n));
+        let tuple = Symbol::intern(&format!("direct.{}", n));
+        let after = Symbol::intern(&format!("after.{}", n));
+
+        out.push(Instr::Movq(Arg::Deref(Reg::R11, 0), rax()));
+        out.push(Instr::Sarq(Arg::Imm(PROXY_BIT.trailing_zeros() as i64), rax()));
+        out.push(Instr::Andq(Arg::Imm(1), rax()));
+        out.push(Instr::Cmpq(Arg::Imm(0), rax()));
+        out.push(Instr::JmpIf(Cc::Ne, through));
+        out.push(Instr::Jmp(tuple));
+
+        self.start_block(through, out);
+        proxy(self, out);
+        out.push(Instr::Jmp(after));
+        self.start_block(tuple, out);
+        direct(self, out);
+        out.push(Instr::Jmp(after));
+        self.start_block(after, out);
+    }
+
+    /// `rax` set to the length of the tuple `r11` points to, looking through a proxy when
+    /// `proxied` says there may be one
+    fn tuple_len(&mut self, proxied: bool, out: &mut Vec<Instr>) {
+        self.dispatch(proxied, out, |s, out| {
+            // The read functions come after the proxied tuple
+            out.push(Instr::Movq(Arg::Deref(Reg::R11, 16), rax()));
+            s.header_len(Reg::Rax, out);
+        }, |s, out| s.header_len(Reg::R11, out));
+    }
+
+    /// Stub printing where and why the program stopped to `stderr`, then exiting
+    fn trap_stub(&mut self, trap: Trap, span: Span) -> Block {
+        let message = format!("{}: {}\n", self.map.describe(span), trap.message());
+        let message = self.string(Symbol::intern(&message));
+        let instrs = vec![
+            Instr::Leaq(Arg::Global(message), Arg::Reg(Reg::Rdi)),
+            Instr::Movq(Arg::Global(Symbol::intern("stderr")), Arg::Reg(Reg::Rsi)),
+            Instr::Callq(Symbol::intern("fputs"), 2),
+            Instr::Movq(Arg::Imm(TRAP_EXIT_CODE as i64), Arg::Reg(Reg::Rdi)),
+            Instr::Callq(Symbol::intern("exit"), 1),
+        ];
+        Block { instrs }
+    }
+
+    /// Jump to the stub stopping the program with `trap` at `span` when the last
+    /// comparison holds `cc`
+    fn trap_if(&mut self, cc: Cc, trap: Trap, span: Span, out: &mut Vec<Instr>) {
+        let found = self.traps.iter().find(|(t, s, _)| *t == trap && *s == span);
+        let label = match found {
+            Some((_, _, label)) => *label,
+            None => {
+                let label = Symbol::intern(&format!("trap.{}", self.trap_count));
+                self.trap_count += 1;
+                self.traps.push((trap, span, label));
+                label
+            }
+        };
+        out.push(Instr::JmpIf(cc, label));
+    }
+
+    /// Whether `atom` is a variable holding an `Any`
+    fn is_any(&self, atom: Atom) -> bool {
+        match atom {
+            Atom::Var(name) => self.types.get(&name) == Some(&Type::Any),
+            _ => false,
+        }
+    }
+
+    /// Trap at `span` unless `value` is tagged with the ground type `ty`
+    fn check_tag(&mut self, value: Arg, ty: &Type, span: Span, out: &mut Vec<Instr>) {
+        out.push(Instr::Movq(value, rax()));
+        out.push(Instr::Andq(Arg::Imm(0b111), rax()));
+        out.push(Instr::Cmpq(Arg::Imm(any_tag(ty)), rax()));
+        self.trap_if(Cc::Ne, Trap::WrongType, span, out);
+    }
+
+    /// `rax` set to the length of the tuple `base` points to, in bits 1 to 6 of its header
+    fn header_len(&mut self, base: Reg, out: &mut Vec<Instr>) {
+        out.push(Instr::Movq(Arg::Deref(base, 0), rax()));
+        out.push(Instr::Sarq(Arg::Imm(1), rax()));
+        out.push(Instr::Andq(Arg::Imm(63), rax()));
+    }
+
+    /// `r11` set to the tuple held by `tuple`, an `Any`, and `rax` to its length. Traps at
+    /// `span` when it holds something else. Returns whether the tuple may be a proxy
+    fn any_tuple(&mut self, tuple: Atom, span: Span, out: &mut Vec<Instr>) -> bool {
+        let tuple = self.arg(tuple, out);
+        out.push(Instr::Movq(tuple, r11()));
+        self.check_tag(r11(), &Type::Tuple(Vec::new()), span, out);
+        out.push(Instr::Andq(Arg::Imm(!0b111), r11()));
+        let proxied = self.any_proxied();
+        self.tuple_len(proxied, out);
+        proxied
+    }
+
+    /// Trap at `span` unless `index` is below the length in `rax`. Literal indexes are
+    /// never negative
+    fn check_bounds(&mut self, index: Arg, span: Span, out: &mut Vec<Instr>) {
+        out.push(Instr::Cmpq(index, rax()));
+        self.trap_if(Cc::Le, Trap::OutOfBounds, span, out);
+        if !matches!(index, Arg::Imm(_)) {
+            out.push(Instr::Cmpq(Arg::Imm(0), index));
+            self.trap_if(Cc::L, Trap::OutOfBounds, span, out);
+        }
+    }
+
+    /// `r11` set to the tuple held by `tuple`, with `index` checked against its length when
+    /// it is an `Any`. Returns whether the tuple may be a proxy
+    fn element(&mut self, tuple: Atom, index: Atom, span: Span, out: &mut Vec<Instr>) -> bool {
+        if self.is_any(tuple) {
+            let proxied = self.any_tuple(tuple, span, out);
+            let index = self.arg(index, out);
+            self.check_bounds(index, span, out);
+            proxied
+        } else {
+            let proxied = self.maybe_proxy(tuple);
+            let tuple = self.arg(tuple, out);
+            out.push(Instr::Movq(tuple, r11()));
+            proxied
+        }
+    }
+
+    /// Operand for `atom`. String literals are loaded into `rax`
+    fn arg(&mut self, atom: Atom, out: &mut Vec<Instr>) -> Arg {
+        match atom {
+            Atom::Int(n) => Arg::Imm(n),
+            Atom::Bool(b) => Arg::Imm(b as i64),
+            Atom::Void => Arg::Imm(0),
+            Atom::Var(name) => Arg::Var(name),
+            Atom::Str(s) => {
+                out.push(Instr::Leaq(Arg::Global(self.string(s)), rax()));
+                rax()
+            }
+        }
+    }
+
+    /// Label of the data holding `s`
+    fn string(&mut self, s: Symbol) -> cir::Label {
+        if let Some((label, _)) = self.strings.iter().find(|(_, t)| *t == s) {
+            return *label;
+        }
+        let label = Symbol::intern(&format!("str.{}", self.strings.len()));
+        self.strings.push((label, s));
+        label
+    }
+
+    /// Address of element `index` of the tuple `r11` points to
+    fn address(&mut self, index: Atom, out: &mut Vec<Instr>) -> Arg {
+        match index {
+            Atom::Int(n) => Arg::Deref(Reg::R11, 8 * (n + 1)),
+            index => {
+                let index = self.arg(index, out);
+                out.push(Instr::Movq(index, rax()));
+                out.push(Instr::Addq(Arg::Imm(1), rax()));
+                out.push(Instr::Imulq(Arg::Imm(8), rax()));
+                out.push(Instr::Addq(rax(), r11()));
+                Arg::Deref(Reg::R11, 0)
+            }
+        }
+    }
+
+    /// Call `callee` with `args`, the result ends up in `rax`
+    fn call(&mut self, callee: Callee, args: &[Atom], out: &mut Vec<Instr>) {
+        let in_regs = args.len().min(ARG_REGS.len());
+        for (arg, reg) in args.iter().zip(ARG_REGS) {
+            let arg = self.arg(*arg, out);
+            out.push(Instr::Movq(arg, Arg::Reg(reg)));
+        }
+
+        // The stack stays 16-byte aligned at the call
+        let pushed = args.len() - in_regs;
+        let padding = pushed % 2;
+        if padding == 1 {
+            out.push(Instr::Subq(Arg::Imm(8), Arg::Reg(Reg::Rsp)));
+        }
+        for arg in args[in_regs..].iter().rev() {
+            let arg = self.arg(*arg, out);
+            out.push(Instr::Pushq(arg));
+        }
+
+        match callee {
+            Callee::Direct(name) => out.push(Instr::Callq(name, in_regs)),
+            Callee::Indirect(target) => {
+                let target = self.arg(target, out);
+                out.push(Instr::IndirectCallq(target, in_regs));
+            }
+        }
+
+        if pushed > 0 {
+            out.push(Instr::Addq(Arg::Imm(8 * (pushed + padding) as i64), Arg::Reg(Reg::Rsp)));
+        }
+    }
+
+    /// Call the runtime function `name` with `args` already as operands
+    fn runtime(&mut self, name: &str, args: &[Arg], out: &mut Vec<Instr>) {
+        for (arg, reg) in args.iter().zip(ARG_REGS) {
+            out.push(Instr::Movq(*arg, Arg::Reg(reg)));
+        }
+        out.push(Instr::Callq(Symbol::intern(name), args.len()));
+    }
+
+    fn binary(&mut self, op: BinaryOp, left: Atom, right: Atom, dest: Arg, out: &mut Vec<Instr>) {
+        let left = self.arg(left, out);
+        let right = self.arg(right, out);
+
+        match op {
+            BinaryOp::Cmp(cmp) => {
+                // The second operand of `cmpq` cannot be an immediate
+                let left = match left {
+                    Arg::Imm(_) => {
+                        out.push(Instr::Movq(left, rax()));
+                        rax()
+                    }
+                    left => left,
+                };
+                out.push(Instr::Cmpq(right, left));
+                out.push(Instr::Set(Cc::from_cmp(cmp), Arg::ByteReg(ByteReg::Al)));
+                out.push(Instr::Movzbq(Arg::ByteReg(ByteReg::Al), dest));
+            }
+
+            BinaryOp::Div | BinaryOp::Mod => {
+                out.push(Instr::Movq(left, rax()));
+                out.push(Instr::Cqto);
+                let divisor = match right {
+                    Arg::Imm(_) => {
+                        out.push(Instr::Movq(right, r11()));
+                        r11()
+                    }
+                    right => right,
+                };
+                out.push(Instr::Idivq(divisor));
+                let result = match op {
+                    BinaryOp::Div => rax(),
+                    _ => Arg::Reg(Reg::Rdx),
+                };
+                mov(result, dest, out);
+            }
+
+            BinaryOp::Shl | BinaryOp::Shr => {
+                let count = match right {
+                    Arg::Imm(_) => right,
+                    _ => {
+                        out.push(Instr::Movq(right, Arg::Reg(Reg::Rcx)));
+                        Arg::ByteReg(ByteReg::Cl)
+                    }
+                };
+                mov(left, dest, out);
+                out.push(match op {
+                    BinaryOp::Shl => Instr::Salq(count, dest),
+                    _ => Instr::Sarq(count, dest),
+                });
+            }
+
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::And | BinaryOp::Or => {
+                let instr = |src: Arg| match op {
+                    BinaryOp::Add => Instr::Addq(src, dest),
+                    BinaryOp::Sub => Instr::Subq(src, dest),
+                    BinaryOp::Mul => Instr::Imulq(src, dest),
+                    BinaryOp::And => Instr::Andq(src, dest),
+                    _ => Instr::Orq(src, dest),
+                };
+
+                // Moving the left operand into the destination would lose the right one
+                if right == dest && left != dest {
+                    match op {
+                        BinaryOp::Sub => {
+                            out.push(Instr::Negq(dest));
+                            out.push(Instr::Addq(left, dest));
+                        }
+                        _ => out.push(instr(left)),
+                    }
+                    return;
+                }
+                mov(left, dest, out);
+                out.push(instr(right));
+            }
+        }
+    }
+
+    /// Instructions putting the value of `e`, of type `ty`, into `dest`
+    fn exp(&mut self, e: &Exp, dest: Arg, ty: &Type, out: &mut Vec<Instr>) {
+        match e {
+            Exp::Atom(Atom::Str(s)) => out.push(Instr::Leaq(Arg::Global(self.string(*s)), dest)),
+            Exp::Atom(atom) => {
+                let src = self.arg(*atom, out);
+                mov(src, dest, out);
+            }
+
+            Exp::Unary(op, operand) => {
+                let src = self.arg(*operand, out);
+                mov(src, dest, out);
+                out.push(match (op, ty) {
+                    (UnaryOp::Neg, _) => Instr::Negq(dest),
+                    // Only the lowest bit of a boolean is flipped
+                    (UnaryOp::Not, Type::Bool) => Instr::Xorq(Arg::Imm(1), dest),
+                    (UnaryOp::Not, _) => Instr::Notq(dest),
+                });
+            }
+
+            Exp::Binary(op, left, right) => self.binary(*op, *left, *right, dest, out),
+
+            Exp::Index(tuple, index, span) => {
+                let proxied = self.element(*tuple, *index, *span, out);
+                self.dispatch(proxied, out, |s, out| {
+                    let index = s.arg(*index, out);
+                    s.runtime("proxy_read", &[Arg::Reg(Reg::R15), r11(), index], out);
+                    mov(rax(), dest, out);
+                }, |s, out| {
+                    let element = s.address(*index, out);
+                    mov(element, dest, out);
+                });
+            }
+
+            Exp::Len(tuple, span) => {
+                if self.is_any(*tuple) {
+                    self.any_tuple(*tuple, *span, out);
+                } else {
+                    let proxied = self.maybe_proxy(*tuple);
+                    let tuple = self.arg(*tuple, out);
+                    out.push(Instr::Movq(tuple, r11()));
+                    self.tuple_len(proxied, out);
+                }
+                mov(rax(), dest, out);
+            }
+
+            // There is room, the check and collection come before
+            Exp::Allocate(len, tag) => {
+                let free_ptr = Arg::Global(Symbol::intern("free_ptr"));
+                out.push(Instr::Movq(free_ptr, r11()));
+                out.push(Instr::Addq(Arg::Imm(8 * (*len as i64 + 1)), free_ptr));
+                out.push(Instr::Movq(Arg::Imm(*tag), rax()));
+                out.push(Instr::Movq(rax(), Arg::Deref(Reg::R11, 0)));
+                mov(r11(), dest, out);
+            }
+
+            Exp::GlobalValue(name) => out.push(Instr::Movq(Arg::Global(*name), dest)),
+            Exp::FunRef(name) => out.push(Instr::Leaq(Arg::Global(*name), dest)),
+
+            Exp::Call(callee, args) => {
+                self.call(*callee, args, out);
+                mov(rax(), dest, out);
+            }
+
+            Exp::Inject(value, ground) => {
+                let value = self.arg(*value, out);
+                mov(value, dest, out);
+                if !tagged_in_place(ground) {
+                    out.push(Instr::Salq(Arg::Imm(3), dest));
+                }
+                out.push(Instr::Orq(Arg::Imm(any_tag(ground)), dest));
+            }
+
+            // Traps with the wrong tag, or the wrong length or arity
+            Exp::Project(value, ground, span) => {
+                let value = self.arg(*value, out);
+                self.check_tag(value, ground, *span, out);
+                mov(value, dest, out);
+                if tagged_in_place(ground) {
+                    out.push(Instr::Andq(Arg::Imm(!0b111), dest));
+                } else {
+                    out.push(Instr::Sarq(Arg::Imm(3), dest));
+                }
+
+                match ground {
+                    Type::Tuple(elems) => {
+                        out.push(Instr::Movq(dest, r11()));
+                        self.tuple_len(self.proxied.contains(ground), out);
+                        out.push(Instr::Cmpq(Arg::Imm(elems.len() as i64), rax()));
+                        self.trap_if(Cc::Ne, Trap::WrongType, *span, out);
+                    }
+                    Type::Fn(params, _) => {
+                        out.push(Instr::Movq(dest, r11()));
+                        out.push(Instr::Movq(Arg::Deref(Reg::R11, 0), rax()));
+                        out.push(Instr::Sarq(Arg::Imm(ARITY_SHIFT), rax()));
+                        out.push(Instr::Andq(Arg::Imm(63), rax()));
+                        out.push(Instr::Cmpq(Arg::Imm(params.len() as i64), rax()));
+                        self.trap_if(Cc::Ne, Trap::WrongArity, *span, out);
+                    }
+                    _ => {}
+                }
+            }
+
+            Exp::Is(value, ground) => {
+                let value = self.arg(*value, out);
+                out.push(Instr::Movq(value, rax()));
+                out.push(Instr::Andq(Arg::Imm(0b111), rax()));
+                out.push(Instr::Cmpq(Arg::Imm(any_tag(ground)), rax()));
+                out.push(Instr::Set(Cc::E, Arg::ByteReg(ByteReg::Al)));
+                out.push(Instr::Movzbq(Arg::ByteReg(ByteReg::Al), dest));
+            }
+
+            // Allocates, so the collector gets the root stack
+            Exp::Proxy(tuple, reads, writes) => {
+                let mut args = vec![Arg::Reg(Reg::R15)];
+                for atom in [tuple, reads, writes] {
+                    args.push(self.arg(*atom, out));
+                }
+                self.runtime("proxy", &args, out);
+                mov(rax(), dest, out);
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, out: &mut Vec<Instr>) {
+        match stmt {
+            Stmt::Assign(name, e) => {
+                let ty = self.types.get(name).cloned().expect("Variable without a type");
+                self.exp(e, Arg::Var(*name), &ty, out);
+            }
+            Stmt::SetIndex(tuple, index, value, span) => {
+                let proxied = self.element(*tuple, *index, *span, out);
+                self.dispatch(proxied, out, |s, out| {
+                    let index = s.arg(*index, out);
+                    let value = s.arg(*value, out);
+                    s.runtime("proxy_write", &[Arg::Reg(Reg::R15), r11(), index, value], out);
+                }, |s, out| {
+                    let element = s.address(*index, out);
+                    let value = s.arg(*value, out);
+                    out.push(Instr::Movq(value, element));
+                });
+            }
+            Stmt::Collect(bytes) => self.runtime("collect", &[Arg::Reg(Reg::R15), Arg::Imm(*bytes)], out),
+            Stmt::Exp(e) => self.exp(e, rax(), &Type::Void, out),
+        }
+    }
+
+    /// Instructions ending a block, returns jump to `conclusion`
+    fn tail(&mut self, tail: &Tail, ret: &Type, conclusion: cir::Label, out: &mut Vec<Instr>) {
+        match tail {
+            Tail::Return(e) => {
+                self.exp(e, rax(), ret, out);
+                out.push(Instr::Jmp(conclusion));
+            }
+            Tail::Goto(label) => out.push(Instr::Jmp(*label)),
+            Tail::If { cmp, left, right, then, otherwise } => {
+                let left = match self.arg(*left, out) {
+                    Arg::Imm(n) => {
+                        out.push(Instr::Movq(Arg::Imm(n), rax()));
+                        rax()
+                    }
+                    left => left,
+                };
+                let right = self.arg(*right, out);
+                out.push(Instr::Cmpq(right, left));
+                out.push(Instr::JmpIf(Cc::from_cmp(*cmp), *then));
+                out.push(Instr::Jmp(*otherwise));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::closure::convert_closures;
+    use crate::explicate::explicate_control;
+    use crate::expose::expose_allocation;
+    use crate::generic::{lower_generics, Strategy};
+    use crate::gradual::{check_gradual, lower_casts};
+    use crate::parser::Parser;
+    use crate::rco::remove_complex_operands;
+    use crate::uniquify::uniquify;
+
+    /// Instructions selected for `source`, checked as a gradually typed program
+    fn select(source: &str) -> Program {
+        let map = SourceMap::new("test.x", source);
+        let mut parser = Parser::from_source(source);
+        let root = parser.parse().expect("Parse error");
+        let mut ast = parser.ast;
+        let mut types = check_gradual(&mut ast, root).expect("Type error");
+        lower_casts(&mut ast, root, &mut types);
+        lower_generics(&mut ast, root, &mut types, Strategy::Monomorphize).expect("Generic error");
+        convert_closures(&mut ast, root, &mut types);
+        expose_allocation(&mut ast, root, &mut types);
+        let mut renames = uniquify(&mut ast, root);
+        remove_complex_operands(&mut ast, root, &mut types, &mut renames);
+        let program = explicate_control(&ast, root, &types, &mut renames);
+        select_instructions(&program, &map)
+    }
+
+    fn function<'p>(program: &'p Program, name: &str) -> &'p Function {
+        program.functions.iter().find(|f| f.name.as_str() == name).expect("Missing function")
+    }
+
+    /// Instructions of the blocks of `f` whose label starts with `prefix`
+    fn instrs_in<'f>(f: &'f Function, prefix: &str) -> Vec<&'f Instr> {
+        f.blocks.iter()
+            .filter(|(label, _)| label.as_str().starts_with(prefix))
+            .flat_map(|(_, block)| &block.instrs)
+            .collect()
+    }
+
+    #[test]
+    fn tuple_in_any_goes_through_its_proxy() {
+        // Casting `p` to `?` wraps it in a proxy of `(?, ?)`, which `c` holds. `main` does
+        // not allocate, every access to a tuple there goes through `c`
+        let program = select("fn pair() -> (Int, Int) { (1, 2) }\nfn wrap(p: (Int, Int)) -> ? { p }\nlet c: ? = wrap(pair());\nc[0] = 5;\nlen(c) + c[0]");
+        let main = function(&program, "main");
+        let proxied = instrs_in(main, "proxy.");
+
+        let tuple = Instr::Movq(r11(), Arg::Reg(Reg::Rsi));
+        assert!(proxied.contains(&&Instr::Callq(Symbol::intern("proxy_write"), 4)));
+        assert!(proxied.contains(&&Instr::Callq(Symbol::intern("proxy_read"), 3)));
+        assert_eq!(proxied.iter().filter(|i| ***i == tuple).count(), 2);
+
+        // The bounds checks of both accesses and `len` take the length of the read functions
+        let reads = Instr::Movq(Arg::Deref(Reg::R11, 16), rax());
+        assert_eq!(proxied.iter().filter(|i| ***i == reads).count(), 3);
+
+        // The element itself is only read and written once the tuple is known not to be a
+        // proxy
+        let element = Arg::Deref(Reg::R11, 8);
+        let touches = |instr: &Instr| matches!(instr, Instr::Movq(src, dest) if *src == element || *dest == element);
+        for (label, block) in &main.blocks {
+            let direct = label.as_str().starts_with("direct.");
+            assert!(direct || !block.instrs.iter().any(touches), "{} reads the tuple directly", label);
+        }
+        assert_eq!(instrs_in(main, "direct.").into_iter().filter(|i| touches(i)).count(), 2);
+    }
+
+    #[test]
+    fn arithmetic_uses_the_registers_x86_requires() {
+        let program = select("let a = 7;\nlet b = 2;\nlet q = a / b;\nlet r = a % 3;\nlet s = a << b;\nlet t = a >> 1;\nlet c = a <= b;\nif c { q } else { r + s + t }");
+        let main = function(&program, "main");
+        let var = |name: &str| Arg::Var(Symbol::intern(name));
+        let (al, cl) = (Arg::ByteReg(ByteReg::Al), Arg::ByteReg(ByteReg::Cl));
+
+        let expected = [
+            // `rax` is sign-extended into `rdx`, the quotient ends up in `rax` and the
+            // remainder in `rdx`. An immediate divisor goes through a register
+            Instr::Movq(var("a.0"), rax()),
+            Instr::Cqto,
+            Instr::Idivq(var("b.1")),
+            Instr::Movq(rax(), var("q.2")),
+            Instr::Movq(var("a.0"), rax()),
+            Instr::Cqto,
+            Instr::Movq(Arg::Imm(3), r11()),
+            Instr::Idivq(r11()),
+            Instr::Movq(Arg::Reg(Reg::Rdx), var("r.3")),
+            // A shift count that is not an immediate has to be in `%cl`
+            Instr::Movq(var("b.1"), Arg::Reg(Reg::Rcx)),
+            Instr::Movq(var("a.0"), var("s.4")),
+            Instr::Salq(cl, var("s.4")),
+            Instr::Movq(var("a.0"), var("t.5")),
+            Instr::Sarq(Arg::Imm(1), var("t.5")),
+            // A comparison sets a byte register, which is zero-extended
+            Instr::Cmpq(var("b.1"), var("a.0")),
+            Instr::Set(Cc::Le, al),
+            Instr::Movzbq(al, var("c.6")),
+        ];
+        let instrs = &main.blocks[0].1.instrs;
+        assert_eq!(instrs[2..2 + expected.len()], expected);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// Half open range `[start, end)` of byte offsets into the source
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        assert!(start <= end, "Inverted span");
+        Span { start, end }
+    }
+
+    /// Empty span right at `offset`, used for things like end of file
+    pub fn at(offset: usize) -> Span {
+        Span { start: offset, end: offset }
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+/// 1-based line and column of an offset
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Maps offsets of a single source file back into lines and columns. Only the offset
+/// each line starts at is kept, line text is sliced out of the source when asked for
+pub struct SourceMap<'a> {
+    pub name: String,
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(name: &str, source: &'a str) -> SourceMap<'a> {
+        let mut line_starts = vec![0];
+        for (offset, byte) in source.bytes().enumerate() {
+            if byte == b'\n' {
+                line_starts.push(offset + 1);
+            }
+        }
+
+        SourceMap {
+            name: name.to_string(),
+            source,
+            line_starts,
+        }
+    }
+
+    pub fn location(&self, offset: usize) -> Location {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+
+        // Columns count characters, not bytes. The offset can land on a stripped `\r`
+        let text = self.line_text(line + 1);
+        let byte_column = (offset - self.line_starts[line]).min(text.len());
+        let column = match text.get(..byte_column) {
+            Some(prefix) => prefix.chars().count(),
+            None => byte_column,
+        };
+
+        Location {
+            line: line + 1,
+            column: column + 1,
+        }
+    }
+
+    /// Text of the 1-based `line`, without its line terminator
+    pub fn line_text(&self, line: usize) -> &'a str {
+        let start = self.line_starts[line - 1];
+        let end = match self.line_starts.get(line) {
+            Some(next) => next - 1,
+            None => self.source.len(),
+        };
+        let text = &self.source[start..end];
+        text.strip_suffix('\r').unwrap_or(text)
+    }
+
+    /// Formats as `file:line:col`
+    pub fn describe(&self, span: Span) -> String {
+        let loc = self.location(span.start);
+        format!("{}:{}:{}", self.name, loc.line, loc.column)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
@@ -0,0 +1,935 @@
+use crate::ast::{Ast, FnType, LambdaExpr, Node, NodeId, Param, PrimaryExpr, TupleType, TypeName};
+use crate::diagnostic::Diagnostic;
+use crate::expose::MAX_TUPLE_LEN;
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::source::Span;
+use crate::Error;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Type {
+    Int,
+    Bool,
+    Void,
+    Str,
+    Tuple(Vec<Type>),
+    /// Function value, a closure once closures have been converted
+    Fn(Vec<Type>, Box<Type>),
+    /// Address of the code of a top-level function, introduced by closure conversion.
+    /// Code made from a lambda takes its closure as an extra first argument
+    Code(Vec<Type>, Box<Type>),
+    /// Unknown type during inference. Left over after it when a binding is generic
+    Var(u32),
+    /// Type parameter of a generic function, only equal to itself
+    Param(Symbol),
+    /// Type of a generic function, `All<T> fn(T) -> T`
+    All(Vec<Symbol>, Box<Type>),
+    /// Value of any type tagged with its type, the only type of dynamically typed programs
+    Any,
+}
+
+impl Type {
+    /// Values of the type live on the heap, the collector has to follow them. Values of
+    /// a type parameter are boxed once generics are lowered. Generic `let` bindings are
+    /// specialized by then, a type variable left over types a value no code produces.
+    /// `Any` values might be pointers, the collector reads their tag
+    pub fn is_pointer(&self) -> bool {
+        matches!(self, Type::Tuple(_) | Type::Fn(..) | Type::Param(_) | Type::Any)
+    }
+
+    /// The type with its type parameters replaced by `args`
+    pub fn instantiate(&self, args: &[(Symbol, Type)]) -> Type {
+        match self {
+            Type::Param(name) => match args.iter().find(|(p, _)| p == name) {
+                Some((_, ty)) => ty.clone(),
+                None => self.clone(),
+            },
+            Type::Tuple(elems) => Type::Tuple(elems.iter().map(|e| e.instantiate(args)).collect()),
+            Type::Fn(params, ret) => Type::Fn(params.iter().map(|p| p.instantiate(args)).collect(), Box::new(ret.instantiate(args))),
+            Type::Code(params, ret) => Type::Code(params.iter().map(|p| p.instantiate(args)).collect(), Box::new(ret.instantiate(args))),
+            Type::All(params, ty) => {
+                let args: Vec<(Symbol, Type)> = args.iter().filter(|(p, _)| !params.contains(p)).cloned().collect();
+                Type::All(params.clone(), Box::new(ty.instantiate(&args)))
+            }
+            ty => ty.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |types: &[Type]| -> String {
+            let types: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+            types.join(", ")
+        };
+
+        match self {
+            Type::Int => write!(f, "Int"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Void => write!(f, "Void"),
+            Type::Str => write!(f, "Str"),
+            Type::Any => write!(f, "Any"),
+            Type::Tuple(elems) if elems.len() == 1 => write!(f, "({},)", elems[0]),
+            Type::Tuple(elems) => write!(f, "({})", list(elems)),
+            Type::Fn(params, ret) => write!(f, "fn({}) -> {}", list(params), ret),
+            Type::Code(params, ret) => write!(f, "code({}) -> {}", list(params), ret),
+            Type::Var(v) if *v < 26 => write!(f, "'{}", (b'a' + *v as u8) as char),
+            Type::Var(v) => write!(f, "'t{}", v),
+            Type::Param(name) => write!(f, "{}", name),
+            Type::All(params, ty) => {
+                let params: Vec<&str> = params.iter().map(|p| p.as_str()).collect();
+                write!(f, "All<{}> {}", params.join(", "), ty)
+            }
+        }
+    }
+}
+
+/// Type of every expression and statement of a program, statements are `Void`. Uses of
+/// generic functions also get the types their parameters were instantiated with, and so
+/// do uses of `let` bindings generalized over type variables
+pub struct TypeTable {
+    types: HashMap<NodeId, Type>,
+    instances: HashMap<NodeId, Vec<Type>>,
+    /// Generalized `let` bindings with the variables they are generic over
+    schemes: HashMap<NodeId, Vec<u32>>,
+    /// Uses of generalized `let` bindings, with the binding and the types its variables
+    /// were instantiated with
+    let_instances: HashMap<NodeId, (NodeId, Vec<Type>)>,
+}
+
+impl TypeTable {
+    pub fn new() -> TypeTable {
+        TypeTable {
+            types: HashMap::new(),
+            instances: HashMap::new(),
+            schemes: HashMap::new(),
+            let_instances: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, id: NodeId) -> &Type {
+        self.types.get(&id).expect("Node was not type checked")
+    }
+
+    /// Type of `id`, type annotations have none
+    pub fn try_get(&self, id: NodeId) -> Option<&Type> {
+        self.types.get(&id)
+    }
+
+    /// Passes creating nodes after type checking record their type here
+    pub fn insert(&mut self, id: NodeId, ty: Type) {
+        self.types.insert(id, ty);
+    }
+
+    pub fn is_pointer(&self, id: NodeId) -> bool {
+        self.get(id).is_pointer()
+    }
+
+    /// Type arguments of `id`, when it names a generic function
+    pub fn instance(&self, id: NodeId) -> Option<&Vec<Type>> {
+        self.instances.get(&id)
+    }
+
+    pub fn set_instance(&mut self, id: NodeId, args: Vec<Type>) {
+        self.instances.insert(id, args);
+    }
+
+    /// Variables the `let` binding `id` is generic over, when it was generalized
+    pub fn scheme(&self, id: NodeId) -> Option<&Vec<u32>> {
+        self.schemes.get(&id)
+    }
+
+    pub fn set_scheme(&mut self, id: NodeId, vars: Vec<u32>) {
+        self.schemes.insert(id, vars);
+    }
+
+    pub fn remove_scheme(&mut self, id: NodeId) {
+        self.schemes.remove(&id);
+    }
+
+    /// Generalized binding `id` refers to and the types of its variables at this use
+    pub fn let_instance(&self, id: NodeId) -> Option<&(NodeId, Vec<Type>)> {
+        self.let_instances.get(&id)
+    }
+
+    pub fn set_let_instance(&mut self, id: NodeId, binding: NodeId, args: Vec<Type>) {
+        self.let_instances.insert(id, (binding, args));
+    }
+}
+
+/// Annotation node spelling out `ty`, for nodes created after inference. Variables are
+/// spelled with their internal name
+pub fn annotation(ast: &mut Ast, ty: &Type, span: Span) -> NodeId {
+    let node = match ty {
+        Type::Tuple(elems) => {
+            let elems = elems.iter().map(|e| annotation(ast, e, span)).collect();
+            Node::TupleType(TupleType { elems })
+        }
+        Type::Fn(params, ret) => {
+            let params = params.iter().map(|p| annotation(ast, p, span)).collect();
+            let ret = annotation(ast, ret, span);
+            Node::FnType(FnType { params, ret: Some(ret) })
+        }
+        ty => Node::TypeName(TypeName { name: Symbol::intern(&ty.to_string()) }),
+    };
+    ast.add(node, span)
+}
+
+/// Type spelled out by the annotation `id`, the other way around from `annotation`
+pub fn annotated(ast: &Ast, id: NodeId) -> Type {
+    match &ast[id] {
+        Node::TypeName(t) => match t.name.as_str() {
+            "Int" => Type::Int,
+            "Bool" => Type::Bool,
+            "Void" => Type::Void,
+            "Str" => Type::Str,
+            "Any" | "?" => Type::Any,
+            _ => Type::Param(t.name),
+        },
+        Node::TupleType(t) => Type::Tuple(t.elems.iter().map(|e| annotated(ast, *e)).collect()),
+        Node::FnType(f) => {
+            let params = f.params.iter().map(|p| annotated(ast, *p)).collect();
+            let ret = match f.ret {
+                Some(ret) => annotated(ast, ret),
+                None => Type::Void,
+            };
+            Type::Fn(params, Box::new(ret))
+        }
+        _ => unreachable!("Node is not a type annotation"),
+    }
+}
+
+/// Infers the type of every node with unification, annotations are optional on `let`
+/// bindings and lambdas and checked where they are given. `let` bindings of lambdas are
+/// generalized, so `let id = fn(x) { x };` can be used at several types. Ill-typed
+/// programs are rejected with the first mismatch.
+pub fn type_check(ast: &Ast, program: NodeId) -> Result<TypeTable, Diagnostic> {
+    let (functions, main) = match &ast[program] {
+        Node::Program(p) => (p.functions.clone(), p.main),
+        _ => panic!("Type checking expects a program"),
+    };
+
+    let mut inference = Inference {
+        ast,
+        table: TypeTable::new(),
+        functions: HashMap::new(),
+        locals: Vec::new(),
+        subst: Vec::new(),
+        deferred: Vec::new(),
+        generics: Vec::new(),
+    };
+
+    // Signatures first, functions can be called before they are declared
+    for id in &functions {
+        if let Node::FnDecl(decl) = &ast[*id] {
+            if let Some(param) = decl.params.iter().find(|p| p.ty.is_none()) {
+                let diag = Diagnostic::error(Error::TypeAnnotationNeeded, param.name_span)
+                    .with_message(format!("parameter `{}` of function `{}` needs a type", param.name, decl.name))
+                    .with_note("only the parameters of lambdas are inferred")
+                    .with_suggestion(format!("write `{}: Type`, or compile with `--dynamic` or `--gradual`", param.name));
+                return Err(diag);
+            }
+            inference.generics = decl.generics.clone();
+            let mut ty = inference.signature(&decl.params, decl.ret, Type::Void)?;
+            if !decl.generics.is_empty() {
+                ty = Type::All(decl.generics.clone(), Box::new(ty));
+            }
+            inference.functions.insert(decl.name, ty);
+        }
+    }
+    inference.generics.clear();
+
+    for id in &functions {
+        inference.infer(*id)?;
+    }
+    inference.infer(main)?;
+    inference.table.insert(program, Type::Void);
+
+    // Operators working on both integers and booleans fall back to integers
+    for (id, operator) in std::mem::take(&mut inference.deferred) {
+        let ty = inference.zonk(inference.table.get(id));
+        match ty {
+            Type::Int | Type::Bool => {}
+            Type::Var(v) => inference.subst[v as usize] = Some(Type::Int),
+            _ => {
+                let ty = normalize(&ty, &mut Vec::new());
+                let diag = Diagnostic::error(Error::TypeMismatch, ast.span(id))
+                    .with_message(format!("cannot apply `{}` to `{}`", operator, ty));
+                return Err(diag);
+            }
+        }
+    }
+
+    let mut table = std::mem::replace(&mut inference.table, TypeTable::new());
+    for ty in table.types.values_mut() {
+        *ty = inference.zonk(ty);
+    }
+    for args in table.instances.values_mut() {
+        for ty in args.iter_mut() {
+            *ty = inference.zonk(ty);
+        }
+    }
+    for (_, args) in table.let_instances.values_mut() {
+        for ty in args.iter_mut() {
+            *ty = inference.zonk(ty);
+        }
+    }
+    Ok(table)
+}
+
+/// Type of a `let` binding with the variables it is generic over
+#[derive(Clone, Debug)]
+struct Scheme {
+    vars: Vec<u32>,
+    ty: Type,
+    /// The `let` binding, when it is generic
+    binding: Option<NodeId>,
+}
+
+/// Why two types could not be unified
+enum Mismatch {
+    Types,
+    /// The variable would have to contain itself
+    Occurs(u32, Type),
+}
+
+struct Inference<'a> {
+    ast: &'a Ast,
+    table: TypeTable,
+    functions: HashMap<Symbol, Type>,
+    locals: Vec<(Symbol, Scheme)>,
+    /// What each type variable has been unified with so far
+    subst: Vec<Option<Type>>,
+    /// Operands that have to end up `Int` or `Bool`, with their operator
+    deferred: Vec<(NodeId, TokenKind)>,
+    /// Type parameters of the function being checked
+    generics: Vec<Symbol>,
+}
+
+impl Inference<'_> {
+    fn fresh(&mut self) -> Type {
+        let v = u32::try_from(self.subst.len()).expect("Too many type variables");
+        self.subst.push(None);
+        Type::Var(v)
+    }
+
+    /// Follow bound variables until the outermost constructor is known
+    fn shallow(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(v) = ty {
+            match &self.subst[v as usize] {
+                Some(bound) => ty = bound.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// `ty` with every bound variable replaced, all the way down
+    fn zonk(&self, ty: &Type) -> Type {
+        match self.shallow(ty) {
+            Type::Tuple(elems) => Type::Tuple(elems.iter().map(|e| self.zonk(e)).collect()),
+            Type::Fn(params, ret) => Type::Fn(params.iter().map(|p| self.zonk(p)).collect(), Box::new(self.zonk(&ret))),
+            Type::Code(params, ret) => Type::Code(params.iter().map(|p| self.zonk(p)).collect(), Box::new(self.zonk(&ret))),
+            Type::All(params, ty) => Type::All(params, Box::new(self.zonk(&ty))),
+            ty => ty,
+        }
+    }
+
+    fn occurs(&self, v: u32, ty: &Type) -> bool {
+        match self.shallow(ty) {
+            Type::Var(w) => v == w,
+            Type::Tuple(elems) => elems.iter().any(|e| self.occurs(v, e)),
+            Type::Fn(params, ret) | Type::Code(params, ret) => {
+                params.iter().any(|p| self.occurs(v, p)) || self.occurs(v, &ret)
+            }
+            Type::All(_, ty) => self.occurs(v, &ty),
+            _ => false,
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), Mismatch> {
+        let a = self.shallow(a);
+        let b = self.shallow(b);
+
+        match (&a, &b) {
+            (Type::Var(v), Type::Var(w)) if v == w => Ok(()),
+            (Type::Var(v), ty) | (ty, Type::Var(v)) => {
+                if self.occurs(*v, ty) {
+                    return Err(Mismatch::Occurs(*v, ty.clone()));
+                }
+                self.subst[*v as usize] = Some(ty.clone());
+                Ok(())
+            }
+            (Type::Tuple(xs), Type::Tuple(ys)) if xs.len() == ys.len() => {
+                for (x, y) in xs.iter().zip(ys) {
+                    self.unify(x, y)?;
+                }
+                Ok(())
+            }
+            (Type::Fn(xs, x), Type::Fn(ys, y)) | (Type::Code(xs, x), Type::Code(ys, y)) if xs.len() == ys.len() => {
+                for (x, y) in xs.iter().zip(ys) {
+                    self.unify(x, y)?;
+                }
+                self.unify(x, y)
+            }
+            _ if a == b => Ok(()),
+            _ => Err(Mismatch::Types),
+        }
+    }
+
+    /// Unify the type found at `span` with the one expected there
+    fn unify_at(&mut self, span: Span, expected: &Type, found: &Type) -> Result<(), Diagnostic> {
+        match self.unify(expected, found) {
+            Ok(()) => Ok(()),
+            Err(Mismatch::Types) => Err(mismatch(span, &self.zonk(expected), &self.zonk(found))),
+            Err(Mismatch::Occurs(v, ty)) => {
+                let mut vars = Vec::new();
+                let var = normalize(&Type::Var(v), &mut vars);
+                let ty = normalize(&self.zonk(&ty), &mut vars);
+                let diag = Diagnostic::error(Error::InfiniteType, span)
+                    .with_message(format!("cannot construct the infinite type `{} = {}`", var, ty))
+                    .with_note("a value cannot contain or return a value of its own type");
+                Err(diag)
+            }
+        }
+    }
+
+    /// Infer `id` and require its type to be `expected`
+    fn expect(&mut self, id: NodeId, expected: &Type) -> Result<(), Diagnostic> {
+        let found = self.infer(id)?;
+        self.unify_at(self.ast.span(id), expected, &found)
+    }
+
+    /// Free variables of `ty` that are not in `bound`
+    fn free_vars(&self, ty: &Type, bound: &[u32], out: &mut Vec<u32>) {
+        match self.shallow(ty) {
+            Type::Var(v) if !bound.contains(&v) && !out.contains(&v) => out.push(v),
+            Type::Tuple(elems) => {
+                for elem in &elems {
+                    self.free_vars(elem, bound, out);
+                }
+            }
+            Type::Fn(params, ret) | Type::Code(params, ret) => {
+                for param in &params {
+                    self.free_vars(param, bound, out);
+                }
+                self.free_vars(&ret, bound, out);
+            }
+            _ => {}
+        }
+    }
+
+    /// Quantify the type of the binding `id` over the variables of `ty` that no variable
+    /// in scope mentions. Operands of operators on integers or booleans cannot be generic
+    /// over which one, they fall back to `Int` first
+    fn generalize(&mut self, id: NodeId, ty: &Type) -> Scheme {
+        let mut env = Vec::new();
+        for (_, scheme) in &self.locals {
+            self.free_vars(&scheme.ty, &scheme.vars, &mut env);
+        }
+
+        let mut vars = Vec::new();
+        self.free_vars(ty, &env, &mut vars);
+        for (operand, _) in &self.deferred {
+            // Operands around the binding are not inferred yet
+            let ty = match self.table.try_get(*operand) {
+                Some(ty) => self.zonk(ty),
+                None => continue,
+            };
+            if let Type::Var(v) = ty {
+                if vars.contains(&v) {
+                    self.subst[v as usize] = Some(Type::Int);
+                }
+            }
+        }
+        vars.retain(|v| self.subst[*v as usize].is_none());
+
+        if vars.is_empty() {
+            return Scheme { vars, ty: self.zonk(ty), binding: None };
+        }
+        self.table.set_scheme(id, vars.clone());
+        Scheme { vars, ty: self.zonk(ty), binding: Some(id) }
+    }
+
+    /// Type of a use `id` of a `let` binding, with fresh variables for the ones it is
+    /// generic over
+    fn instantiate(&mut self, id: NodeId, scheme: &Scheme) -> Type {
+        let fresh: Vec<(u32, Type)> = scheme.vars.iter().map(|v| (*v, self.fresh())).collect();
+        if let Some(binding) = scheme.binding {
+            self.table.set_let_instance(id, binding, fresh.iter().map(|(_, ty)| ty.clone()).collect());
+        }
+        substitute(&scheme.ty, &fresh)
+    }
+
+    /// Type of a use of `name`, generic functions get fresh variables for their parameters
+    /// and the use remembers them
+    fn instantiate_use(&mut self, id: NodeId, name: Symbol) -> Option<Type> {
+        let ty = match self.lookup(name)? {
+            Scheme { ty: Type::All(params, ty), .. } => {
+                let args: Vec<(Symbol, Type)> = params.iter().map(|p| (*p, self.fresh())).collect();
+                self.table.set_instance(id, args.iter().map(|(_, ty)| ty.clone()).collect());
+                ty.instantiate(&args)
+            }
+            scheme => self.instantiate(id, &scheme),
+        };
+        Some(ty)
+    }
+
+    fn lookup(&self, name: Symbol) -> Option<Scheme> {
+        match self.locals.iter().rev().find(|(n, _)| *n == name) {
+            Some((_, scheme)) => Some(scheme.clone()),
+            None => self.functions.get(&name).map(|ty| Scheme { vars: Vec::new(), ty: ty.clone(), binding: None }),
+        }
+    }
+
+    /// Type written in an annotation
+    fn resolve(&self, id: NodeId) -> Result<Type, Diagnostic> {
+        match &self.ast[id] {
+            Node::TypeName(t) if self.generics.contains(&t.name) => Ok(Type::Param(t.name)),
+            Node::TypeName(t) => match t.name.as_str() {
+                "Int" => Ok(Type::Int),
+                "Bool" => Ok(Type::Bool),
+                "Void" => Ok(Type::Void),
+                "Str" => Ok(Type::Str),
+                "?" => {
+                    let diag = Diagnostic::error(Error::UnknownType, self.ast.span(id))
+                        .with_message("the dynamic type `?` needs gradual typing")
+                        .with_suggestion("compile with `--gradual`");
+                    Err(diag)
+                }
+                _ => {
+                    let diag = Diagnostic::error(Error::UnknownType, self.ast.span(id))
+                        .with_message(format!("cannot find type `{}`", t.name))
+                        .with_note("the types are `Int`, `Bool`, `Void`, `Str`, tuples and functions");
+                    Err(diag)
+                }
+            },
+
+            Node::TupleType(t) => {
+                check_tuple_len(t.elems.len(), self.ast.span(id))?;
+                let elems = t.elems.iter().map(|e| self.resolve(*e)).collect::<Result<_, _>>()?;
+                Ok(Type::Tuple(elems))
+            }
+
+            Node::FnType(f) => {
+                let params = f.params.iter().map(|p| self.resolve(*p)).collect::<Result<_, _>>()?;
+                let ret = match f.ret {
+                    Some(ret) => self.resolve(ret)?,
+                    None => Type::Void,
+                };
+                Ok(Type::Fn(params, Box::new(ret)))
+            }
+
+            _ => unreachable!("Node is not a type annotation"),
+        }
+    }
+
+    /// Function type of a signature, with fresh variables for the missing parameter types
+    /// and `missing_ret` when there is no `->`
+    fn signature(&mut self, params: &[Param], ret: Option<NodeId>, missing_ret: Type) -> Result<Type, Diagnostic> {
+        let mut param_types = Vec::new();
+        for param in params {
+            match param.ty {
+                Some(ty) => param_types.push(self.resolve(ty)?),
+                None => param_types.push(self.fresh()),
+            }
+        }
+        let ret = match ret {
+            Some(ret) => self.resolve(ret)?,
+            None => missing_ret,
+        };
+        Ok(Type::Fn(param_types, Box::new(ret)))
+    }
+
+    /// Body of a function or lambda against its return type
+    fn infer_body(&mut self, params: &[Param], ty: &Type, ret: Option<NodeId>, body: NodeId) -> Result<(), Diagnostic> {
+        let (param_types, ret_type) = match ty {
+            Type::Fn(params, ret) => (params, ret),
+            _ => unreachable!("Signature is not a function type"),
+        };
+
+        let scope = self.locals.len();
+        for (param, ty) in params.iter().zip(param_types) {
+            self.locals.push((param.name, Scheme { vars: Vec::new(), ty: ty.clone(), binding: None }));
+        }
+
+        let result = self.expect(body, ret_type).map_err(|diag| match ret {
+            Some(ret) => diag.with_label(self.ast.span(ret), "expected because of this return type"),
+            None => diag,
+        });
+
+        self.locals.truncate(scope);
+        result
+    }
+
+    /// Tuple type of `id`, which has to be known by now
+    fn infer_tuple(&mut self, id: NodeId) -> Result<Vec<Type>, Diagnostic> {
+        let ty = self.infer(id)?;
+        match self.shallow(&ty) {
+            Type::Tuple(elems) => Ok(elems),
+            Type::Var(_) => {
+                let diag = Diagnostic::error(Error::TypeAnnotationNeeded, self.ast.span(id))
+                    .with_message("the type of this tuple must be known at this point")
+                    .with_suggestion("add a type annotation to the variable or parameter");
+                Err(diag)
+            }
+            other => {
+                let diag = Diagnostic::error(Error::TypeMismatch, self.ast.span(id))
+                    .with_message(format!("expected tuple, found `{}`", normalize(&self.zonk(&other), &mut Vec::new())));
+                Err(diag)
+            }
+        }
+    }
+
+    fn infer(&mut self, id: NodeId) -> Result<Type, Diagnostic> {
+        let ast = self.ast;
+        let span = ast.span(id);
+        let scope = self.locals.len();
+
+        let ty = match &ast[id] {
+            Node::Primary(PrimaryExpr { value: Token { kind, .. } }) => match kind {
+                TokenKind::Integer(_) => Type::Int,
+                TokenKind::True | TokenKind::False => Type::Bool,
+                TokenKind::String(_) => Type::Str,
+                TokenKind::Identifier(name) => match self.instantiate_use(id, *name) {
+                    Some(ty) => ty,
+                    None => {
+                        let diag = Diagnostic::error(Error::UnknownVariable, span)
+                            .with_message(format!("cannot find `{}` in this scope", name));
+                        return Err(diag);
+                    }
+                },
+                _ => unreachable!("Parser only produces literals and identifiers as primaries"),
+            },
+
+            Node::Unary(u) => match u.operator {
+                TokenKind::Minus => {
+                    self.expect(u.operand, &Type::Int)?;
+                    Type::Int
+                }
+                _ => {
+                    self.deferred.push((u.operand, u.operator.clone()));
+                    self.infer(u.operand)?
+                }
+            },
+
+            Node::Binary(b) => {
+                use TokenKind as T;
+
+                match b.operator {
+                    T::Plus | T::Minus | T::Star | T::Slash | T::Modulo | T::ShiftLeft | T::ShiftRight => {
+                        self.expect(b.left, &Type::Int)?;
+                        self.expect(b.right, &Type::Int)?;
+                        Type::Int
+                    }
+                    T::Lt | T::LtEq | T::Gt | T::GtEq => {
+                        self.expect(b.left, &Type::Int)?;
+                        self.expect(b.right, &Type::Int)?;
+                        Type::Bool
+                    }
+                    // Logical on booleans, bitwise on integers
+                    T::Equal | T::NotEqual | T::And | T::Or => {
+                        let left = self.infer(b.left)?;
+                        self.deferred.push((b.left, b.operator.clone()));
+                        self.expect(b.right, &left)
+                            .map_err(|diag| diag.with_label(ast.span(b.left), "expected because of this operand"))?;
+
+                        match b.operator {
+                            T::Equal | T::NotEqual => Type::Bool,
+                            _ => left,
+                        }
+                    }
+                    _ => unreachable!("Parser only produces infix operators"),
+                }
+            }
+
+            Node::Block(b) => {
+                for stmt in &b.stmts {
+                    self.infer(*stmt)?;
+                }
+                match b.value {
+                    Some(value) => self.infer(value)?,
+                    None => Type::Void,
+                }
+            }
+
+            Node::If(i) => {
+                self.expect(i.cond, &Type::Bool)?;
+                let then = self.infer(i.then)?;
+                match i.otherwise {
+                    Some(otherwise) => {
+                        self.expect(otherwise, &then)
+                            .map_err(|diag| diag.with_label(ast.span(i.then), "expected because of this branch"))?;
+                        then
+                    }
+                    None => {
+                        self.unify_at(ast.span(i.then), &Type::Void, &then)
+                            .map_err(|diag| diag.with_note("an `if` without `else` evaluates to `Void`"))?;
+                        Type::Void
+                    }
+                }
+            }
+
+            Node::While(w) => {
+                self.expect(w.cond, &Type::Bool)?;
+                self.expect(w.body, &Type::Void)?;
+                Type::Void
+            }
+
+            Node::Assign(a) => {
+                // Assigning a generic variable would fix its type for every other use
+                if let Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) = &ast[a.target] {
+                    if let Some(scheme) = self.lookup(*name) {
+                        if !scheme.vars.is_empty() {
+                            let diag = Diagnostic::error(Error::InvalidAssignment, ast.span(a.target))
+                                .with_message(format!(
+                                    "cannot assign to `{}`, its type `{}` is generic",
+                                    name, normalize(&scheme.ty, &mut Vec::new()),
+                                ))
+                                .with_suggestion("add a type annotation to the `let`");
+                            return Err(diag);
+                        }
+                    }
+                }
+
+                let target = self.infer(a.target)?;
+                self.expect(a.value, &target)
+                    .map_err(|diag| diag.with_label(ast.span(a.target), "expected because of this place"))?;
+                Type::Void
+            }
+
+            Node::Break | Node::Continue => Type::Void,
+
+            Node::Call(c) => {
+                let callee = self.infer(c.callee)?;
+                let (params, ret) = match self.shallow(&callee) {
+                    Type::Fn(params, ret) => (params, *ret),
+                    Type::Var(_) => {
+                        let params: Vec<Type> = c.args.iter().map(|_| self.fresh()).collect();
+                        let ret = self.fresh();
+                        let ty = Type::Fn(params.clone(), Box::new(ret.clone()));
+                        self.unify_at(ast.span(c.callee), &ty, &callee)?;
+                        (params, ret)
+                    }
+                    other => {
+                        let diag = Diagnostic::error(Error::NotCallable, ast.span(c.callee))
+                            .with_message(format!("expected function, found `{}`", normalize(&self.zonk(&other), &mut Vec::new())));
+                        return Err(diag);
+                    }
+                };
+
+                if params.len() != c.args.len() {
+                    let plural = if params.len() == 1 { "" } else { "s" };
+                    let diag = Diagnostic::error(Error::ArityMismatch, span)
+                        .with_message(format!(
+                            "function takes {} argument{} but {} {} supplied",
+                            params.len(), plural, c.args.len(),
+                            if c.args.len() == 1 { "was" } else { "were" },
+                        ));
+                    return Err(diag);
+                }
+
+                for (arg, param) in c.args.iter().zip(&params) {
+                    self.expect(*arg, param)?;
+                }
+                ret
+            }
+
+            Node::Tuple(t) => {
+                check_tuple_len(t.elems.len(), span)?;
+                let mut elems = Vec::new();
+                for elem in &t.elems {
+                    elems.push(self.infer(*elem)?);
+                }
+                Type::Tuple(elems)
+            }
+
+            Node::Index(i) => {
+                let elems = self.infer_tuple(i.tuple)?;
+
+                let index = match &ast[i.index] {
+                    Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Integer(n), .. } }) => *n,
+                    _ => {
+                        let diag = Diagnostic::error(Error::InvalidTupleIndex, ast.span(i.index))
+                            .with_message("tuple index must be an integer literal")
+                            .with_note("elements can have different types, so the index has to be known");
+                        return Err(diag);
+                    }
+                };
+                self.table.insert(i.index, Type::Int);
+
+                match usize::try_from(index).ok().and_then(|n| elems.get(n)) {
+                    Some(elem) => elem.clone(),
+                    None => {
+                        let ty = normalize(&self.zonk(&Type::Tuple(elems.clone())), &mut Vec::new());
+                        let diag = Diagnostic::error(Error::InvalidTupleIndex, ast.span(i.index))
+                            .with_message(format!("index {} is out of bounds for `{}`", index, ty))
+                            .with_label(ast.span(i.tuple), format!("has {} elements", elems.len()));
+                        return Err(diag);
+                    }
+                }
+            }
+
+            Node::Len(l) => {
+                self.infer_tuple(l.tuple)?;
+                Type::Int
+            }
+
+            Node::Lambda(LambdaExpr { params, ret, body }) => {
+                let missing_ret = self.fresh();
+                let ty = self.signature(params, *ret, missing_ret)?;
+                self.infer_body(params, &ty, *ret, *body)?;
+                ty
+            }
+
+            Node::Let(l) => {
+                let init = self.infer(l.init)?;
+                if let Some(annotation) = l.ty {
+                    let expected = self.resolve(annotation)?;
+                    self.unify_at(ast.span(l.init), &expected, &init)
+                        .map_err(|diag| diag.with_label(ast.span(annotation), "expected because of this annotation"))?;
+                }
+
+                // Only functions are generic, other values could be assigned to later
+                let scheme = match &ast[l.init] {
+                    Node::Lambda(_) => self.generalize(id, &init),
+                    _ => Scheme { vars: Vec::new(), ty: init, binding: None },
+                };
+
+                // Stays in scope until the enclosing block ends
+                self.locals.push((l.name, scheme));
+                self.table.insert(id, Type::Void);
+                return Ok(Type::Void);
+            }
+
+            Node::ExprStmt(e) => {
+                self.infer(e.expr)?;
+                Type::Void
+            }
+
+            Node::FnDecl(decl) => {
+                let ty = match &self.functions[&decl.name] {
+                    Type::All(_, ty) => (**ty).clone(),
+                    ty => ty.clone(),
+                };
+                self.generics = decl.generics.clone();
+                let result = self.infer_body(&decl.params, &ty, decl.ret, decl.body);
+                self.generics.clear();
+                result
+                    .map_err(|diag| match decl.ret {
+                        Some(_) => diag,
+                        None => diag.with_note("functions without `->` return `Void`"),
+                    })?;
+                ty
+            }
+
+            node => unreachable!("Unexpected node before lowering: {:?}", node),
+        };
+
+        self.locals.truncate(scope);
+        self.table.insert(id, ty.clone());
+        Ok(ty)
+    }
+}
+
+/// `ty` with the variables of `vars` replaced
+pub fn substitute(ty: &Type, vars: &[(u32, Type)]) -> Type {
+    match ty {
+        Type::Var(v) => match vars.iter().find(|(w, _)| w == v) {
+            Some((_, ty)) => ty.clone(),
+            None => ty.clone(),
+        },
+        Type::Tuple(elems) => Type::Tuple(elems.iter().map(|e| substitute(e, vars)).collect()),
+        Type::Fn(params, ret) => Type::Fn(params.iter().map(|p| substitute(p, vars)).collect(), Box::new(substitute(ret, vars))),
+        Type::Code(params, ret) => Type::Code(params.iter().map(|p| substitute(p, vars)).collect(), Box::new(substitute(ret, vars))),
+        Type::All(params, ty) => Type::All(params.clone(), Box::new(substitute(ty, vars))),
+        ty => ty.clone(),
+    }
+}
+
+/// Renumber the variables of `ty` in order of appearance, so messages show `'a`, `'b`..
+/// whatever the variables are called internally. `vars` is shared by the types of one message
+fn normalize(ty: &Type, vars: &mut Vec<u32>) -> Type {
+    match ty {
+        Type::Var(v) => match vars.iter().position(|w| w == v) {
+            Some(i) => Type::Var(i as u32),
+            None => {
+                vars.push(*v);
+                Type::Var(vars.len() as u32 - 1)
+            }
+        },
+        Type::Tuple(elems) => Type::Tuple(elems.iter().map(|e| normalize(e, vars)).collect()),
+        Type::Fn(params, ret) => {
+            let params = params.iter().map(|p| normalize(p, vars)).collect();
+            Type::Fn(params, Box::new(normalize(ret, vars)))
+        }
+        Type::Code(params, ret) => {
+            let params = params.iter().map(|p| normalize(p, vars)).collect();
+            Type::Code(params, Box::new(normalize(ret, vars)))
+        }
+        Type::All(params, ty) => Type::All(params.clone(), Box::new(normalize(ty, vars))),
+        ty => ty.clone(),
+    }
+}
+
+pub fn check_tuple_len(len: usize, span: Span) -> Result<(), Diagnostic> {
+    if len > MAX_TUPLE_LEN {
+        let diag = Diagnostic::error(Error::TupleTooLong, span)
+            .with_message(format!("tuple has {} elements, the maximum is {}", len, MAX_TUPLE_LEN));
+        return Err(diag);
+    }
+    Ok(())
+}
+
+fn mismatch(span: Span, expected: &Type, found: &Type) -> Diagnostic {
+    let mut vars = Vec::new();
+    let expected = normalize(expected, &mut vars);
+    let found = normalize(found, &mut vars);
+    Diagnostic::error(Error::TypeMismatch, span)
+        .with_message(format!("expected `{}`, found `{}`", expected, found))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn check(source: &str) -> Result<TypeTable, Diagnostic> {
+        let mut parser = Parser::from_source(source);
+        let root = parser.parse().expect("Parse error");
+        type_check(&parser.ast, root)
+    }
+
+    fn mismatch(source: &str) -> Diagnostic {
+        let diag = check(source).err().expect("Ill-typed program accepted");
+        assert_eq!(diag.code(), "E0200");
+        diag
+    }
+
+    #[test]
+    fn adding_a_string_is_a_mismatch() {
+        let diag = mismatch("1 + \"str\"");
+        assert_eq!(diag.span, Span::new(4, 9));
+        assert_eq!(diag.message, "expected `Int`, found `Str`");
+    }
+
+    #[test]
+    fn if_condition_must_be_a_bool() {
+        let diag = mismatch("if 5 { }");
+        assert_eq!(diag.span, Span::new(3, 4));
+        assert_eq!(diag.message, "expected `Bool`, found `Int`");
+    }
+
+    #[test]
+    fn well_typed_nodes_get_their_type() {
+        let mut parser = Parser::from_source("let t = (1, true);\nif t[1] { t[0] } else { 2 }");
+        let root = parser.parse().expect("Parse error");
+        let types = type_check(&parser.ast, root).expect("Type error");
+        let typed = parser.ast.dump_with(root, &|id| types.try_get(id).map(|ty| ty.to_string()));
+        assert!(typed.contains("[(tuple [1 : Int] [true : Bool]) : (Int, Bool)]"), "{}", typed);
+        assert!(typed.contains("[(index [t : (Int, Bool)] [1 : Int]) : Bool]"), "{}", typed);
+    }
+}
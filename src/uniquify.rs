@@ -0,0 +1,142 @@
+use crate::ast::{Ast, Node, NodeId, PrimaryExpr};
+use crate::intern::Symbol;
+use crate::lexer::{Token, TokenKind};
+use crate::source::SourceMap;
+use std::collections::HashMap;
+
+/// Binding a fresh name stands for
+#[derive(Copy, Clone, Debug)]
+pub struct Origin {
+    /// Name before renaming, as the user wrote it unless an earlier pass made it up
+    pub name: Symbol,
+    /// Offset of the token naming the binding
+    pub offset: usize,
+}
+
+/// Fresh names given out by `uniquify`, each mapped back to the binding it replaces
+pub struct Renames {
+    origins: HashMap<Symbol, Origin>,
+    counter: usize,
+}
+
+impl Renames {
+    /// Name for a variable made up after renaming, `base` followed by a number no other
+    /// name has, so it cannot collide with a renamed user variable
+    pub fn fresh(&mut self, base: &str) -> Symbol {
+        let name = Symbol::intern(&format!("{}.{}", base, self.counter));
+        self.counter += 1;
+        name
+    }
+
+    /// Name `name` had before renaming, `name` itself when it was not renamed
+    #[allow(dead_code, reason = "debug info will name variables like the source does")]
+    pub fn original(&self, name: Symbol) -> Symbol {
+        match self.origins.get(&name) {
+            Some(origin) => origin.name,
+            None => name,
+        }
+    }
+
+    /// One comment line per renamed user variable in source order, like
+    /// `; x.3 is x at main.x:2:5`. Names made up by earlier passes contain a `.` and are
+    /// left out
+    pub fn dump(&self, map: &SourceMap) -> String {
+        let mut renamed: Vec<(Symbol, Origin)> = self.origins.iter()
+            .filter(|(_, origin)| !origin.name.as_str().contains('.'))
+            .map(|(name, origin)| (*name, *origin))
+            .collect();
+        renamed.sort_by_key(|(name, origin)| (origin.offset, name.as_str()));
+
+        let mut out = String::new();
+        for (name, origin) in renamed {
+            let loc = map.location(origin.offset);
+            out.push_str(&format!("; {} is {} at {}:{}\n", name, origin.name, map.name, loc));
+        }
+        out
+    }
+}
+
+/// Gives every `let` binding and parameter below `program` a name of its own, the old
+/// name followed by a counter:
+///
+/// ```text
+/// let x = 1; let x = x + 1; x    =>  let x.0 = 1; let x.1 = x.0 + 1; x.1
+/// ```
+///
+/// Later passes can then treat a name as one variable, without looking at scopes.
+/// Function names are global and stay as they are. The returned table maps the fresh
+/// names back, so dumps and debug info can show what the user wrote.
+pub fn uniquify(ast: &mut Ast, program: NodeId) -> Renames {
+    let mut renamer = Renamer {
+        ast,
+        scopes: Vec::new(),
+        origins: HashMap::new(),
+        counter: 0,
+    };
+    renamer.visit(program);
+    Renames { origins: renamer.origins, counter: renamer.counter }
+}
+
+struct Renamer<'a> {
+    ast: &'a mut Ast,
+    /// Variables in scope with their fresh name
+    scopes: Vec<(Symbol, Symbol)>,
+    origins: HashMap<Symbol, Origin>,
+    counter: usize,
+}
+
+impl Renamer<'_> {
+    /// Fresh name for a binding of `name` at `offset`, in scope from now on
+    fn bind(&mut self, name: Symbol, offset: usize) -> Symbol {
+        let fresh = Symbol::intern(&format!("{}.{}", name, self.counter));
+        self.counter += 1;
+        self.origins.insert(fresh, Origin { name, offset });
+        self.scopes.push((name, fresh));
+        fresh
+    }
+
+    fn visit(&mut self, id: NodeId) {
+        let scope = self.scopes.len();
+
+        match self.ast[id].clone() {
+            Node::FnDecl(mut decl) => {
+                for param in decl.params.iter_mut() {
+                    param.name = self.bind(param.name, param.name_span.start);
+                }
+                self.ast.replace(id, Node::FnDecl(decl.clone())).expect("Stale function");
+                self.visit(decl.body);
+            }
+
+            Node::Lambda(mut lambda) => {
+                for param in lambda.params.iter_mut() {
+                    param.name = self.bind(param.name, param.name_span.start);
+                }
+                self.ast.replace(id, Node::Lambda(lambda.clone())).expect("Stale lambda");
+                self.visit(lambda.body);
+            }
+
+            // In scope until the enclosing block ends, but not in its own initializer
+            Node::Let(mut l) => {
+                self.visit(l.init);
+                l.name = self.bind(l.name, l.name_span.start);
+                self.ast.replace(id, Node::Let(l)).expect("Stale let");
+                return;
+            }
+
+            Node::Primary(PrimaryExpr { value: Token { kind: TokenKind::Identifier(name), .. } }) => {
+                let fresh = self.scopes.iter().rev().find(|(n, _)| *n == name).map(|(_, fresh)| *fresh);
+                if let (Some(fresh), Node::Primary(p)) = (fresh, &mut self.ast[id]) {
+                    p.value.kind = TokenKind::Identifier(fresh);
+                }
+            }
+
+            node => {
+                for child in node.children() {
+                    self.visit(child);
+                }
+            }
+        }
+
+        self.scopes.truncate(scope);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::cir::{Cmp, Label};
+use crate::intern::Symbol;
+use crate::types::Type;
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[allow(dead_code, reason = "the callee-saved registers are only handed out by register allocation")]
+pub enum Reg {
+    Rsp,
+    Rbp,
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+/// Registers arguments are passed in, in order. Further arguments go on the stack
+pub const ARG_REGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+/// Registers a call is free to overwrite
+pub const CALLER_SAVED: [Reg; 9] = [Reg::Rax, Reg::Rcx, Reg::Rdx, Reg::Rsi, Reg::Rdi, Reg::R8, Reg::R9, Reg::R10, Reg::R11];
+
+/// Low byte of a register, written by `setcc` and read by shifts
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ByteReg {
+    Al,
+    Cl,
+}
+
+impl ByteReg {
+    /// Register the byte belongs to
+    pub fn reg(self) -> Reg {
+        match self {
+            ByteReg::Al => Reg::Rax,
+            ByteReg::Cl => Reg::Rcx,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Arg {
+    Imm(i64),
+    Reg(Reg),
+    ByteReg(ByteReg),
+    /// Memory at the address in the register plus the offset
+    Deref(Reg, i64),
+    /// Pseudo-register, until registers are allocated
+    Var(Symbol),
+    /// Data at a symbol, addressed relative to `rip`
+    Global(Symbol),
+}
+
+/// Condition code of `setcc` and `jcc`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Cc {
+    E,
+    Ne,
+    L,
+    Le,
+    G,
+    Ge,
+}
+
+impl Cc {
+    pub fn from_cmp(cmp: Cmp) -> Cc {
+        match cmp {
+            Cmp::Eq => Cc::E,
+            Cmp::Ne => Cc::Ne,
+            Cmp::Lt => Cc::L,
+            Cmp::Le => Cc::Le,
+            Cmp::Gt => Cc::G,
+            Cmp::Ge => Cc::Ge,
+        }
+    }
+}
+
+/// Instructions in AT&T order, the source before the destination
+#[derive(Clone, PartialEq, Debug)]
+pub enum Instr {
+    Movq(Arg, Arg),
+    Addq(Arg, Arg),
+    Subq(Arg, Arg),
+    Imulq(Arg, Arg),
+    Andq(Arg, Arg),
+    Orq(Arg, Arg),
+    Xorq(Arg, Arg),
+    /// Shift left by an immediate or `cl`
+    Salq(Arg, Arg),
+    /// Arithmetic shift right by an immediate or `cl`
+    Sarq(Arg, Arg),
+    Negq(Arg),
+    Notq(Arg),
+    /// Sets the flags from the second operand minus the first
+    Cmpq(Arg, Arg),
+    /// Sets a byte register to 1 when the condition holds, 0 otherwise
+    Set(Cc, Arg),
+    /// Zero-extends a byte register
+    Movzbq(Arg, Arg),
+    /// Address of a global
+    Leaq(Arg, Arg),
+    /// Sign-extends `rax` into `rdx`
+    Cqto,
+    /// Divides `rdx:rax` by the operand, the quotient goes to `rax` and the remainder
+    /// to `rdx`
+    Idivq(Arg),
+    Pushq(Arg),
+    #[allow(dead_code, reason = "the conclusion added with the frame restores registers")]
+    Popq(Arg),
+    /// Call with the number of arguments passed in registers
+    Callq(Symbol, usize),
+    IndirectCallq(Arg, usize),
+    Jmp(Label),
+    JmpIf(Cc, Label),
+    #[allow(dead_code, reason = "the conclusion added with the frame returns")]
+    Retq,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Block {
+    pub instrs: Vec<Instr>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Function {
+    pub name: Symbol,
+    /// Variables used as pseudo-registers, with their type
+    pub vars: Vec<(Symbol, Type)>,
+    /// The entry block comes first, returns jump to the conclusion added with the frame
+    pub blocks: Vec<(Label, Block)>,
+}
+
+impl Function {
+    /// Block returns jump to, it pops the frame
+    pub fn conclusion(&self) -> Label {
+        Symbol::intern(&format!("{}.conclusion", self.name))
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    /// String literals with the label of their data
+    pub strings: Vec<(Label, Symbol)>,
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Reg::Rsp => "rsp",
+            Reg::Rbp => "rbp",
+            Reg::Rax => "rax",
+            Reg::Rbx => "rbx",
+            Reg::Rcx => "rcx",
+            Reg::Rdx => "rdx",
+            Reg::Rsi => "rsi",
+            Reg::Rdi => "rdi",
+            Reg::R8 => "r8",
+            Reg::R9 => "r9",
+            Reg::R10 => "r10",
+            Reg::R11 => "r11",
+            Reg::R12 => "r12",
+            Reg::R13 => "r13",
+            Reg::R14 => "r14",
+            Reg::R15 => "r15",
+        };
+        write!(f, "%{}", s)
+    }
+}
+
+impl fmt::Display for ByteReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ByteReg::Al => write!(f, "%al"),
+            ByteReg::Cl => write!(f, "%cl"),
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Imm(n) => write!(f, "${}", n),
+            Arg::Reg(reg) => write!(f, "{}", reg),
+            Arg::ByteReg(reg) => write!(f, "{}", reg),
+            Arg::Deref(reg, offset) => write!(f, "{}({})", offset, reg),
+            Arg::Var(name) => write!(f, "{}", name),
+            Arg::Global(name) => write!(f, "{}(%rip)", name),
+        }
+    }
+}
+
+impl fmt::Display for Cc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Cc::E => "e",
+            Cc::Ne => "ne",
+            Cc::L => "l",
+            Cc::Le => "le",
+            Cc::G => "g",
+            Cc::Ge => "ge",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Movq(src, dst) => write!(f, "movq {}, {}", src, dst),
+            Instr::Addq(src, dst) => write!(f, "addq {}, {}", src, dst),
+            Instr::Subq(src, dst) => write!(f, "subq {}, {}", src, dst),
+            Instr::Imulq(src, dst) => write!(f, "imulq {}, {}", src, dst),
+            Instr::Andq(src, dst) => write!(f, "andq {}, {}", src, dst),
+            Instr::Orq(src, dst) => write!(f, "orq {}, {}", src, dst),
+            Instr::Xorq(src, dst) => write!(f, "xorq {}, {}", src, dst),
+            Instr::Salq(count, dst) => write!(f, "salq {}, {}", count, dst),
+            Instr::Sarq(count, dst) => write!(f, "sarq {}, {}", count, dst),
+            Instr::Negq(dst) => write!(f, "negq {}", dst),
+            Instr::Notq(dst) => write!(f, "notq {}", dst),
+            Instr::Cmpq(a, b) => write!(f, "cmpq {}, {}", a, b),
+            Instr::Set(cc, dst) => write!(f, "set{} {}", cc, dst),
+            Instr::Movzbq(src, dst) => write!(f, "movzbq {}, {}", src, dst),
+            Instr::Leaq(src, dst) => write!(f, "leaq {}, {}", src, dst),
+            Instr::Cqto => write!(f, "cqto"),
+            Instr::Idivq(src) => write!(f, "idivq {}", src),
+            Instr::Pushq(src) => write!(f, "pushq {}", src),
+            Instr::Popq(dst) => write!(f, "popq {}", dst),
+            Instr::Callq(name, _) => write!(f, "callq {}", name),
+            Instr::IndirectCallq(target, _) => write!(f, "callq *{}", target),
+            Instr::Jmp(label) => write!(f, "jmp {}", label),
+            Instr::JmpIf(cc, label) => write!(f, "j{} {}", cc, label),
+            Instr::Retq => write!(f, "retq"),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "    .globl {}", self.name)?;
+        writeln!(f, "{}:", self.name)?;
+        for (label, block) in &self.blocks {
+            writeln!(f, "{}:", label)?;
+            for instr in &block.instrs {
+                writeln!(f, "    {}", instr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.strings.is_empty() {
+            writeln!(f, "    .section .rodata")?;
+            for (label, s) in &self.strings {
+                writeln!(f, "{}:", label)?;
+                writeln!(f, "    .asciz {:?}", s.as_str())?;
+            }
+        }
+        writeln!(f, "    .text")?;
+        let functions: Vec<String> = self.functions.iter().map(|func| func.to_string()).collect();
+        write!(f, "{}", functions.join("\n"))
+    }
+}